@@ -4,11 +4,9 @@ use std::{
     time::Duration,
 };
 
-use bytes::Bytes;
 use clap::Parser;
-use tokio::sync::mpsc::error::SendError;
 use tracing_subscriber::EnvFilter;
-use wust_socket::{Event, WebSocketClient};
+use wust_socket::{Event, SendError, WebSocketClient};
 
 static LOREM: &str = "Lorem ipsum dolor sit amet consectetur adipiscing elit quisque faucibus ex sapien vitae pellentesque sem placerat in id cursus mi pretium tellus duis convallis tempus leo eu aenean sed diam urna tempor pulvinar vivamus fringilla lacus nec metus bibendum egestas iaculis massa nisl malesuada lacinia integer nunc posuere ut hendrerit semper vel class aptent taciti sociosqu ad litora torquent per conubia nostra inceptos himenaeos orci varius natoque penatibus et magnis dis parturient montes nascetur ridiculus mus donec rhoncus eros lobortis nulla molestie mattis scelerisque maximus eget fermentum odio phasellus non purus est efficitur laoreet mauris pharetra vestibulum fusce dictum risus.";
 
@@ -29,7 +27,7 @@ impl Args {
 }
 
 #[tokio::main]
-async fn main() -> Result<(), SendError<Bytes>> {
+async fn main() -> Result<(), SendError> {
     tracing_subscriber::fmt()
         .with_env_filter(
             EnvFilter::from_default_env().add_directive("wust_socket=info".parse().unwrap()),
@@ -60,11 +58,12 @@ async fn main() -> Result<(), SendError<Bytes>> {
     let mut f = File::create("out.bmp").unwrap();
     while let Some(e) = ws.recv_timeout(Duration::from_secs(1)).await {
         match e {
-            Event::Closed => {
-                println!("connection closed");
+            Event::Closed(info) => {
+                println!("connection closed {} {:?}", info.code, info.reason);
                 break;
             }
-            Event::Pong(n) => println!("PONG: {n}ms"),
+            Event::Pong { latency, seq } => println!("PONG #{seq}: {latency}ms"),
+            Event::Ping(_) | Event::Message { .. } | Event::Drained => {}
             Event::Text(t) => {
                 let s = t.as_str();
                 let l = s.ceil_char_boundary(200);
@@ -1,7 +1,9 @@
 use bytes::Bytes;
 use clap::Parser;
 use tracing_subscriber::EnvFilter;
-use wust_socket::{Message, MessageHandler, Text, UpgradeError, WebSocketServer};
+use wust_socket::{
+    ConnectionContext, Message, MessageHandler, Text, UpgradeError, WebSocketServer,
+};
 
 #[derive(Parser)]
 #[command(author, version, about)]
@@ -13,6 +15,14 @@ struct Args {
     /// Port to connect to
     #[arg(short, long, default_value_t = 0)]
     port: u16,
+
+    /// PEM certificate chain, accepts TLS connections along with --key
+    #[arg(long, requires = "key")]
+    cert: Option<String>,
+
+    /// PEM private key for --cert
+    #[arg(long, requires = "cert")]
+    key: Option<String>,
 }
 #[tokio::main]
 async fn main() -> Result<(), UpgradeError> {
@@ -26,10 +36,11 @@ async fn main() -> Result<(), UpgradeError> {
 
     let args = Args::parse();
 
-    WebSocketServer::bind((args.addr.as_str(), args.port), true, true)
-        .await?
-        .run(EchoHandler)
-        .await;
+    let mut server = WebSocketServer::bind((args.addr.as_str(), args.port), true, false).await?;
+    if let (Some(cert), Some(key)) = (&args.cert, &args.key) {
+        server = server.with_tls_files(cert, key)?;
+    }
+    server.run(EchoHandler).await;
 
     Ok(())
 }
@@ -37,28 +48,28 @@ async fn main() -> Result<(), UpgradeError> {
 struct EchoHandler;
 #[async_trait::async_trait]
 impl MessageHandler for EchoHandler {
-    async fn on_text(&self, t: Text) -> Option<Message> {
+    async fn on_text(&self, _conn: &ConnectionContext, t: Text) -> Option<Message> {
         let s = t.to_string();
         // let l = s.ceil_char_boundary(20);
         // println!("got message T {} {:?}", s.len(), &s[..l]);
         Some(Message::Text(s))
     }
 
-    async fn on_binary(&self, b: Bytes) -> Option<Message> {
+    async fn on_binary(&self, _conn: &ConnectionContext, b: Bytes) -> Option<Message> {
         //let l = b.len().min(10);
         //println!("got messsage B {} {:?}", b.len(), &b[..l]);
         Some(Message::Binary(b))
     }
 
-    async fn on_close(&self) {
-        println!("------ client closed");
+    async fn on_close(&self, conn: &ConnectionContext) {
+        println!("------ client {} closed", conn.peer_addr());
     }
 
-    async fn on_error(&self) {
+    async fn on_error(&self, _conn: &ConnectionContext) {
         eprintln!("client error");
     }
 
-    async fn on_pong(&self, latency: u16) {
+    async fn on_pong(&self, _conn: &ConnectionContext, latency: u16) {
         println!("pong latency {latency}ms");
     }
 }
@@ -1,17 +1,33 @@
-use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+#[cfg(unix)]
+use std::path::Path;
+use std::{collections::HashMap, io, net::SocketAddr, sync::Arc, time::Duration};
 
 use base64::engine::{Engine, general_purpose::STANDARD as BASE64};
 use rustls::ClientConfig;
+#[cfg(unix)]
+use tokio::net::UnixStream;
 use tokio::{
-    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
-    net::TcpStream,
+    io::{AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpSocket, TcpStream, lookup_host},
 };
 use tokio_rustls::{
     TlsConnector,
-    rustls::pki_types::{CertificateDer, IpAddr, ServerName, pem::PemObject},
+    rustls::pki_types::{CertificateDer, ServerName, pem::PemObject},
 };
 
-use crate::{error::UpgradeError, role::Client, ws::WebSocket};
+use crate::{
+    RetryConfig, WebSocketConfig,
+    config::{ResponseLimits, SocketBuffers},
+    error::UpgradeError,
+    role::{Client, Server},
+    ws::{ConnectionInfo, DeflateParams, WebSocket, read_headers},
+};
+
+mod builder;
+mod ws_url;
+
+pub use builder::ClientBuilder;
+pub use ws_url::{Scheme, WsUrl};
 
 type Result<T> = std::result::Result<T, UpgradeError>;
 
@@ -27,58 +43,107 @@ struct ClientContext<'a> {
 
 /// Client connection implementation for WebSocket
 impl WebSocketClient {
+    /// Returns a [`ClientBuilder`] for connecting to `url`, for settings beyond those
+    /// taken by the `connect` functions. See [`connect`](WebSocketClient::connect) for the
+    /// url format.
+    #[must_use]
+    pub fn builder(url: &str) -> ClientBuilder { ClientBuilder::new(url) }
+
     /// Attempts to connect to the given url and upgrade connection.
-    /// Urls must be of format `"ws[s]://host[:port][/path]"` where
+    /// Urls must be of format `"ws[s]://host[:port][/path][?query]"` where
     /// host is either a domain name or IP address.
     /// # Errors
     /// Fails if unable to connect to the peer.
     pub async fn connect(input: &str, compressed: bool, use_context: bool) -> Result<Self> {
-        // url metadata
-        let url = url::Url::parse(input).map_err(|_| UpgradeError::InvalidUrl)?;
-        let host = url.host_str().ok_or(UpgradeError::InvalidUrl)?;
-        let port = url
-            .port_or_known_default()
-            .ok_or(UpgradeError::InvalidUrl)?;
-        let path = url.path();
-
-        let stream = TcpStream::connect((host, port))
+        Self::connect_with_config(input, compressed, use_context, &WebSocketConfig::default()).await
+    }
+
+    /// Attempts to connect to `url` with the default settings and no compression.
+    ///
+    /// The request line carries the url's path and query, e.g. `GET /chat?token=abc`
+    /// for `ws://example.com:9001/chat?token=abc`, and the `Host` header its authority.
+    /// # Errors
+    /// Fails with [`UpgradeError::InvalidUrl`] if `url` cannot be parsed, see
+    /// [`WsUrl::parse`], or if unable to connect to the peer.
+    pub async fn connect_url(url: &str) -> Result<Self> { Self::builder(url).connect().await }
+
+    /// Attempts to call [`connect`](WebSocketClient::connect), applying the given
+    /// [`WebSocketConfig`] to the connection. See `connect` for more information.
+    /// # Errors
+    /// Fails if unable to connect to the peer.
+    pub async fn connect_with_config(
+        input: &str,
+        compressed: bool,
+        use_context: bool,
+        config: &WebSocketConfig,
+    ) -> Result<Self> {
+        Self::builder(input)
+            .compression(compressed, use_context)
+            .config(config.clone())
+            .connect()
             .await
-            .map_err(|_| UpgradeError::Connect)?;
+    }
+
+    /// Attempts to call [`connect_with_config`](WebSocketClient::connect_with_config),
+    /// retrying the TCP connection as described by `retry`. This is useful when waiting for
+    /// a server to come up.
+    ///
+    /// Only the TCP connection is retried, the WebSocket handshake is attempted once.
+    /// # Errors
+    /// Fails if every connection attempt fails, or the handshake fails.
+    pub async fn connect_retry(
+        input: &str,
+        compressed: bool,
+        use_context: bool,
+        config: &WebSocketConfig,
+        retry: &RetryConfig,
+    ) -> Result<Self> {
+        Self::builder(input)
+            .compression(compressed, use_context)
+            .config(config.clone())
+            .retry(retry.clone())
+            .connect()
+            .await
+    }
+
+    async fn connect_inner(opts: &ClientBuilder) -> Result<Self> {
+        // url metadata
+        let url = WsUrl::parse(&opts.url)?;
+        let (host, port, target) = (url.host(), url.port(), url.target());
+
+        let stream = tcp_connect(host, port, opts).await?;
 
         let ctx = ClientContext {
             host,
-            path,
+            path: &target,
             port,
             local_addr: stream.local_addr().map_err(|_| UpgradeError::Addr)?,
             peer_addr: stream.peer_addr().map_err(|_| UpgradeError::Addr)?,
         };
 
-        if url.scheme() == "ws" {
+        if url.scheme() == Scheme::Ws {
             // standard TCP
             tracing::info!("attempting insecure upgrade");
-            Self::try_upgrade(stream, ctx, compressed, use_context).await
-        } else if url.scheme() == "wss" {
+            Self::try_upgrade(stream, ctx, opts).await
+        } else {
             // TCP with TLS
 
-            // try host as hostname (DNS) otherwise try IP address
-            let server = ServerName::try_from(host.to_string()).or_else(|_| {
-                let name = format!("{host}:{port}");
-                IpAddr::try_from(name.as_str())
-                    .map(ServerName::from)
-                    .map_err(|_| UpgradeError::InvalidUrl)
-            })?;
+            // the host is sent for SNI and checked against the certificate, an IP
+            // address is checked against the certificate's addresses instead
+            let server =
+                ServerName::try_from(host.to_string()).map_err(|_| UpgradeError::InvalidUrl)?;
 
             // convert stream to TLS and try handshake
-            let connector = TlsConnector::from(get_tls_config());
-            let stream = connector
+            let config = opts.tls.clone().unwrap_or_else(default_tls_config);
+            let stream = TlsConnector::from(config)
                 .connect(server, stream)
                 .await
-                .map_err(|_| UpgradeError::Connect)?;
+                .map_err(|e| {
+                    tracing::error!(e = ?e, "tls handshake");
+                    UpgradeError::Tls(e.to_string())
+                })?;
             tracing::info!("attempting TLS upgrade");
-            Self::try_upgrade(stream, ctx, compressed, use_context).await
-        } else {
-            tracing::error!("invalid scheme");
-            Err(UpgradeError::InvalidUrl)
+            Self::try_upgrade(stream, ctx, opts).await
         }
     }
 
@@ -100,29 +165,114 @@ impl WebSocketClient {
         }
     }
 
+    /// Attempts to connect to the Unix domain socket at the given path and upgrade connection.
+    ///
+    /// The handshake is sent with `Host: localhost` and, since Unix sockets have no IP address,
+    /// the connection's [`local_addr`](WebSocketClient::local_addr) and
+    /// [`peer_addr`](WebSocketClient::peer_addr) are unspecified.
+    /// # Errors
+    /// Fails if unable to connect to the peer.
+    #[cfg(unix)]
+    pub async fn connect_unix<P: AsRef<Path>>(
+        path: P,
+        compressed: bool,
+        use_context: bool,
+    ) -> Result<Self> {
+        let config = WebSocketConfig::default();
+        Self::connect_unix_with_config(path, compressed, use_context, &config).await
+    }
+
+    /// Attempts to call [`connect_unix`](WebSocketClient::connect_unix), applying the given
+    /// [`WebSocketConfig`] to the connection. See `connect_unix` for more information.
+    /// # Errors
+    /// Fails if unable to connect to the peer.
+    #[cfg(unix)]
+    pub async fn connect_unix_with_config<P: AsRef<Path>>(
+        path: P,
+        compressed: bool,
+        use_context: bool,
+        config: &WebSocketConfig,
+    ) -> Result<Self> {
+        let stream = UnixStream::connect(path)
+            .await
+            .map_err(|_| UpgradeError::Connect)?;
+
+        let ctx = ClientContext {
+            host: "localhost",
+            path: "/",
+            port: 80,
+            local_addr: crate::UNSPECIFIED_ADDR,
+            peer_addr: crate::UNSPECIFIED_ADDR,
+        };
+
+        tracing::info!("attempting unix socket upgrade");
+        let opts = ClientBuilder::new("")
+            .compression(compressed, use_context)
+            .config(config.clone());
+        Self::try_upgrade(stream, ctx, &opts).await
+    }
+
+    /// Returns the headers of the server's handshake response, keyed by lowercased name,
+    /// e.g. to read a `set-cookie` or request id, see [`ConnectionInfo::headers`].
+    #[must_use]
+    pub fn response_headers(&self) -> &HashMap<String, String> { self.info.headers() }
+
+    /// Creates a client connected to a server-role [`WebSocket`] over an in-memory pipe.
+    ///
+    /// No handshake takes place and no ports are bound, which makes this useful for testing
+    /// protocol-level behaviour. Compression is disabled and both ends report unspecified
+    /// socket addresses. Must be called from within a tokio runtime.
+    #[must_use]
+    pub fn pair() -> (Self, WebSocket<Server>) {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let addr = crate::UNSPECIFIED_ADDR;
+        let config = WebSocketConfig::default();
+        (
+            Self::from_stream(client, addr, addr, None, &config, ConnectionInfo::default()),
+            WebSocket::from_stream(server, addr, addr, None, &config, ConnectionInfo::default()),
+        )
+    }
+
     async fn try_upgrade<S>(
         mut stream: S,
         ctx: ClientContext<'_>,
-        req_compressed: bool,
-        req_use_context: bool,
+        opts: &ClientBuilder,
     ) -> Result<Self>
     where
         S: AsyncReadExt + AsyncWriteExt + Send + Unpin + 'static,
     {
+        if let Some(name) = &opts.invalid_header {
+            return Err(UpgradeError::InvalidHeader(name.clone()));
+        }
         let sec_websocket_key = BASE64.encode(rand::random::<[u8; 16]>());
 
         let mut req = format!(
             "GET {} HTTP/1.1\r\n\
-            Host: {}:{}\r\n\
-            Upgrade: websocket\r\n\
-            Connection: Upgrade\r\n\
+            Host: {}\r\n\
+            Upgrade: {}\r\n\
+            Connection: {}\r\n\
             Sec-WebSocket-Key: {sec_websocket_key}\r\n\
             Sec-WebSocket-Version: 13\r\n",
-            ctx.path, ctx.host, ctx.port
+            ctx.path,
+            ws_url::authority(ctx.host, ctx.port),
+            opts.upgrade_header,
+            opts.connection_header
         );
-        if req_compressed {
+        for (name, value) in &opts.headers {
+            req.push_str(name);
+            req.push_str(": ");
+            req.push_str(value);
+            req.push_str("\r\n");
+        }
+        if !opts.protocols.is_empty() {
+            req.push_str("Sec-WebSocket-Protocol: ");
+            req.push_str(&opts.protocols.join(", "));
+            req.push_str("\r\n");
+        }
+        let offer = opts.compressed && opts.config.deflate_fits();
+        if offer {
             req.push_str("Sec-WebSocket-Extensions: permessage-deflate");
-            if !req_use_context {
+            if !opts.use_context {
                 req.push_str("; client_no_context_takeover");
                 req.push_str("; server_no_context_takeover");
             }
@@ -137,57 +287,31 @@ impl WebSocketClient {
             .map_err(|_| UpgradeError::Write)?;
         stream.flush().await.map_err(|_| UpgradeError::Write)?;
 
-        // get status line and validate status code
         let mut reader = BufReader::new(stream);
-        let mut status_line = String::new();
-        reader
-            .read_line(&mut status_line)
-            .await
-            .map_err(|_| UpgradeError::Read)?;
-
-        let mut status_parts = status_line.split_whitespace();
-        if status_parts.next() != Some("HTTP/1.1") || status_parts.next() != Some("101") {
-            return Err(UpgradeError::StatusLine(status_line));
-        }
-
-        // collect headers in a hashmap
-        let mut headers = HashMap::new();
-        loop {
-            let mut line = String::new();
-            reader
-                .read_line(&mut line)
-                .await
-                .map_err(|_| UpgradeError::Read)?;
-            let line = line.trim_end(); // remove \r\n
-            if line.is_empty() {
-                break;
-            } // end of headers
-
-            if let Some((name, value)) = line.split_once(':') {
-                headers.insert(name.trim().to_lowercase(), value.trim().to_string());
-            }
+        let mut received = Vec::new();
+        let res = read_response(&mut reader, opts.limits, &mut received).await;
+        if let Some(capture) = &opts.capture {
+            capture.record(req.into_bytes(), received);
         }
+        let headers = res?;
 
         Self::validate_header(&headers, "upgrade", "websocket")?;
-        // TODO: multiple connection values
         Self::validate_header(&headers, "connection", "upgrade")?;
-        // TODO: case sensitive check
-        let expected_accept = Self::hash_key(&sec_websocket_key);
-        Self::validate_header(&headers, "sec-websocket-accept", &expected_accept)?;
-
-        // TODO: parse context and compression from headers
-        let mut compressed = false;
-        let mut use_context = true;
-        if let Some(ext_reply) = headers.get("sec-websocket-extensions") {
+        validate_accept(&headers, &Self::hash_key(&sec_websocket_key))?;
+        let protocol = validate_protocol(&headers, &opts.protocols)?;
+
+        let mut deflate = None;
+        // a server refusing deflate omits it from the reply, and one which accepts
+        // an extension we never offered must not make us compress
+        let ext_reply = headers.get("sec-websocket-extensions").filter(|_| offer);
+        if let Some(ext_reply) = ext_reply {
             let mut tokens = ext_reply.split(';');
             if tokens.next() == Some("permessage-deflate") {
-                compressed = true;
+                let params = deflate.insert(DeflateParams::symmetric(true));
                 for token in tokens {
-                    let token = token.trim();
-                    match token {
-                        "client_no_context_takeover" | "server_no_context_takeover" => {
-                            use_context = false;
-                        }
+                    match token.trim() {
+                        "client_no_context_takeover" => params.client_no_context_takeover = true,
+                        "server_no_context_takeover" => params.server_no_context_takeover = true,
                         _ => {}
                     }
                 }
@@ -195,33 +319,425 @@ impl WebSocketClient {
         }
 
         tracing::info!(addr = ?ctx.peer_addr, "successfully connected to peer");
-        Ok(Self::from_stream(
+        let mut ws = Self::from_stream(
             reader.into_inner(),
             ctx.local_addr,
             ctx.peer_addr,
-            compressed,
-            use_context,
-        ))
+            deflate,
+            &opts.config,
+            ConnectionInfo::from_target(ctx.path).with_headers(headers),
+        );
+        ws.protocol = protocol;
+        Ok(ws)
     }
 }
 
-fn get_tls_config() -> Arc<ClientConfig> {
-    let certs = CertificateDer::pem_file_iter("certs/root-ca.pem")
-        .unwrap()
-        .collect::<std::result::Result<Vec<_>, _>>()
-        .unwrap();
+// Read the status line and headers of the server's response, failing unless it
+// switches protocols
+async fn read_response<S: AsyncReadExt + Unpin>(
+    reader: &mut BufReader<S>,
+    limits: ResponseLimits,
+    raw: &mut Vec<u8>,
+) -> Result<HashMap<String, String>> {
+    let status_line = WebSocketClient::read_status_line(reader, limits.status_line).await?;
+    raw.extend_from_slice(status_line.as_bytes());
+
+    // validate status code
+    let mut status_parts = status_line.split_whitespace();
+    if status_parts.next() != Some("HTTP/1.1") || status_parts.next() != Some("101") {
+        return Err(UpgradeError::StatusLine(status_line));
+    }
+
+    read_headers(reader, limits.header_bytes, limits.headers, raw).await
+}
 
-    let root_cert_store = {
-        let mut s = rustls::RootCertStore::empty();
-        for cert in certs {
-            s.add(cert).unwrap();
+async fn tcp_connect(host: &str, port: u16, opts: &ClientBuilder) -> Result<TcpStream> {
+    let retry = &opts.retry;
+    let mut attempt = 1;
+    loop {
+        match connect_socket(host, port, opts.buffers).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) if attempt < retry.attempts => {
+                tracing::debug!(attempt = attempt, e = ?e, "failed to connect, retrying");
+                attempt += 1;
+                tokio::time::sleep(retry.delay).await;
+            }
+            Err(_) => return Err(UpgradeError::Connect),
         }
-        s.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
-        s
-    };
+    }
+}
 
-    let config = rustls::ClientConfig::builder()
-        .with_root_certificates(root_cert_store)
-        .with_no_client_auth(); // i guess this was previously the default?
+// Connect to the first address `host` resolves to which accepts, as
+// `TcpStream::connect` does, sizing the buffers before the SYN is sent
+async fn connect_socket(host: &str, port: u16, buffers: SocketBuffers) -> io::Result<TcpStream> {
+    let clamp = |size: usize| u32::try_from(size).unwrap_or(u32::MAX);
+    let mut last_err = None;
+    for addr in lookup_host((host, port)).await? {
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        if let Some(size) = buffers.send {
+            socket.set_send_buffer_size(clamp(size))?;
+        }
+        if let Some(size) = buffers.recv {
+            socket.set_recv_buffer_size(clamp(size))?;
+        }
+        match socket.connect(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no addresses")))
+}
+
+// Compare the accept header exactly and in constant time. Base64 is case-sensitive,
+// the value is already trimmed of surrounding whitespace.
+fn validate_accept(headers: &HashMap<String, String>, expected: &str) -> Result<()> {
+    let got = headers
+        .get("sec-websocket-accept")
+        .ok_or(UpgradeError::MissingHeader("sec-websocket-accept"))?;
+
+    let matches = got.len() == expected.len()
+        && got
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0;
+    if matches {
+        Ok(())
+    } else {
+        Err(UpgradeError::AcceptMismatch {
+            expected: expected.into(),
+            got: got.clone(),
+        })
+    }
+}
+
+// The server may pick one of the requested subprotocols, or none, RFC 6455 section 4.1
+fn validate_protocol(
+    headers: &HashMap<String, String>,
+    requested: &[String],
+) -> Result<Option<String>> {
+    match headers.get("sec-websocket-protocol") {
+        Some(got) if !requested.contains(got) => Err(UpgradeError::Header {
+            field: "sec-websocket-protocol",
+            expected: requested.join(", "),
+            got: got.clone(),
+        }),
+        got => Ok(got.cloned()),
+    }
+}
+
+// Trust the usual web roots, along with a local development CA if there is one
+fn default_tls_config() -> Arc<ClientConfig> {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    if let Ok(certs) = CertificateDer::pem_file_iter("certs/root-ca.pem") {
+        let (added, ignored) = roots.add_parsable_certificates(certs.flatten());
+        tracing::debug!(added, ignored, "loaded certs/root-ca.pem");
+    }
+
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
     Arc::new(config)
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncBufReadExt;
+
+    use super::*;
+    use crate::{Event, HandshakeCapture, role::Server};
+
+    #[test]
+    fn protocol_must_be_requested() {
+        let requested = ["chat".to_string()];
+        let mut headers = HashMap::new();
+        assert_eq!(validate_protocol(&headers, &requested).unwrap(), None);
+
+        headers.insert("sec-websocket-protocol".to_string(), "chat".to_string());
+        assert_eq!(
+            validate_protocol(&headers, &requested).unwrap().as_deref(),
+            Some("chat")
+        );
+
+        headers.insert("sec-websocket-protocol".to_string(), "mqtt".to_string());
+        assert!(validate_protocol(&headers, &requested).is_err());
+    }
+
+    #[tokio::test]
+    async fn pair_round_trip() {
+        let (mut client, mut server) = WebSocketClient::pair();
+
+        client.send_text("hello").await.unwrap();
+        match server.recv().await {
+            Some(Event::Text(t)) => assert_eq!(t.as_str(), "hello"),
+            e => panic!("unexpected event {e:?}"),
+        }
+
+        server.send_bytes(&[1, 2, 3]).await.unwrap();
+        match client.recv().await {
+            Some(Event::Binary(b)) => assert_eq!(&b[..], &[1, 2, 3]),
+            e => panic!("unexpected event {e:?}"),
+        }
+    }
+
+    // the lines of a handshake request, up to the blank line which ends it
+    async fn request_lines<S: AsyncReadExt + Unpin>(stream: &mut S) -> Vec<String> {
+        let mut lines = Vec::new();
+        let mut reader = BufReader::new(stream);
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).await.unwrap();
+            if line == "\r\n" {
+                return lines;
+            }
+            lines.push(line);
+        }
+    }
+
+    // where the handshake tests connect to
+    fn localhost() -> ClientContext<'static> {
+        let addr = crate::UNSPECIFIED_ADDR;
+        ClientContext {
+            host: "localhost",
+            path: "/",
+            port: 80,
+            local_addr: addr,
+            peer_addr: addr,
+        }
+    }
+
+    // Answer the handshake request on `stream` with 101 as a server which knows no
+    // extensions would, adding the `extra` header lines
+    async fn switch_protocols<S>(mut stream: S, extra: &str) -> S
+    where
+        S: AsyncReadExt + AsyncWriteExt + Unpin,
+    {
+        let lines = request_lines(&mut stream).await;
+        let key = lines
+            .iter()
+            .find_map(|line| line.strip_prefix("Sec-WebSocket-Key: "))
+            .unwrap()
+            .trim();
+        let accept = WebSocket::<Server>::hash_key(key);
+        let response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\n\
+            Upgrade: websocket\r\n\
+            Connection: Upgrade\r\n\
+            Sec-WebSocket-Accept: {accept}\r\n{extra}\r\n"
+        );
+        stream.write_all(response.as_bytes()).await.unwrap();
+        stream
+    }
+
+    #[tokio::test]
+    async fn retried_connect_uses_config() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let request =
+            tokio::spawn(
+                async move { request_lines(&mut listener.accept().await.unwrap().0).await },
+            );
+
+        let config = WebSocketConfig::default().max_deflate_memory(0);
+        let retry = RetryConfig::default();
+        let res = WebSocketClient::connect_retry(&url, true, true, &config, &retry).await;
+        assert!(res.is_err());
+        let lines = request.await.unwrap();
+        assert!(
+            !lines
+                .iter()
+                .any(|l| l.starts_with("Sec-WebSocket-Extensions"))
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_connect_uses_config() {
+        let path = std::env::temp_dir().join(format!("wust-config-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        let request =
+            tokio::spawn(
+                async move { request_lines(&mut listener.accept().await.unwrap().0).await },
+            );
+
+        // too little deflate memory to offer compression, and nobody answers
+        let config = WebSocketConfig::default().max_deflate_memory(0);
+        let res = WebSocketClient::connect_unix_with_config(&path, true, true, &config).await;
+        let _ = std::fs::remove_file(&path);
+        assert!(res.is_err());
+        let lines = request.await.unwrap();
+        assert!(lines[0].starts_with("GET / HTTP/1.1"));
+        assert!(
+            !lines
+                .iter()
+                .any(|l| l.starts_with("Sec-WebSocket-Extensions"))
+        );
+    }
+
+    #[tokio::test]
+    async fn deflate_refused() {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+
+        // a server which ignores the extension offer
+        let server = tokio::spawn(async move {
+            let stream = switch_protocols(server, "").await;
+            WebSocket::<Server>::over_stream(stream, None, &WebSocketConfig::default())
+        });
+
+        let ctx = localhost();
+        let opts = ClientBuilder::new("").compression(true, false);
+        let mut client = WebSocketClient::try_upgrade(client, ctx, &opts)
+            .await
+            .unwrap();
+        assert!(client.compression().is_none());
+
+        // the server rejects frames with RSV1 set, so these must be uncompressed
+        let mut server = server.await.unwrap();
+        client.send_text(&"hello ".repeat(100)).await.unwrap();
+        match server.recv().await {
+            Some(Event::Text(t)) => assert_eq!(t.as_str(), "hello ".repeat(100)),
+            e => panic!("unexpected event {e:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn response_headers_kept() {
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            let mut stream = switch_protocols(server, "X-Request-Id: 42\r\n").await;
+            // hold the pipe open until the client has read the response
+            let _ = stream.read(&mut [0; 1]).await;
+        });
+
+        let ctx = localhost();
+        let client = WebSocketClient::try_upgrade(client, ctx, &ClientBuilder::new(""))
+            .await
+            .unwrap();
+        assert_eq!(client.response_headers()["x-request-id"], "42");
+        assert_eq!(client.info().header("X-Request-ID"), Some("42"));
+    }
+
+    #[tokio::test]
+    async fn extra_headers_sent() {
+        let (client, mut server) = tokio::io::duplex(4096);
+        // read the request, then hang up without answering
+        tokio::spawn(async move { server.read(&mut [0; 4096]).await });
+
+        let ctx = localhost();
+        let capture = HandshakeCapture::new();
+        let opts = ClientBuilder::new("")
+            .capture_handshake(&capture)
+            .header("Authorization", "Bearer token")
+            .header("sec-websocket-key", "chosen")
+            .header("Bad Name", "x");
+        assert!(
+            WebSocketClient::try_upgrade(client, ctx, &opts)
+                .await
+                .is_err()
+        );
+
+        let (sent, _) = capture.last_handshake_exchange().unwrap();
+        let request = String::from_utf8(sent).unwrap();
+        assert!(request.contains("\r\nAuthorization: Bearer token\r\n"));
+        assert_eq!(request.matches("Sec-WebSocket-Key").count(), 1);
+        assert!(!request.contains("chosen") && !request.contains("Bad Name"));
+    }
+
+    #[tokio::test]
+    async fn header_line_break_rejected() {
+        let injected = [
+            ClientBuilder::new("").header("Cookie", "a=1\r\nX-Injected: 1"),
+            ClientBuilder::new("").connection_header("Upgrade\nX-Injected: 1"),
+            ClientBuilder::new("").upgrade_header("websocket\r"),
+        ];
+        for (opts, name) in injected.iter().zip(["Cookie", "Connection", "Upgrade"]) {
+            let (client, _server) = tokio::io::duplex(4096);
+            let ctx = localhost();
+            let res = WebSocketClient::try_upgrade(client, ctx, opts).await;
+            assert!(matches!(res, Err(UpgradeError::InvalidHeader(n)) if n == name));
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_handshake_captured() {
+        let (client, mut server) = tokio::io::duplex(4096);
+        let refusal = "HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n";
+        tokio::spawn(async move {
+            let _ = server.read(&mut [0; 4096]).await;
+            server.write_all(refusal.as_bytes()).await
+        });
+
+        let ctx = localhost();
+        let capture = HandshakeCapture::new();
+        let opts = ClientBuilder::new("").capture_handshake(&capture);
+        let res = WebSocketClient::try_upgrade(client, ctx, &opts).await;
+        assert!(matches!(res, Err(UpgradeError::StatusLine(_))));
+
+        let (sent, received) = capture.last_handshake_exchange().unwrap();
+        assert!(sent.starts_with(b"GET / HTTP/1.1\r\n"));
+        // reading stops at the status line
+        assert!(received.starts_with(b"HTTP/1.1 403 Forbidden"));
+    }
+
+    #[test]
+    fn accept_compared_exactly() {
+        // the example from RFC 6455 section 1.3
+        let expected = "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=";
+        let headers = |value: &str| HashMap::from([("sec-websocket-accept".into(), value.into())]);
+        assert!(validate_accept(&headers(expected), expected).is_ok());
+        let res = validate_accept(&headers("S3PPLMBITXAQ9KYGZZHZRBK+XOO="), expected);
+        assert!(matches!(res, Err(UpgradeError::AcceptMismatch { .. })));
+        let res = validate_accept(&headers("s3pPLMBiTxaQ9kYGzzhZRbK+xOo"), expected);
+        assert!(matches!(res, Err(UpgradeError::AcceptMismatch { .. })));
+    }
+
+    #[tokio::test]
+    async fn response_limits_applied() {
+        async fn upgrade(opts: ClientBuilder) -> Result<WebSocketClient> {
+            let (client, mut server) = tokio::io::duplex(4096);
+            tokio::spawn(async move {
+                let _ = server.read(&mut [0; 4096]).await;
+                let response = "HTTP/1.1 101 Switching Protocols\r\nX-A: 1\r\nX-B: 2\r\n\r\n";
+                server.write_all(response.as_bytes()).await
+            });
+            let ctx = localhost();
+            WebSocketClient::try_upgrade(client, ctx, &opts).await
+        }
+
+        // within the defaults the response gets as far as the missing upgrade headers
+        let res = upgrade(ClientBuilder::new("")).await;
+        assert!(matches!(res, Err(UpgradeError::MissingHeader(_))));
+        let res = upgrade(ClientBuilder::new("").max_status_line(16)).await;
+        assert!(matches!(res, Err(UpgradeError::StatusLine(_))));
+        let res = upgrade(ClientBuilder::new("").max_headers(1)).await;
+        assert!(matches!(res, Err(UpgradeError::HeadersTooLarge)));
+        let res = upgrade(ClientBuilder::new("").max_header_bytes(8)).await;
+        assert!(matches!(res, Err(UpgradeError::HeadersTooLarge)));
+    }
+
+    #[tokio::test]
+    async fn tls_handshake_error_reported() {
+        // a server which answers the TLS hello with plain HTTP
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let _ = stream.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").await;
+        });
+
+        let config = ClientConfig::builder()
+            .with_root_certificates(rustls::RootCertStore::empty())
+            .with_no_client_auth();
+        let result = WebSocketClient::builder(&format!("wss://127.0.0.1:{port}/"))
+            .tls_config(Arc::new(config))
+            .connect()
+            .await;
+        assert!(matches!(result, Err(UpgradeError::Tls(_))));
+    }
+}
@@ -0,0 +1,256 @@
+use std::sync::Arc;
+
+use rustls::ClientConfig;
+
+use super::{Result, WebSocketClient};
+use crate::{
+    HandshakeCapture, RetryConfig, WebSocketConfig,
+    config::{ResponseLimits, SocketBuffers},
+};
+
+/// Configures and opens a client connection, created with [`WebSocketClient::builder`].
+///
+/// ```no_run
+/// # async fn run() -> Result<(), wust_socket::UpgradeError> {
+/// let ws = wust_socket::WebSocketClient::builder("ws://localhost:8080/chat")
+///     .compression(true, true)
+///     .connection_header("keep-alive, Upgrade")
+///     .connect()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct ClientBuilder {
+    pub(crate) url: String,
+    pub(crate) compressed: bool,
+    pub(crate) use_context: bool,
+    pub(crate) config: WebSocketConfig,
+    pub(crate) retry: RetryConfig,
+    pub(crate) connection_header: String,
+    pub(crate) upgrade_header: String,
+    pub(crate) capture: Option<HandshakeCapture>,
+    pub(crate) buffers: SocketBuffers,
+    pub(crate) limits: ResponseLimits,
+    pub(crate) protocols: Vec<String>,
+    pub(crate) tls: Option<Arc<ClientConfig>>,
+    pub(crate) headers: Vec<(String, String)>,
+    // the first header given a value with a line break, which fails the upgrade
+    pub(crate) invalid_header: Option<String>,
+}
+
+// Headers the handshake itself depends on, set through their own methods if at all
+const RESERVED_HEADERS: [&str; 7] = [
+    "host",
+    "upgrade",
+    "connection",
+    "sec-websocket-key",
+    "sec-websocket-version",
+    "sec-websocket-extensions",
+    "sec-websocket-protocol",
+];
+
+impl ClientBuilder {
+    pub(crate) fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            compressed: false,
+            use_context: true,
+            config: WebSocketConfig::default(),
+            retry: RetryConfig::once(),
+            connection_header: "Upgrade".into(),
+            upgrade_header: "websocket".into(),
+            capture: None,
+            buffers: SocketBuffers::default(),
+            limits: ResponseLimits::default(),
+            protocols: Vec::new(),
+            tls: None,
+            headers: Vec::new(),
+            invalid_header: None,
+        }
+    }
+
+    /// Requests permessage-deflate compression, optionally with context takeover.
+    /// Compression is off by default.
+    #[must_use]
+    pub fn compression(mut self, compressed: bool, use_context: bool) -> Self {
+        self.compressed = compressed;
+        self.use_context = use_context;
+        self
+    }
+
+    /// Sets the [`WebSocketConfig`] applied to the connection.
+    #[must_use]
+    pub fn config(mut self, config: WebSocketConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Sets how long the connection may sit idle before the peer is pinged, `None` to
+    /// never ping, see [`WebSocketConfig::ping_interval`]. Applied to the config, so call
+    /// it after [`config`](ClientBuilder::config).
+    #[must_use]
+    pub fn ping_interval(mut self, interval: Option<std::time::Duration>) -> Self {
+        self.config.ping_interval = interval;
+        self
+    }
+
+    /// Sets how many items each of the connection's internal queues holds, see
+    /// [`WebSocketConfig::channel_capacity`]. Applied to the config, so call it after
+    /// [`config`](ClientBuilder::config).
+    #[must_use]
+    pub fn channel_capacity(mut self, capacity: usize) -> Self {
+        self.config = self.config.channel_capacity(capacity);
+        self
+    }
+
+    /// Retries the TCP connection as described by `retry`, see
+    /// [`connect_retry`](WebSocketClient::connect_retry). A single attempt is made by default.
+    #[must_use]
+    pub fn retry(mut self, retry: RetryConfig) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Overrides the value of the `Connection` header in the upgrade request, `Upgrade`
+    /// by default, for servers which are particular about it, e.g. `keep-alive, Upgrade`.
+    ///
+    /// A value with a line break fails the upgrade with
+    /// [`UpgradeError::InvalidHeader`](crate::UpgradeError::InvalidHeader).
+    #[must_use]
+    pub fn connection_header(mut self, value: &str) -> Self {
+        if self.check_value("Connection", value) {
+            self.connection_header = value.to_string();
+        }
+        self
+    }
+
+    /// Adds a header to the upgrade request, e.g. `Authorization` or `Cookie`. May be
+    /// called repeatedly, and the headers are sent in the order added.
+    ///
+    /// A value with a line break fails the upgrade with
+    /// [`UpgradeError::InvalidHeader`](crate::UpgradeError::InvalidHeader). Headers which
+    /// the handshake depends on, such as `Upgrade`, `Connection` and `Sec-WebSocket-Key`,
+    /// cannot be set this way and are ignored, as are names which are not valid HTTP tokens.
+    #[must_use]
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        let valid = !name.is_empty()
+            && name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+        if !valid || RESERVED_HEADERS.contains(&name.to_ascii_lowercase().as_str()) {
+            tracing::warn!(name, "ignoring handshake header");
+            return self;
+        }
+        if self.check_value(name, value) {
+            self.headers.push((name.to_string(), value.to_string()));
+        }
+        self
+    }
+
+    /// Overrides the value of the `Upgrade` header in the upgrade request, `websocket`
+    /// by default, for servers which are particular about its casing.
+    ///
+    /// A value with a line break fails the upgrade with
+    /// [`UpgradeError::InvalidHeader`](crate::UpgradeError::InvalidHeader).
+    #[must_use]
+    pub fn upgrade_header(mut self, value: &str) -> Self {
+        if self.check_value("Upgrade", value) {
+            self.upgrade_header = value.to_string();
+        }
+        self
+    }
+
+    // A line break would end the header early and start another, so the value is
+    // refused and the upgrade will fail
+    fn check_value(&mut self, name: &str, value: &str) -> bool {
+        if value.contains(['\r', '\n']) {
+            tracing::warn!(name, "line break in handshake header value");
+            self.invalid_header.get_or_insert_with(|| name.to_string());
+            return false;
+        }
+        true
+    }
+
+    /// Requests the given subprotocols in order of preference, none by default. The one
+    /// the server picks is available from [`WebSocket::protocol`](crate::WebSocket::protocol).
+    ///
+    /// The connection fails if the server picks a protocol which was not requested.
+    #[must_use]
+    pub fn protocols<I, S>(mut self, protocols: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.protocols = protocols.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Sets the TLS configuration for `wss://` urls, e.g. to trust a self-signed
+    /// certificate. By default the usual web roots are trusted, along with the CA in
+    /// `certs/root-ca.pem` if that file exists.
+    #[must_use]
+    pub fn tls_config(mut self, config: Arc<ClientConfig>) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
+    /// Records the raw handshake into `capture`, including when it fails.
+    #[must_use]
+    pub fn capture_handshake(mut self, capture: &HandshakeCapture) -> Self {
+        self.capture = Some(capture.clone());
+        self
+    }
+
+    /// Sets the size of the socket's send buffer (`SO_SNDBUF`), for bulk transfers over
+    /// links with a high bandwidth-delay product.
+    ///
+    /// Setting a size turns off the kernel's automatic tuning of the buffer. Linux doubles
+    /// the value to allow for bookkeeping and caps it at `net.core.wmem_max`.
+    #[must_use]
+    pub fn send_buffer_size(mut self, size: usize) -> Self {
+        self.buffers.send = Some(size);
+        self
+    }
+
+    /// Sets the size of the socket's receive buffer (`SO_RCVBUF`), which bounds the TCP
+    /// window advertised to the server. It is set before connecting so the window can scale.
+    ///
+    /// Setting a size turns off the kernel's automatic tuning of the buffer. Linux doubles
+    /// the value to allow for bookkeeping and caps it at `net.core.rmem_max`.
+    #[must_use]
+    pub fn recv_buffer_size(mut self, size: usize) -> Self {
+        self.buffers.recv = Some(size);
+        self
+    }
+
+    /// Sets the maximum length in bytes of the status line of the server's response, 8K
+    /// by default. Longer lines fail the upgrade with
+    /// [`UpgradeError::StatusLine`](crate::UpgradeError::StatusLine).
+    #[must_use]
+    pub fn max_status_line(mut self, len: usize) -> Self {
+        self.limits.status_line = len;
+        self
+    }
+
+    /// Sets the maximum total length in bytes of the response headers, 16K by default.
+    #[must_use]
+    pub fn max_header_bytes(mut self, len: usize) -> Self {
+        self.limits.header_bytes = len;
+        self
+    }
+
+    /// Sets the maximum number of response header lines, 64 by default.
+    #[must_use]
+    pub fn max_headers(mut self, count: usize) -> Self {
+        self.limits.headers = count;
+        self
+    }
+
+    /// Attempts to connect and upgrade the connection.
+    /// # Errors
+    /// Fails if unable to connect to the peer, or the handshake fails.
+    pub async fn connect(&self) -> Result<WebSocketClient> {
+        WebSocketClient::connect_inner(self).await
+    }
+}
@@ -0,0 +1,160 @@
+use url::{Host, Url};
+
+use super::Result;
+use crate::UpgradeError;
+
+/// Whether a [`WsUrl`] connects over plain TCP or TLS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scheme {
+    /// `ws://`, plain TCP on port 80 by default.
+    Ws,
+    /// `wss://`, TLS on port 443 by default.
+    Wss,
+}
+
+/// A parsed `ws://` or `wss://` URL, as used by [`WebSocketClient::connect`](super::WebSocketClient::connect).
+///
+/// Useful for opening the underlying stream yourself before upgrading it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WsUrl {
+    scheme: Scheme,
+    host: String,
+    port: u16,
+    path: String,
+    query: Option<String>,
+}
+
+impl WsUrl {
+    /// Parses a WebSocket URL, defaulting the port from the scheme.
+    /// # Errors
+    /// Fails with [`UpgradeError::InvalidUrl`] if the input is not a URL, its scheme is not
+    /// `ws` or `wss`, it has no host, or it has a fragment, which RFC 6455 forbids.
+    pub fn parse(input: &str) -> Result<Self> {
+        let url = Url::parse(input).map_err(|_| UpgradeError::InvalidUrl)?;
+        let scheme = match url.scheme() {
+            "ws" => Scheme::Ws,
+            "wss" => Scheme::Wss,
+            _ => {
+                tracing::error!("invalid scheme");
+                return Err(UpgradeError::InvalidUrl);
+            }
+        };
+        if url.fragment().is_some() {
+            return Err(UpgradeError::InvalidUrl);
+        }
+        // IPv6 literals lose their brackets so the host can be connected to directly
+        let host = match url.host().ok_or(UpgradeError::InvalidUrl)? {
+            Host::Ipv6(addr) => addr.to_string(),
+            host => host.to_string(),
+        };
+        Ok(Self {
+            scheme,
+            host,
+            port: url
+                .port_or_known_default()
+                .ok_or(UpgradeError::InvalidUrl)?,
+            path: url.path().to_string(),
+            query: url.query().map(str::to_string),
+        })
+    }
+
+    /// Returns the scheme.
+    #[must_use]
+    pub fn scheme(&self) -> Scheme { self.scheme }
+
+    /// Returns the host name or IP address, without brackets for IPv6.
+    #[must_use]
+    pub fn host(&self) -> &str { &self.host }
+
+    /// Returns the port, 80 or 443 by default.
+    #[must_use]
+    pub fn port(&self) -> u16 { self.port }
+
+    /// Returns the path, `/` if the URL has none.
+    #[must_use]
+    pub fn path(&self) -> &str { &self.path }
+
+    /// Returns the query string without the leading `?`, if any.
+    #[must_use]
+    pub fn query(&self) -> Option<&str> { self.query.as_deref() }
+
+    /// Returns the request target sent in the upgrade request, the path followed by
+    /// the query if any, e.g. `/chat?token=abc`.
+    #[must_use]
+    pub fn target(&self) -> String {
+        match &self.query {
+            Some(query) => format!("{}?{query}", self.path),
+            None => self.path.clone(),
+        }
+    }
+
+    /// Returns `host:port`, bracketing IPv6 addresses, e.g. `[::1]:8080`.
+    #[must_use]
+    pub fn authority(&self) -> String { authority(&self.host, self.port) }
+}
+
+pub(crate) fn authority(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{host}]:{port}")
+    } else {
+        format!("{host}:{port}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_ports_and_paths() {
+        let url = WsUrl::parse("ws://example.com").unwrap();
+        assert_eq!(url.scheme(), Scheme::Ws);
+        assert_eq!(
+            (url.host(), url.port(), url.path()),
+            ("example.com", 80, "/")
+        );
+        assert_eq!(url.query(), None);
+
+        let url = WsUrl::parse("wss://example.com:8443/chat?room=1").unwrap();
+        assert_eq!(url.scheme(), Scheme::Wss);
+        assert_eq!(
+            (url.port(), url.path(), url.query()),
+            (8443, "/chat", Some("room=1"))
+        );
+        assert_eq!(WsUrl::parse("wss://example.com").unwrap().port(), 443);
+    }
+
+    #[test]
+    fn request_target() {
+        let url = WsUrl::parse("ws://example.com:9001/chat?token=abc").unwrap();
+        assert_eq!(url.target(), "/chat?token=abc");
+        assert_eq!(url.authority(), "example.com:9001");
+        assert_eq!(
+            WsUrl::parse("ws://example.com?a=1").unwrap().target(),
+            "/?a=1"
+        );
+        assert_eq!(WsUrl::parse("ws://example.com").unwrap().target(), "/");
+    }
+
+    #[test]
+    fn ipv6_literals() {
+        let url = WsUrl::parse("ws://[::1]:9001/").unwrap();
+        assert_eq!(url.host(), "::1");
+        assert_eq!(url.authority(), "[::1]:9001");
+    }
+
+    #[test]
+    fn invalid_urls() {
+        for input in [
+            "http://example.com",
+            "example.com",
+            "ws://",
+            "ws://host/#frag",
+        ] {
+            assert!(
+                matches!(WsUrl::parse(input), Err(UpgradeError::InvalidUrl)),
+                "{input}"
+            );
+        }
+    }
+}
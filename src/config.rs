@@ -0,0 +1,489 @@
+use std::{sync::Arc, time::Duration};
+
+use crate::{
+    HandshakeCapture, HttpError, UpgradeRequest,
+    server::UpgradeHook,
+    ws::{Observation, Observer},
+};
+
+/// Default initial capacity of a connection's read buffer.
+const DEFAULT_READ_BUFFER_SIZE: usize = 8 * 1024; // 8K
+/// Default inflation ratio above which inbound messages are reported.
+const DEFAULT_INFLATE_RATIO_WARNING: usize = 1000;
+
+/// Default maximum payload length of each outbound frame.
+const DEFAULT_MAX_SEND_FRAME_SIZE: usize = crate::MAX_FRAME_PAYLOAD;
+
+/// How a connection handles pings and pongs from the peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PingPolicy {
+    /// Answer every ping with its own pong and warn about pongs which do not answer our ping.
+    #[default]
+    Strict,
+    /// Answer a burst of pings with a single pong carrying the latest payload, and silently
+    /// ignore unsolicited pongs, which peers may send as one-way heartbeats. Both are allowed
+    /// by RFC 6455 and reduce traffic under ping floods.
+    Lenient,
+}
+
+/// How a connection replies to the peer's close frame.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum ClosePolicy {
+    /// Reply with 1000 Normal Closure whatever code the peer sent.
+    #[default]
+    AlwaysNormal,
+    /// Reply with the code the peer sent, as RFC 6455 suggests.
+    EchoPeerCode,
+    /// Reply with the code returned for the peer's code, which is 1005 if their close
+    /// frame had none.
+    Map(fn(u16) -> u16),
+}
+
+/// Default idle time before the peer is pinged.
+const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Default number of ping intervals a ping may go unanswered.
+const DEFAULT_PING_TIMEOUT_MULTIPLIER: u32 = 2;
+const DEFAULT_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Default maximum number of pings awaiting a pong.
+const DEFAULT_MAX_INFLIGHT_PINGS: usize = 8;
+
+/// Default maximum number of control frames queued for the writer.
+const DEFAULT_MAX_QUEUED_CONTROL_FRAMES: usize = 32;
+
+/// Default number of items in each of a connection's queues.
+const DEFAULT_CHANNEL_CAPACITY: usize = 64;
+
+/// Settings applied to each [`WebSocket`](crate::WebSocket) connection.
+///
+/// Start from [`WebSocketConfig::default`] and override individual settings
+/// with the builder methods.
+#[derive(Debug, Clone)]
+pub struct WebSocketConfig {
+    pub(crate) read_buffer_size: usize,
+    pub(crate) inflate_ratio_warning: Option<usize>,
+    pub(crate) max_send_frame_size: usize,
+    pub(crate) idle_read_timeout: Option<Duration>,
+    pub(crate) observer: Option<Observer>,
+    pub(crate) ping_policy: PingPolicy,
+    pub(crate) close_policy: ClosePolicy,
+    pub(crate) max_inflight_pings: usize,
+    pub(crate) max_queued_control_frames: usize,
+    pub(crate) drain_marks: Option<(usize, usize)>,
+    pub(crate) max_deflate_memory: Option<usize>,
+    pub(crate) send_window: Option<usize>,
+    pub(crate) message_metadata: bool,
+    pub(crate) auto_pong: bool,
+    pub(crate) ping_interval: Option<Duration>,
+    pub(crate) ping_timeout_multiplier: u32,
+    pub(crate) close_timeout: Duration,
+    pub(crate) channel_capacity: usize,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self {
+            read_buffer_size: DEFAULT_READ_BUFFER_SIZE,
+            inflate_ratio_warning: Some(DEFAULT_INFLATE_RATIO_WARNING),
+            max_send_frame_size: DEFAULT_MAX_SEND_FRAME_SIZE,
+            idle_read_timeout: None,
+            observer: None,
+            ping_policy: PingPolicy::Strict,
+            close_policy: ClosePolicy::AlwaysNormal,
+            max_inflight_pings: DEFAULT_MAX_INFLIGHT_PINGS,
+            max_queued_control_frames: DEFAULT_MAX_QUEUED_CONTROL_FRAMES,
+            drain_marks: None,
+            max_deflate_memory: None,
+            send_window: None,
+            message_metadata: false,
+            auto_pong: true,
+            ping_interval: Some(DEFAULT_PING_INTERVAL),
+            ping_timeout_multiplier: DEFAULT_PING_TIMEOUT_MULTIPLIER,
+            close_timeout: DEFAULT_CLOSE_TIMEOUT,
+            channel_capacity: DEFAULT_CHANNEL_CAPACITY,
+        }
+    }
+}
+
+impl WebSocketConfig {
+    /// Sets the initial capacity of the socket read buffer, 8K by default.
+    ///
+    /// This is independent of the maximum frame size. Smaller buffers save
+    /// memory on mostly idle connections, larger buffers reduce the number of
+    /// reads on busy connections.
+    ///
+    /// While the application is not receiving and the event queue is full, pings are
+    /// still answered. Until then about this many bytes of later frames are held back,
+    /// after which reading pauses.
+    #[must_use]
+    pub fn read_buffer_size(mut self, size: usize) -> Self {
+        self.read_buffer_size = size;
+        self
+    }
+
+    /// Sets the compression ratio (inflated:compressed) above which a warning
+    /// is logged for an inbound message, 1000:1 by default.
+    ///
+    /// Extreme ratios are a sign of deflate bombs, so this lets operators
+    /// notice probing even when messages stay under the size limit.
+    /// `None` disables the warning.
+    #[must_use]
+    pub fn inflate_ratio_warning(mut self, ratio: Option<usize>) -> Self {
+        self.inflate_ratio_warning = ratio;
+        self
+    }
+
+    /// Sets the maximum payload length in bytes of each frame sent, 16K by default.
+    ///
+    /// Larger messages are fragmented into frames of at most this size. Smaller frames let
+    /// control frames such as pings interleave sooner, and suit peers or intermediaries which
+    /// prefer them. This is independent of the limit on inbound frames. Values below 1 are
+    /// treated as 1.
+    #[must_use]
+    pub fn max_send_frame_size(mut self, size: usize) -> Self {
+        self.max_send_frame_size = size.max(1);
+        self
+    }
+
+    /// Sets how long the connection may go without receiving any bytes before it is
+    /// closed with `1001 Going Away`, disabled by default.
+    ///
+    /// Unlike the ping interval, this bounds how long the peer may stay silent whether or
+    /// not it answers pings, since only our own pings are sent in the meantime.
+    #[must_use]
+    pub fn idle_read_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_read_timeout = timeout;
+        self
+    }
+
+    /// Sets a callback which is told about each [`Observation`] on the connection, such
+    /// as messages sent and received, pings and pongs, and closing.
+    ///
+    /// The callback runs on the connection's tasks so it should return quickly.
+    #[must_use]
+    pub fn observer<F: Fn(&Observation) + Send + Sync + 'static>(mut self, f: F) -> Self {
+        self.observer = Some(Observer::new(f));
+        self
+    }
+
+    /// Sets how pings and pongs from the peer are handled, [`PingPolicy::Strict`] by default.
+    #[must_use]
+    pub fn ping_policy(mut self, policy: PingPolicy) -> Self {
+        self.ping_policy = policy;
+        self
+    }
+
+    /// Sets how the peer's close frame is answered, [`ClosePolicy::AlwaysNormal`] by default.
+    ///
+    /// Codes which may not be sent, e.g. a mapping returning 1005, are replaced with 1000.
+    /// Peers sending a code reserved for local use are always answered with 1002.
+    #[must_use]
+    pub fn close_policy(mut self, policy: ClosePolicy) -> Self {
+        self.close_policy = policy;
+        self
+    }
+
+    /// Sets how long the connection may sit idle before the peer is pinged, 30 seconds
+    /// by default, `None` to never ping. Anything read from the peer counts as activity.
+    ///
+    /// Short intervals keep flaky connections, e.g. on mobile networks, from being
+    /// dropped by middleboxes. See also [`WebSocket::set_ping_interval`](crate::WebSocket::set_ping_interval).
+    #[must_use]
+    pub fn ping_interval(mut self, interval: Option<Duration>) -> Self {
+        self.ping_interval = interval;
+        self
+    }
+
+    /// Sets how many ping intervals a ping may go unanswered before the connection is
+    /// closed as dead, 2 by default. Values below 1 are treated as 1.
+    #[must_use]
+    pub fn ping_timeout_multiplier(mut self, multiplier: u32) -> Self {
+        self.ping_timeout_multiplier = multiplier.max(1);
+        self
+    }
+
+    /// Sets how long to wait for the peer to answer our close before the connection is
+    /// torn down anyway, 5 seconds by default.
+    ///
+    /// The stream is only shut down once the closing handshake completes, so that the
+    /// peer's close can still be read and reported in [`Event::Closed`](crate::Event::Closed).
+    #[must_use]
+    pub fn close_timeout(mut self, timeout: Duration) -> Self {
+        self.close_timeout = timeout;
+        self
+    }
+
+    /// Sets how many pings may await a pong at once, 8 by default.
+    ///
+    /// Further pings fail with [`PingError::TooMany`](crate::PingError::TooMany) until pongs
+    /// arrive or the outstanding pings expire. They expire once unanswered for as long as
+    /// the [`ping_timeout_multiplier`](Self::ping_timeout_multiplier) allows, or after a
+    /// minute if the [`ping_interval`](Self::ping_interval) is `None`. This bounds the cost
+    /// of an application pinging in a tight loop. Values below 1 are treated as 1.
+    #[must_use]
+    pub fn max_inflight_pings(mut self, count: usize) -> Self {
+        self.max_inflight_pings = count.max(1);
+        self
+    }
+
+    /// Produces [`Event::Drained`](crate::Event::Drained) once at most `low` frames wait to
+    /// be written, after `high` or more have been waiting. Off by default.
+    ///
+    /// The marks count frames, not bytes: a message is queued as one frame per
+    /// [`max_send_frame_size`](Self::max_send_frame_size). A producer can pause sending once
+    /// it has queued enough to reach `high` and resume on the event. The send queue holds
+    /// [`channel_capacity`](Self::channel_capacity) frames, so higher marks are never
+    /// reached. The event is dropped if the event queue is full. `low` is clamped below
+    /// `high`.
+    #[must_use]
+    pub fn drain_events(mut self, high: usize, low: usize) -> Self {
+        self.drain_marks = Some((high.max(1), low.min(high.saturating_sub(1))));
+        self
+    }
+
+    /// Sets how many control frames may wait for the writer, 32 by default.
+    ///
+    /// When the queue is full, pongs answering the peer's pings are dropped rather than
+    /// stalling the reader, so a ping flood cannot stop us reading the peer's close.
+    /// Values below 1 are treated as 1.
+    #[must_use]
+    pub fn max_queued_control_frames(mut self, count: usize) -> Self {
+        self.max_queued_control_frames = count.max(1);
+        self
+    }
+
+    /// Delivers messages as [`Event::Message`](crate::Event::Message), which records
+    /// whether each was compressed and how many frames it arrived in, off by default.
+    ///
+    /// Useful for protocol analytics, e.g. spotting peers which fragment heavily.
+    #[must_use]
+    pub fn message_metadata(mut self, enabled: bool) -> Self {
+        self.message_metadata = enabled;
+        self
+    }
+
+    /// Sets whether pings from the peer are answered automatically, on by default.
+    ///
+    /// Pings are reported as [`Event::Ping`](crate::Event::Ping) either way. With automatic
+    /// replies off, answer them with [`WebSocket::pong`](crate::WebSocket::pong), echoing
+    /// the payload, or the peer may consider the connection dead.
+    #[must_use]
+    pub fn auto_pong(mut self, enabled: bool) -> Self {
+        self.auto_pong = enabled;
+        self
+    }
+
+    /// Sets how many items each of a connection's internal queues holds, 64 by default.
+    ///
+    /// Outgoing data frames and received events each have a queue of this size. Sending
+    /// waits while the send queue is full, and
+    /// [`try_send_text`](crate::WebSocket::try_send_text) fails instead. Values below 1
+    /// are treated as 1.
+    #[must_use]
+    pub fn channel_capacity(mut self, capacity: usize) -> Self {
+        self.channel_capacity = capacity.max(1);
+        self
+    }
+
+    /// Caps the bytes of frames queued for the writer or being written, unlimited by default.
+    ///
+    /// Sending waits while the window is full and resumes as the writer flushes frames,
+    /// so a slow peer bounds the memory of a connection in bytes rather than in queued
+    /// frames of any size. Frames are counted as encoded. A single frame larger than
+    /// the window is sent on its own once the window is empty.
+    #[must_use]
+    pub fn send_window(mut self, bytes: usize) -> Self {
+        self.send_window = Some(bytes);
+        self
+    }
+
+    /// Caps the memory a connection may spend on permessage-deflate, unlimited by default.
+    ///
+    /// A compressor and decompressor with the 15 bit window, the only size negotiated,
+    /// take an estimated 300K together. With a lower cap compression is neither offered
+    /// nor accepted, see [`WebSocket::deflate_memory`](crate::WebSocket::deflate_memory).
+    #[must_use]
+    pub fn max_deflate_memory(mut self, bytes: usize) -> Self {
+        self.max_deflate_memory = Some(bytes);
+        self
+    }
+
+    // Whether a compressed connection fits within `max_deflate_memory`
+    pub(crate) fn deflate_fits(&self) -> bool {
+        self.max_deflate_memory
+            .is_none_or(|max| max >= crate::protocol::DEFLATE_MEMORY)
+    }
+}
+
+// Kernel buffer sizes requested for TCP sockets, the OS defaults when unset
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct SocketBuffers {
+    pub(crate) send: Option<usize>,
+    pub(crate) recv: Option<usize>,
+}
+
+// Limits on the server's handshake response, the same defaults as `UpgradeConfig`
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ResponseLimits {
+    pub(crate) status_line: usize,
+    pub(crate) header_bytes: usize,
+    pub(crate) headers: usize,
+}
+
+impl Default for ResponseLimits {
+    fn default() -> Self {
+        Self {
+            status_line: DEFAULT_MAX_REQUEST_LINE,
+            header_bytes: DEFAULT_MAX_HEADER_BYTES,
+            headers: DEFAULT_MAX_HEADERS,
+        }
+    }
+}
+
+/// Default maximum length of the handshake request or status line.
+const DEFAULT_MAX_REQUEST_LINE: usize = 8 * 1024; // 8K
+/// Default maximum total length of the handshake headers.
+const DEFAULT_MAX_HEADER_BYTES: usize = 16 * 1024; // 16K
+/// Default maximum number of handshake header lines.
+const DEFAULT_MAX_HEADERS: usize = 64;
+/// Default time allowed for a client to complete the handshake.
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Limits applied while reading a handshake, guarding servers against slow or
+/// oversized upgrade requests.
+///
+/// Set on a server with [`WebSocketServer::with_upgrade_config`](crate::WebSocketServer::with_upgrade_config).
+/// Clients set the limits on the server's response through the
+/// [`ClientBuilder`](crate::ClientBuilder) instead.
+#[derive(Debug, Clone)]
+pub struct UpgradeConfig {
+    pub(crate) max_request_line: usize,
+    pub(crate) max_header_bytes: usize,
+    pub(crate) max_headers: usize,
+    pub(crate) handshake_timeout: Option<Duration>,
+    pub(crate) allowed_origins: Option<Arc<[String]>>,
+    pub(crate) protocols: Arc<[String]>,
+    pub(crate) capture: Option<HandshakeCapture>,
+    pub(crate) hook: Option<UpgradeHook>,
+    // paths with a routed handler, any path is upgraded if `None`
+    pub(crate) routes: Option<Arc<[String]>>,
+}
+
+impl Default for UpgradeConfig {
+    fn default() -> Self {
+        Self {
+            max_request_line: DEFAULT_MAX_REQUEST_LINE,
+            max_header_bytes: DEFAULT_MAX_HEADER_BYTES,
+            max_headers: DEFAULT_MAX_HEADERS,
+            handshake_timeout: Some(DEFAULT_HANDSHAKE_TIMEOUT),
+            allowed_origins: None,
+            protocols: Arc::new([]),
+            capture: None,
+            hook: None,
+            routes: None,
+        }
+    }
+}
+
+impl UpgradeConfig {
+    /// Sets the maximum length in bytes of the first line of the handshake, 8K by default.
+    ///
+    /// This is the request line read by servers and the status line read by clients.
+    /// Longer lines fail the upgrade with [`UpgradeError::StatusLine`](crate::UpgradeError::StatusLine)
+    /// rather than being buffered without bound.
+    #[must_use]
+    pub fn max_request_line(mut self, len: usize) -> Self {
+        self.max_request_line = len;
+        self
+    }
+
+    /// Sets the maximum total length in bytes of the handshake headers, 16K by default.
+    #[must_use]
+    pub fn max_header_bytes(mut self, len: usize) -> Self {
+        self.max_header_bytes = len;
+        self
+    }
+
+    /// Sets the maximum number of handshake header lines, 64 by default.
+    #[must_use]
+    pub fn max_headers(mut self, count: usize) -> Self {
+        self.max_headers = count;
+        self
+    }
+
+    /// Sets how long a client has to send its upgrade request once connected, 10s by default.
+    ///
+    /// Slower handshakes fail with [`UpgradeError::Timeout`](crate::UpgradeError::Timeout).
+    /// This does not include the TLS handshake. `None` waits forever.
+    #[must_use]
+    pub fn handshake_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// Only accept upgrades from browsers on one of the given origins, e.g. `https://example.com`.
+    ///
+    /// See [`WebSocketServer::allowed_origins`](crate::WebSocketServer::allowed_origins).
+    #[must_use]
+    pub fn allowed_origins(mut self, origins: Vec<String>) -> Self {
+        self.allowed_origins = Some(origins.into());
+        self
+    }
+
+    /// Sets the subprotocols the server speaks, none by default.
+    ///
+    /// See [`WebSocketServer::protocols`](crate::WebSocketServer::protocols).
+    #[must_use]
+    pub fn protocols(mut self, protocols: Vec<String>) -> Self {
+        self.protocols = protocols.into();
+        self
+    }
+
+    /// Records the raw handshake of each connection into `capture`, including those
+    /// which fail, so the latest can be inspected.
+    #[must_use]
+    pub fn capture_handshake(mut self, capture: &HandshakeCapture) -> Self {
+        self.capture = Some(capture.clone());
+        self
+    }
+
+    /// Sets a callback which decides whether to accept each valid upgrade request, e.g.
+    /// to check an authentication header.
+    ///
+    /// Returning headers accepts the request and adds them to the `101 Switching Protocols`
+    /// response, e.g. a `Set-Cookie` carrying a session token. Headers whose name or value
+    /// would break the response are left out. Returning an [`HttpError`] refuses the
+    /// request with that status instead. The callback runs on the connection's task, so it
+    /// should return quickly.
+    #[must_use]
+    pub fn on_upgrade<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&UpgradeRequest) -> Result<Vec<(String, String)>, HttpError> + Send + Sync + 'static,
+    {
+        self.hook = Some(UpgradeHook::new(f));
+        self
+    }
+}
+
+/// How [`WebSocketClient::connect_retry`](crate::WebSocketClient::connect_retry)
+/// retries a failed TCP connection.
+#[derive(Debug, Clone)]
+pub struct RetryConfig {
+    pub(crate) attempts: u32,
+    pub(crate) delay: Duration,
+}
+
+impl Default for RetryConfig {
+    /// Five attempts, half a second apart.
+    fn default() -> Self { Self::new(5, Duration::from_millis(500)) }
+}
+
+impl RetryConfig {
+    /// Makes up to `attempts` connection attempts in total, waiting `delay` between each.
+    #[must_use]
+    pub fn new(attempts: u32, delay: Duration) -> Self { Self { attempts, delay } }
+
+    // a single attempt, no retries
+    pub(crate) fn once() -> Self { Self::new(1, Duration::ZERO) }
+}
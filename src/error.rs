@@ -1,8 +1,8 @@
 /// Close reason codes as specified in
 /// [RFC 6455](https://www.rfc-editor.org/rfc/rfc6455.html#section-7.4)
 #[repr(u16)]
-#[derive(Debug)]
-pub(crate) enum CloseReason {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
     /// Normal close
     Normal = 1000,
     /// Going away
@@ -27,12 +27,22 @@ pub(crate) enum CloseReason {
     Extension = 1010,
     /// An unexpected condition that prevented the request from being fulfilled
     Unexpected = 1011,
+    /// The service is restarting, clients may reconnect
+    Restart = 1012,
+    /// The service is overloaded, clients should back off before reconnecting
+    TryAgainLater = 1013,
+    /// A gateway or proxy got an invalid response from upstream
+    BadGateway = 1014,
     /// TLS error
     Tls = 1015,
     /// Other valid codes with unknown meanings
     Unknown = 4000, // private use code
 }
 
+impl From<CloseReason> for u16 {
+    fn from(value: CloseReason) -> Self { value as u16 }
+}
+
 /// Converts a reason code to bytes of the appropriate endianness.
 impl From<CloseReason> for [u8; 2] {
     fn from(value: CloseReason) -> Self { (value as u16).to_be_bytes() }
@@ -53,18 +63,65 @@ impl From<[u8; 2]> for CloseReason {
             1009 => CloseReason::TooBig,
             1010 => CloseReason::Extension,
             1011 => CloseReason::Unexpected,
+            1012 => CloseReason::Restart,
+            1013 => CloseReason::TryAgainLater,
+            1014 => CloseReason::BadGateway,
             1015 => CloseReason::Tls,
             _ => CloseReason::Unknown,
         }
     }
 }
 
+/// Errors from sending on a connection, e.g. with
+/// [`WebSocket::send_text`](crate::WebSocket::send_text).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendError {
+    /// The connection has closed, or was torn down without a closing handshake.
+    Closed,
+    /// We have started closing, nothing more may be sent after our close frame.
+    Closing,
+    /// The message is longer than the 16M a message may be, or a ping or pong payload
+    /// is longer than 125 bytes.
+    TooLarge,
+    /// The close code may not be sent in a close frame, e.g. 1005 or 1006.
+    InvalidCloseCode,
+}
+
+/// Errors from sending without waiting, e.g. with
+/// [`WebSocket::try_send_text`](crate::WebSocket::try_send_text).
+#[derive(Debug)]
+pub enum TrySendError {
+    /// The send queue has no room for the message, which is handed back to be dropped,
+    /// coalesced with later ones or retried.
+    Full(crate::Message),
+    /// The message cannot be sent, as with the waiting send.
+    Failed(SendError),
+}
+
+impl From<SendError> for TrySendError {
+    fn from(e: SendError) -> Self { Self::Failed(e) }
+}
+
+/// Errors from pinging the peer, e.g. with [`WebSocket::ping_rtt`](crate::WebSocket::ping_rtt).
+#[derive(Debug)]
+pub enum PingError {
+    /// The connection closed before the pong arrived.
+    Closed,
+    /// The pong did not arrive in time.
+    Timeout,
+    /// Too many pings are already awaiting a pong.
+    TooMany,
+    /// The control queue has no room for the ping, or the ping statistics are in use, see
+    /// [`WebSocket::try_ping`](crate::WebSocket::try_ping).
+    Full,
+}
+
 /// Errors that can occur when upgrading a TCP stream to a WebSocket.
 #[derive(Debug)]
 pub enum UpgradeError {
     /// Tried to connect to invalid url.
     InvalidUrl,
-    /// Failed to bind TCP listener.
+    /// Failed to bind TCP listener, or to set its socket options.
     Bind,
     /// Failed to read from the TCP stream.
     Read,
@@ -94,4 +151,27 @@ pub enum UpgradeError {
     Timeout,
     /// Protocol mismatch
     Protocol,
+    /// TLS could not be set up, with the reason: a server's certificate or private key
+    /// could not be loaded, or a client's handshake failed, e.g. over an untrusted certificate.
+    Tls(String),
+    /// The request's `Origin` is not in the server's allowlist.
+    Origin(String),
+    /// The server's [`on_upgrade`](crate::UpgradeConfig::on_upgrade) callback refused
+    /// the request with this HTTP status.
+    Rejected(u16),
+    /// No handler is [routed](crate::WebSocketServer::route) for the request path, which
+    /// is given.
+    NotFound(String),
+    /// The handshake headers exceeded the configured size or count.
+    HeadersTooLarge,
+    /// A header added to the upgrade request has a line break in its value, the name
+    /// of the header is given.
+    InvalidHeader(String),
+    /// The server's `Sec-WebSocket-Accept` did not match the key we sent.
+    AcceptMismatch {
+        /// The value derived from our `Sec-WebSocket-Key`.
+        expected: String,
+        /// The value the server sent.
+        got: String,
+    },
 }
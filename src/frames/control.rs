@@ -8,12 +8,22 @@ pub(crate) fn ping<R: RolePolicy>(payload: &[u8]) -> Vec<u8> { encode::<R>(Opcod
 
 pub(crate) fn pong<R: RolePolicy>(payload: &[u8]) -> Vec<u8> { encode::<R>(Opcode::Pong, payload) }
 
-pub(crate) fn close<R: RolePolicy>(reason: CloseReason, text: &'static str) -> Vec<u8> {
+pub(crate) fn close<R: RolePolicy>(reason: CloseReason, text: &str) -> Vec<u8> {
+    close_code::<R>(reason as u16, text)
+}
+
+// Whether `code` may appear in a close frame, RFC 6455 section 7.4. 1004 is reserved,
+// 1005 and 1006 only stand in for a missing code, 1015 is for TLS failures
+pub(crate) fn sendable(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+}
+
+// Close with a raw code, for codes which have no `CloseReason`
+pub(crate) fn close_code<R: RolePolicy>(code: u16, text: &str) -> Vec<u8> {
     let mut payload = [0; 125];
 
     // push code bytes
-    let code: [u8; 2] = reason.into();
-    payload[..2].copy_from_slice(&code);
+    payload[..2].copy_from_slice(&code.to_be_bytes());
 
     // limit text length to 123
     let len = text.floor_char_boundary(123);
@@ -25,11 +35,13 @@ pub(crate) fn close<R: RolePolicy>(reason: CloseReason, text: &'static str) -> V
 // encoding: sets Opcode, FIN, MASK and optionally masks payload
 #[allow(clippy::cast_possible_truncation)]
 fn encode<R: RolePolicy>(opcode: Opcode, payload: &[u8]) -> Vec<u8> {
-    tracing::trace!(
-        opcode = ?opcode,
-        len = payload.len(),
-        "encoding CTRL"
-    );
+    if tracing::enabled!(tracing::Level::TRACE) {
+        tracing::trace!(
+            opcode = ?opcode,
+            len = payload.len(),
+            "encoding CTRL"
+        );
+    }
 
     let mut buf = [0; 131]; // max single frame size
     buf[0] = opcode as u8 | 0x80; // always set FIN
@@ -87,3 +99,19 @@ mod bench {
 
     bench_data_sizes!(0, 1, 4, 16, 32, 64, 125);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::role::Server;
+
+    #[test]
+    fn close_reason_truncated_on_char_boundary() {
+        let reason = format!("error {}", "é".repeat(100));
+        let frame = close::<Server>(CloseReason::Unexpected, &reason);
+        let payload = &frame[2..];
+        assert_eq!(payload.len(), 2 + 122);
+        assert_eq!(&payload[..2], &1011u16.to_be_bytes());
+        assert!(reason.starts_with(str::from_utf8(&payload[2..]).unwrap()));
+    }
+}
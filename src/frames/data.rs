@@ -1,69 +1,354 @@
-use std::io::Write;
+use std::{
+    io::Write,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 use bytes::{BufMut, Bytes, BytesMut};
-use flate2::write::DeflateEncoder;
-use tokio::sync::mpsc::{Sender, error::SendError};
+use flate2::{
+    Compression,
+    write::{DeflateDecoder, DeflateEncoder},
+};
+use tokio::sync::{
+    mpsc::{
+        Sender,
+        error::{SendError, TrySendError},
+    },
+    oneshot,
+};
 
-use super::Opcode;
-use crate::{MAX_FRAME_PAYLOAD, MAX_MESSAGE_SIZE, role::RolePolicy};
+use super::{Opcode, SendWindow};
+use crate::role::RolePolicy;
+
+// Items queued for the writer task on the data channel
+pub(crate) enum Outgoing {
+    Frame(Bytes),
+    // header and payload of an unmasked frame, written without joining them
+    Split(Bytes, Bytes),
+    // acknowledged once every frame queued before it has been flushed
+    Flush(oneshot::Sender<()>),
+}
+
+// The writer's data channel, along with the count of bytes queued on it
+#[derive(Clone, Copy)]
+pub(crate) struct DataChannel<'a> {
+    pub(crate) tx: &'a Sender<Outgoing>,
+    pub(crate) buffered: &'a AtomicUsize,
+    pub(crate) window: Option<&'a SendWindow>,
+}
+
+// Encodes data frames into a buffer kept across sends. Once the writer has released
+// earlier frames their memory is reclaimed, so steady traffic stops allocating.
+pub(crate) struct FrameEncoder {
+    buf: BytesMut,
+    frame_size: usize,
+}
+
+impl FrameEncoder {
+    pub(crate) fn new(frame_size: usize) -> Self {
+        Self {
+            // room for one full frame and its header
+            buf: BytesMut::with_capacity(frame_size + 14),
+            frame_size,
+        }
+    }
+
+    // maximum payload length of each frame
+    pub(crate) fn frame_size(&self) -> usize { self.frame_size }
+
+    fn frame<R: RolePolicy>(
+        &mut self,
+        opcode: Opcode,
+        chunk: &[u8],
+        first: &mut bool,
+        last: bool,
+        compressed: bool,
+    ) -> Bytes {
+        self.buf.reserve(chunk.len() + 14);
+        single_frame::<R>(&mut self.buf, opcode, chunk, first, last, compressed);
+        self.buf.split().freeze()
+    }
+
+    fn header(&mut self, opcode: Opcode, len: usize, first: &mut bool, last: bool) -> Bytes {
+        self.buf.reserve(10);
+        frame_header(&mut self.buf, opcode, len, first, last, false);
+        self.buf.split().freeze()
+    }
+}
 
 // DataFrames may be fragmented or very large hence they need extra processing compared to ControlFrames
 pub(crate) async fn data<R: RolePolicy>(
-    data_tx: &Sender<Bytes>,
+    chan: DataChannel<'_>,
     payload: &[u8],
     opcode: Opcode,
     deflater: &mut Option<DeflateEncoder<Vec<u8>>>,
     use_context: bool,
-) -> Result<(), SendError<Bytes>> {
-    if let Some(deflater) = deflater {
-        let init_size = payload.len();
+    dictionary: Option<&[u8]>,
+    encoder: &mut FrameEncoder,
+) -> Result<(), SendError<Outgoing>> {
+    let (payload, compressed) = deflate(payload, deflater, use_context, dictionary);
+    all_frames::<R>(chan, opcode, payload, compressed, encoder).await
+}
+
+// Frame a whole message up front, for callers which queue the frames themselves
+pub(crate) fn encode<R: RolePolicy>(
+    payload: &[u8],
+    opcode: Opcode,
+    deflater: &mut Option<DeflateEncoder<Vec<u8>>>,
+    use_context: bool,
+    dictionary: Option<&[u8]>,
+    encoder: &mut FrameEncoder,
+) -> Vec<Outgoing> {
+    let (payload, compressed) = deflate(payload, deflater, use_context, dictionary);
+    let mut first = true;
+    // an empty payload is still sent as a single empty frame
+    let count = payload.len().div_ceil(encoder.frame_size).max(1);
+    (0..count)
+        .map(|i| {
+            let end = payload.len().min((i + 1) * encoder.frame_size);
+            let chunk = &payload[i * encoder.frame_size..end];
+            let frame = encoder.frame::<R>(opcode, chunk, &mut first, i + 1 == count, compressed);
+            Outgoing::Frame(frame)
+        })
+        .collect()
+}
 
-        let end = if use_context {
-            deflater.get_ref().len()
+// Why `try_data` queued nothing
+#[derive(Debug)]
+pub(crate) enum TryDataError {
+    Full,
+    Closed,
+    // more frames than the queue holds, it would never fit
+    TooLarge,
+}
+
+// Queue a whole message without waiting, or none of it. Room is reserved before
+// compressing, as compressing with context takeover cannot be undone.
+pub(crate) fn try_data<R: RolePolicy>(
+    chan: DataChannel<'_>,
+    payload: &[u8],
+    opcode: Opcode,
+    deflater: &mut Option<DeflateEncoder<Vec<u8>>>,
+    use_context: bool,
+    dictionary: Option<&[u8]>,
+    encoder: &mut FrameEncoder,
+) -> Result<(), TryDataError> {
+    // deflate grows incompressible data by a few bytes per stored block
+    let bound = if deflater.is_some() {
+        payload.len() + payload.len() / 1024 + 32
+    } else {
+        payload.len()
+    };
+    let frame_size = encoder.frame_size;
+    let frames = bound.div_ceil(frame_size).max(1);
+    if frames > chan.tx.max_capacity() {
+        return Err(TryDataError::TooLarge);
+    }
+    let permits = chan.tx.try_reserve_many(frames).map_err(|e| match e {
+        TrySendError::Full(()) => TryDataError::Full,
+        TrySendError::Closed(()) => TryDataError::Closed,
+    })?;
+    // each frame has a header of at most 14 bytes
+    let reserved = bound + frames * 14;
+    if let Some(window) = chan.window
+        && !window.try_acquire(reserved)
+    {
+        return Err(TryDataError::Full);
+    }
+
+    let (payload, compressed) = deflate(payload, deflater, use_context, dictionary);
+    // should the output outgrow its bound, the last frame reserved carries the rest
+    let count = payload.len().div_ceil(frame_size).clamp(1, frames);
+    let mut first = true;
+    let mut queued = 0;
+    for (i, permit) in permits.take(count).enumerate() {
+        let last = i + 1 == count;
+        let end = if last {
+            payload.len()
         } else {
-            let _ = deflater.reset(vec![]);
-            0
+            (i + 1) * frame_size
         };
+        let chunk = &payload[i * frame_size..end];
+        let frame = encoder.frame::<R>(opcode, chunk, &mut first, last, compressed);
+        queued += frame.len();
+        chan.buffered.fetch_add(frame.len(), Ordering::Relaxed);
+        permit.send(Outgoing::Frame(frame));
+    }
+    if let Some(window) = chan.window {
+        window.settle(reserved, queued);
+    }
+    Ok(())
+}
+
+// The bytes to frame for `payload`, deflated if compression is on, and whether they were
+fn deflate<'a>(
+    payload: &'a [u8],
+    deflater: &'a mut Option<DeflateEncoder<Vec<u8>>>,
+    use_context: bool,
+    dictionary: Option<&[u8]>,
+) -> (&'a [u8], bool) {
+    let Some(deflater) = deflater else {
+        return (payload, false);
+    };
+    let init_size = payload.len();
+
+    if !use_context {
+        let _ = deflater.reset(vec![]);
+        // without context takeover the dictionary is lost on every reset
+        if let Some(dictionary) = dictionary {
+            prime_dictionary(deflater, dictionary);
+        }
+    }
+    // earlier output was sent already, keeping it would grow the buffer forever
+    deflater.get_mut().clear();
+
+    let _ = deflater.write_all(payload);
+    let _ = deflater.flush();
+
+    let b = strip_tail(deflater.get_ref());
+    tracing::trace!("deflated {init_size} -> {}", b.len());
+    (b, true)
+}
+
+// Send an uncompressed message from the server without copying the payload, each
+// frame's header is queued alongside a slice of it
+pub(crate) async fn data_shared<R: RolePolicy>(
+    chan: DataChannel<'_>,
+    payload: Bytes,
+    opcode: Opcode,
+    encoder: &mut FrameEncoder,
+) -> Result<(), SendError<Outgoing>> {
+    debug_assert!(R::SERVER, "client frames must be masked");
+    let mut first = true;
+    let mut offset = 0;
+    loop {
+        let len = encoder.frame_size.min(payload.len() - offset);
+        let last = offset + len == payload.len();
+        let header = encoder.header(opcode, len, &mut first, last);
+        let chunk = payload.slice(offset..offset + len);
+        send(chan, Outgoing::Split(header, chunk)).await?;
+        offset += len;
+        if last {
+            return Ok(());
+        }
+    }
+}
+
+// Encode one fragment of a message which is streamed rather than sent whole
+pub(crate) fn fragment<R: RolePolicy>(
+    payload: &[u8],
+    opcode: Opcode,
+    deflater: &mut Option<DeflateEncoder<Vec<u8>>>,
+    use_context: bool,
+    dictionary: Option<&[u8]>,
+    first: &mut bool,
+    last: bool,
+) -> Bytes {
+    let mut buf = BytesMut::with_capacity(payload.len() + 14);
+    if let Some(deflater) = deflater {
+        // the whole message is one deflate stream, so only reset before the first fragment
+        if *first && !use_context {
+            let _ = deflater.reset(vec![]);
+            if let Some(dictionary) = dictionary {
+                prime_dictionary(deflater, dictionary);
+            }
+        }
+        deflater.get_mut().clear();
 
         let _ = deflater.write_all(payload);
         let _ = deflater.flush();
-        let _ = deflater.flush();
-
-        let b = &deflater.get_ref()[end..];
-        tracing::trace!("deflated {init_size} -> {}", b.len());
 
-        all_frames::<R>(data_tx, opcode, b, true).await
+        // earlier fragments keep theirs, the stream only ends after the last one
+        let b = deflater.get_ref();
+        let b = if last { strip_tail(b) } else { b };
+        single_frame::<R>(&mut buf, opcode, b, first, last, true);
     } else {
-        all_frames::<R>(data_tx, opcode, payload, false).await
+        single_frame::<R>(&mut buf, opcode, payload, first, last, false);
     }
+    buf.freeze()
+}
+
+// A sync flush ends with an empty stored block, which is left off the end of each
+// message and added back by the receiver, RFC 7692 section 7.2.1
+pub(crate) const DEFLATE_TAIL: [u8; 4] = [0, 0, 0xFF, 0xFF];
+
+fn strip_tail(compressed: &[u8]) -> &[u8] {
+    compressed.strip_suffix(&DEFLATE_TAIL).unwrap_or(compressed)
+}
+
+// Seed the sliding window of the encoder with a dictionary, the output is
+// never sent so the peer must prime its inflater with the same dictionary
+pub(crate) fn prime_dictionary(deflater: &mut DeflateEncoder<Vec<u8>>, dictionary: &[u8]) {
+    let _ = deflater.write_all(dictionary);
+    let _ = deflater.flush();
+}
+
+// Seed the sliding window of the decoder with the dictionary the peer primed its encoder
+// with, by inflating the dictionary as stored blocks and discarding the output
+pub(crate) fn prime_inflater(inflater: &mut DeflateDecoder<Vec<u8>>, dictionary: &[u8]) {
+    let mut stored = DeflateEncoder::new(Vec::new(), Compression::none());
+    let _ = stored.write_all(dictionary);
+    let _ = stored.flush();
+    let _ = inflater.write_all(stored.get_ref());
+    let _ = inflater.flush();
+    inflater.get_mut().clear();
 }
 
 async fn all_frames<R: RolePolicy>(
-    data_tx: &Sender<Bytes>,
+    chan: DataChannel<'_>,
     opcode: Opcode,
     payload: &[u8],
     compressed: bool,
-) -> Result<(), SendError<Bytes>> {
+    encoder: &mut FrameEncoder,
+) -> Result<(), SendError<Outgoing>> {
     let mut first = true;
-    let mut buf = BytesMut::with_capacity(MAX_MESSAGE_SIZE);
 
-    let (chunked, remainder) = payload.as_chunks::<MAX_FRAME_PAYLOAD>();
-
-    for (i, chunk) in chunked.iter().enumerate() {
-        let last = remainder.is_empty() && i == chunked.len() - 1;
-        single_frame::<R>(&mut buf, opcode, chunk, &mut first, last, compressed);
-        data_tx.send(buf.split().freeze()).await?;
-        buf.clear();
+    // an empty payload is still sent as a single empty frame
+    if payload.is_empty() {
+        let frame = encoder.frame::<R>(opcode, payload, &mut first, true, compressed);
+        return send_frame(chan, frame).await;
     }
 
-    // if there is a remainder, send as last or if empty payload, send empty
-    if !remainder.is_empty() || payload.is_empty() {
-        single_frame::<R>(&mut buf, opcode, remainder, &mut first, true, compressed);
-        data_tx.send(buf.freeze()).await?;
+    let mut chunks = payload.chunks(encoder.frame_size).peekable();
+    while let Some(chunk) = chunks.next() {
+        let last = chunks.peek().is_none();
+        let frame = encoder.frame::<R>(opcode, chunk, &mut first, last, compressed);
+        send_frame(chan, frame).await?;
     }
     Ok(())
 }
 
+async fn send_frame(chan: DataChannel<'_>, frame: Bytes) -> Result<(), SendError<Outgoing>> {
+    send(chan, Outgoing::Frame(frame)).await
+}
+
+async fn send(chan: DataChannel<'_>, item: Outgoing) -> Result<(), SendError<Outgoing>> {
+    let len = item.len();
+    if let Some(window) = chan.window
+        && !window.acquire(len, chan.tx).await
+    {
+        return Err(SendError(item));
+    }
+    chan.buffered.fetch_add(len, Ordering::Relaxed);
+    chan.tx.send(item).await.map_err(|SendError(item)| {
+        chan.buffered.fetch_sub(len, Ordering::Relaxed);
+        if let Some(window) = chan.window {
+            window.release(len);
+        }
+        SendError(item)
+    })
+}
+
+impl Outgoing {
+    // bytes counted as buffered while this waits for the writer
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Self::Frame(frame) => frame.len(),
+            Self::Split(header, payload) => header.len() + payload.len(),
+            Self::Flush(_) => 0,
+        }
+    }
+}
+
 fn single_frame<R: RolePolicy>(
     buf: &mut BytesMut,
     opcode: Opcode,
@@ -72,15 +357,46 @@ fn single_frame<R: RolePolicy>(
     last: bool,
     compressed: bool,
 ) {
-    tracing::trace!(
-        opcode = ?opcode,
-        len = chunk.len(),
-        first = first,
-        fin = last,
-        compressed = compressed,
-        "encoding DATA"
-    );
+    // skip building the event entirely in the send hot path unless tracing
+    if tracing::enabled!(tracing::Level::TRACE) {
+        tracing::trace!(
+            opcode = ?opcode,
+            len = chunk.len(),
+            first = first,
+            fin = last,
+            compressed = compressed,
+            "encoding DATA"
+        );
+    }
+
+    frame_header(buf, opcode, chunk.len(), first, last, compressed);
+
+    // Clients must SEND masked
+    if R::CLIENT {
+        buf[1] |= 0x80; // set MASK bit
+        // get random bytes and push to buf
+        let mut mask_key = [0; 4];
+        rand::fill(&mut mask_key);
+        buf.extend_from_slice(&mask_key);
 
+        // mask bytes
+        let start = buf.len();
+        buf.extend_from_slice(chunk);
+        crate::protocol::mask(&mut buf[start..], mask_key);
+    } else {
+        buf.extend_from_slice(chunk);
+    }
+}
+
+// Push the first byte and payload length of a frame, without the MASK bit
+fn frame_header(
+    buf: &mut BytesMut,
+    opcode: Opcode,
+    len: usize,
+    first: &mut bool,
+    last: bool,
+    compressed: bool,
+) {
     let mut b1 = if *first { opcode } else { Opcode::Cont } as u8;
     b1 |= if last { 0b1000_0000 } else { 0 }; // set FIN
     b1 |= if *first && compressed { 0b0100_0000 } else { 0 }; // set RSV1
@@ -89,32 +405,82 @@ fn single_frame<R: RolePolicy>(
 
     // push LEN
     #[allow(clippy::cast_possible_truncation)]
-    match chunk.len() {
-        0..=125 => buf.put_u8(chunk.len() as u8),
+    match len {
+        0..=125 => buf.put_u8(len as u8),
         126..=65535 => {
             buf.put_u8(126);
-            buf.put_u16(chunk.len() as u16);
+            buf.put_u16(len as u16);
         }
         _ => {
             buf.put_u8(127);
-            buf.put_u64(chunk.len() as u64);
+            buf.put_u64(len as u64);
         }
     }
+}
 
-    // Clients must SEND masked
-    if R::CLIENT {
-        buf[1] |= 0x80; // set MASK bit
-        // get random bytes and push to buf
-        let mut mask_key = [0; 4];
-        rand::fill(&mut mask_key);
-        buf.extend_from_slice(&mask_key);
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc::{Receiver, channel};
 
-        // mask bytes
-        let start = buf.len();
-        buf.extend_from_slice(chunk);
-        crate::protocol::mask(&mut buf[start..], mask_key);
-    } else {
-        buf.extend_from_slice(chunk);
+    use super::*;
+    use crate::{MAX_FRAME_PAYLOAD, role::Server};
+
+    // the first byte of each frame queued
+    fn first_bytes(rx: &mut Receiver<Outgoing>) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        while let Ok(item) = rx.try_recv() {
+            match item {
+                Outgoing::Frame(frame) | Outgoing::Split(frame, _) => bytes.push(frame[0]),
+                Outgoing::Flush(_) => {}
+            }
+        }
+        bytes
+    }
+
+    #[tokio::test]
+    async fn fin_on_last_full_frame() {
+        let (tx, mut rx) = channel(8);
+        let buffered = AtomicUsize::new(0);
+        let chan = DataChannel {
+            tx: &tx,
+            buffered: &buffered,
+            window: None,
+        };
+        let mut encoder = FrameEncoder::new(MAX_FRAME_PAYLOAD);
+        let (bin, cont, fin) = (Opcode::Bin as u8, Opcode::Cont as u8, 0x80);
+
+        for (frames, expected) in [(1, vec![fin | bin]), (2, vec![bin, fin | cont])] {
+            let payload = vec![0; frames * MAX_FRAME_PAYLOAD];
+            data::<Server>(
+                chan,
+                &payload,
+                Opcode::Bin,
+                &mut None,
+                true,
+                None,
+                &mut encoder,
+            )
+            .await
+            .unwrap();
+            assert_eq!(first_bytes(&mut rx), expected);
+
+            data_shared::<Server>(chan, payload.clone().into(), Opcode::Bin, &mut encoder)
+                .await
+                .unwrap();
+            assert_eq!(first_bytes(&mut rx), expected);
+
+            try_data::<Server>(
+                chan,
+                &payload,
+                Opcode::Bin,
+                &mut None,
+                true,
+                None,
+                &mut encoder,
+            )
+            .unwrap();
+            assert_eq!(first_bytes(&mut rx), expected);
+        }
     }
 }
 
@@ -125,24 +491,103 @@ mod bench {
 
     use paste::paste;
     use test::Bencher;
-    use tokio::sync::{Mutex, mpsc::channel};
+    use tokio::sync::mpsc::channel;
 
     use super::*;
-    use crate::role::*;
+    use crate::{MAX_FRAME_PAYLOAD, role::*};
 
     #[allow(clippy::cast_possible_truncation)]
     fn make_payload(len: usize) -> Vec<u8> { (0..len).map(|i| i as u8).collect() }
 
     fn bench_data_frame<R: RolePolicy>(b: &mut Bencher, payload_len: usize) {
         let payload = make_payload(payload_len);
-        let (tx, rx) = channel(1);
-        let rx = Mutex::new(rx);
-        b.iter(async || {
-            data::<R>(&tx, &payload, Opcode::Text, &mut None, false)
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        // large enough to hold every frame of the biggest payload
+        let (tx, mut rx) = channel(64);
+        let buffered = AtomicUsize::new(0);
+        let mut encoder = FrameEncoder::new(MAX_FRAME_PAYLOAD);
+        b.iter(|| {
+            rt.block_on(async {
+                let chan = DataChannel {
+                    tx: &tx,
+                    buffered: &buffered,
+                    window: None,
+                };
+                data::<R>(
+                    chan,
+                    &payload,
+                    Opcode::Text,
+                    &mut None,
+                    false,
+                    None,
+                    &mut encoder,
+                )
                 .await
                 .unwrap();
-            while let Some(bytes) = rx.lock().await.recv().await {
-                black_box(bytes);
+            });
+            while let Ok(frame) = rx.try_recv() {
+                black_box(frame);
+            }
+        });
+    }
+
+    // throughput of many small messages, where per-send allocations dominate
+    fn bench_small_messages<R: RolePolicy>(b: &mut Bencher) {
+        let payload = make_payload(64);
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let (tx, mut rx) = channel(64);
+        let buffered = AtomicUsize::new(0);
+        let mut encoder = FrameEncoder::new(MAX_FRAME_PAYLOAD);
+        b.iter(|| {
+            rt.block_on(async {
+                let chan = DataChannel {
+                    tx: &tx,
+                    buffered: &buffered,
+                    window: None,
+                };
+                for _ in 0..1000 {
+                    let encoder = &mut encoder;
+                    data::<R>(chan, &payload, Opcode::Bin, &mut None, false, None, encoder)
+                        .await
+                        .unwrap();
+                    black_box(rx.try_recv().unwrap());
+                }
+            });
+        });
+    }
+
+    #[bench]
+    fn bench_client_small_messages(b: &mut Bencher) { bench_small_messages::<Client>(b); }
+
+    #[bench]
+    fn bench_server_small_messages(b: &mut Bencher) { bench_small_messages::<Server>(b); }
+
+    // the copy-free path servers take for binary they already own
+    fn bench_shared_frame(b: &mut Bencher, payload_len: usize) {
+        let payload = Bytes::from(make_payload(payload_len));
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let (tx, mut rx) = channel(64);
+        let buffered = AtomicUsize::new(0);
+        let mut encoder = FrameEncoder::new(MAX_FRAME_PAYLOAD);
+        b.iter(|| {
+            rt.block_on(async {
+                let chan = DataChannel {
+                    tx: &tx,
+                    buffered: &buffered,
+                    window: None,
+                };
+                data_shared::<Server>(chan, payload.clone(), Opcode::Bin, &mut encoder)
+                    .await
+                    .unwrap();
+            });
+            while let Ok(frame) = rx.try_recv() {
+                black_box(frame);
             }
         });
     }
@@ -158,9 +603,13 @@ mod bench {
             fn [<bench_server_ $len>](b: &mut Bencher) {
                 bench_data_frame::<Server>(b, $len);
             }
+            #[bench]
+            fn [<bench_server_shared_ $len>](b: &mut Bencher) {
+                bench_shared_frame(b, $len);
+            }
         })*
     };
     }
 
-    bench_data_sizes!(125, 1024, 4096, 16384, 32768);
+    bench_data_sizes!(125, 1024, 4096, 16384, 32768, 1_048_576);
 }
@@ -2,7 +2,7 @@ use std::{marker::PhantomData, ops::Deref};
 
 use bytes::{Bytes, BytesMut};
 
-use super::Opcode;
+use super::{control::sendable, Opcode};
 use crate::{role::RolePolicy, MAX_FRAME_PAYLOAD};
 
 // helper type since decoder errors return FrameParseResult
@@ -74,11 +74,15 @@ impl<P: RolePolicy> FrameDecoder<P> {
     pub(crate) fn push_bytes(&mut self, bytes: &[u8]) { self.buf.extend_from_slice(bytes); }
 
     pub(crate) fn next_frame(&mut self) -> Result<Option<FrameState>> {
-        tracing::trace!(
-            state = ?self.state,
-            buf_len = self.buf.len(),
-            "decoder"
-        );
+        // checked once per call rather than at each state transition
+        let trace = tracing::enabled!(tracing::Level::TRACE);
+        if trace {
+            tracing::trace!(
+                state = ?self.state,
+                buf_len = self.buf.len(),
+                "decoder"
+            );
+        }
         loop {
             let next_state = match self.state {
                 DecodeState::Header1 => {
@@ -110,13 +114,15 @@ impl<P: RolePolicy> FrameDecoder<P> {
                     };
                     self.state = DecodeState::Header1;
 
-                    tracing::trace!(
-                        opcode = ?self.ctx.opcode,
-                        fin = self.ctx.is_fin,
-                        payload_len = payload.len(),
-                        masked = self.ctx.mask_key[0] > 0,
-                        "frame decoded"
-                    );
+                    if trace {
+                        tracing::trace!(
+                            opcode = ?self.ctx.opcode,
+                            fin = self.ctx.is_fin,
+                            payload_len = payload.len(),
+                            masked = self.ctx.mask_key[0] > 0,
+                            "frame decoded"
+                        );
+                    }
                     return Ok(Some(FrameState::Complete(DecodedFrame {
                         opcode: self.ctx.opcode,
                         payload: payload.freeze(),
@@ -125,11 +131,13 @@ impl<P: RolePolicy> FrameDecoder<P> {
                     })));
                 }
             };
-            tracing::trace!(
-                from = ?self.state,
-                to = ?next_state,
-                "state transition"
-            );
+            if trace {
+                tracing::trace!(
+                    from = ?self.state,
+                    to = ?next_state,
+                    "state transition"
+                );
+            }
             self.state = next_state;
         }
     }
@@ -252,14 +260,14 @@ impl<P: RolePolicy> FrameDecoder<P> {
     }
 }
 
+// An empty payload, or a code which may be sent followed by a UTF-8 reason
 fn is_valid_close_payload(bytes: &[u8]) -> bool {
-    match bytes.len() {
-        0 => true,
-        1 => false,
-        _ => {
-            let code = u16::from_be_bytes([bytes[0], bytes[1]]);
-            matches!(code , 1000..=1011 | 3000..=4999) && str::from_utf8(&bytes[2..]).is_ok()
+    match bytes {
+        [] => true,
+        [hi, lo, reason @ ..] => {
+            sendable(u16::from_be_bytes([*hi, *lo])) && str::from_utf8(reason).is_ok()
         }
+        [_] => false,
     }
 }
 
@@ -344,6 +352,57 @@ mod tests {
         bytes
     }
 
+    #[test]
+    fn frames_split_across_reads() {
+        let frames = [
+            (Opcode::Close, [&1000u16.to_be_bytes()[..], b"bye"].concat()),
+            (Opcode::Ping, b"abc".to_vec()),
+            // a 16-bit extended length
+            (Opcode::Bin, vec![7; 300]),
+        ];
+        for (opcode, payload) in frames {
+            let bytes = build_frame_bytes(opcode, &payload, true, Client::SERVER);
+            for split in 0..=bytes.len() {
+                let mut decoder = FrameDecoder::<Client>::new(false);
+                decoder.push_bytes(&bytes[..split]);
+                if split < bytes.len() {
+                    assert!(!matches!(
+                        decoder.next_frame(),
+                        Ok(Some(FrameState::Complete(_)))
+                    ));
+                }
+                decoder.push_bytes(&bytes[split..]);
+                let Ok(Some(FrameState::Complete(frame))) = decoder.next_frame() else {
+                    panic!("{opcode:?} split at {split} not decoded");
+                };
+                assert_eq!((frame.opcode, &frame.payload[..]), (opcode, &payload[..]));
+            }
+        }
+    }
+
+    #[test]
+    fn close_payloads_validated() {
+        let close = |code: u16, reason: &[u8]| [&code.to_be_bytes()[..], reason].concat();
+        assert!(is_valid_close_payload(&[]));
+        for code in [1000, 1003, 1007, 1011, 1013, 3000, 4999] {
+            assert!(is_valid_close_payload(&close(code, b"bye")), "{code}");
+        }
+        for code in [0, 999, 1004, 1005, 1006, 1015, 1016, 2999, 5000] {
+            assert!(!is_valid_close_payload(&close(code, b"")), "{code}");
+        }
+        assert!(!is_valid_close_payload(&[0x03]));
+        assert!(!is_valid_close_payload(&close(1000, b"\xFF")));
+
+        // the decoder fails the frame, so the connection closes with 1002
+        let bytes = build_frame_bytes(Opcode::Close, &close(1005, b""), true, Client::SERVER);
+        let mut decoder = FrameDecoder::<Client>::new(false);
+        decoder.push_bytes(&bytes);
+        assert!(matches!(
+            decoder.next_frame(),
+            Err(FrameParseError::ProtoError)
+        ));
+    }
+
     proptest! {
         #![proptest_config(ProptestConfig::with_cases(1000))]
 
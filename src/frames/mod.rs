@@ -2,7 +2,12 @@ pub(crate) mod control;
 mod data;
 mod decode;
 mod opcode;
+mod window;
 
-pub(crate) use data::data;
+pub(crate) use data::{
+    DEFLATE_TAIL, DataChannel, FrameEncoder, Outgoing, TryDataError, data, data_shared, encode,
+    fragment, prime_dictionary, prime_inflater, try_data,
+};
 pub(crate) use decode::{DecodedFrame, FrameDecoder, FrameParseError, FrameState};
 pub(crate) use opcode::Opcode;
+pub(crate) use window::SendWindow;
@@ -0,0 +1,101 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tokio::sync::{Notify, mpsc::Sender};
+
+use super::Outgoing;
+
+// Bounds the bytes queued for the writer or being written by it. Bytes are taken
+// when a frame is queued and given back once the writer has flushed it.
+pub(crate) struct SendWindow {
+    limit: usize,
+    used: AtomicUsize,
+    room: Notify,
+}
+
+impl SendWindow {
+    pub(crate) fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            used: AtomicUsize::new(0),
+            room: Notify::new(),
+        }
+    }
+
+    // Wait until `len` more bytes fit, returning false if the writer has gone away.
+    // Anything larger than the whole window waits for it to empty.
+    pub(crate) async fn acquire(&self, len: usize, tx: &Sender<Outgoing>) -> bool {
+        loop {
+            // registered before checking, so a release in between is not missed
+            let room = self.room.notified();
+            let taken = self
+                .used
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                    (used == 0 || used + len <= self.limit).then_some(used + len)
+                });
+            if taken.is_ok() {
+                return true;
+            }
+            tokio::select! {
+                () = room => {}
+                () = tx.closed() => return false,
+            }
+        }
+    }
+
+    // Take `len` bytes only if they fit now
+    pub(crate) fn try_acquire(&self, len: usize) -> bool {
+        self.used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                (used == 0 || used + len <= self.limit).then_some(used + len)
+            })
+            .is_ok()
+    }
+
+    // Take bytes without waiting, for frames which must be queued regardless
+    pub(crate) fn force(&self, len: usize) { self.used.fetch_add(len, Ordering::AcqRel); }
+
+    pub(crate) fn release(&self, len: usize) {
+        self.used.fetch_sub(len, Ordering::AcqRel);
+        self.room.notify_waiters();
+    }
+
+    // Correct an estimate taken with `acquire` to the size actually queued
+    pub(crate) fn settle(&self, taken: usize, len: usize) {
+        if len > taken {
+            self.force(len - taken);
+        } else {
+            self.release(taken - len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::sync::mpsc::channel;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn waits_for_release() {
+        let (tx, _rx) = channel(1);
+        let window = SendWindow::new(10);
+        assert!(window.acquire(8, &tx).await);
+        // oversized items still go through alone
+        let blocked = tokio::time::timeout(Duration::from_millis(20), window.acquire(20, &tx));
+        assert!(blocked.await.is_err());
+
+        window.release(8);
+        assert!(window.acquire(20, &tx).await);
+    }
+
+    #[tokio::test]
+    async fn gives_up_once_closed() {
+        let (tx, rx) = channel(1);
+        let window = SendWindow::new(10);
+        window.force(10);
+        drop(rx);
+        assert!(!window.acquire(1, &tx).await);
+    }
+}
@@ -1,8 +1,9 @@
-#![feature(test)]
+#![cfg_attr(test, feature(test))]
 #![warn(clippy::all, clippy::pedantic)]
 // #![warn(missing_docs)]
 
 mod client;
+mod config;
 mod error;
 mod frames;
 mod protocol;
@@ -13,10 +14,16 @@ mod ws;
 ///
 /// extra context
 pub use async_trait::async_trait;
-pub use client::WebSocketClient;
-pub use error::UpgradeError;
-pub use server::WebSocketServer;
-pub use ws::{Event, Message, MessageHandler, Text, WebSocket};
+pub use client::{ClientBuilder, Scheme, WebSocketClient, WsUrl};
+pub use config::{ClosePolicy, PingPolicy, RetryConfig, UpgradeConfig, WebSocketConfig};
+pub use error::{CloseReason, PingError, SendError, TrySendError, UpgradeError};
+pub use protocol::LatencyStats;
+pub use server::{HttpError, ShutdownHandle, UpgradeRequest, WebSocketServer};
+pub use ws::{
+    BinaryWriter, BoxedWebSocket, CloseInfo, CompressionInfo, ConnectionContext, ConnectionInfo,
+    DeflateParams, Event, HandshakeCapture, Heartbeat, Message, MessageHandler, MessageWriter,
+    NegotiatedExtensions, Observation, Text, WebSocket, WsHandle, WsSink, WsStream,
+};
 
 // If using autobahn, set frames to 16M for testing
 // otherwise our real max is 16K frames
@@ -26,3 +33,7 @@ pub(crate) const MAX_FRAME_PAYLOAD: usize = 16 * 1024 * 1024; // 16M
 pub(crate) const MAX_FRAME_PAYLOAD: usize = 16 * 1024; // 16K
 
 pub(crate) const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024; // 16MB
+
+// Connections without an IP address (Unix sockets, in-memory pairs) report this placeholder
+pub(crate) const UNSPECIFIED_ADDR: std::net::SocketAddr =
+    std::net::SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED), 0);
@@ -0,0 +1,63 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicUsize, Ordering},
+};
+
+// flate2 does not report its allocations, so this is estimated for a 15 bit window.
+// The compressor keeps the window alongside its hash chains and a buffer of pending
+// codes, the decompressor little more than the window itself.
+pub(crate) const DEFLATE_MEMORY: usize = 256 * 1024 + 48 * 1024;
+
+// Deflate memory shared by every connection of a server
+#[derive(Debug)]
+pub(crate) struct DeflateBudget {
+    limit: usize,
+    used: AtomicUsize,
+}
+
+impl DeflateBudget {
+    pub(crate) fn new(limit: usize) -> Arc<Self> {
+        Arc::new(Self {
+            limit,
+            used: AtomicUsize::new(0),
+        })
+    }
+
+    pub(crate) fn used(&self) -> usize { self.used.load(Ordering::Relaxed) }
+
+    // Reserve memory for one compressed connection, None once the budget is spent
+    pub(crate) fn lease(self: &Arc<Self>) -> Option<DeflateLease> {
+        self.used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                used.checked_add(DEFLATE_MEMORY)
+                    .filter(|&total| total <= self.limit)
+            })
+            .ok()?;
+        Some(DeflateLease(self.clone()))
+    }
+}
+
+// Returns its share of the budget when the connection goes away
+#[derive(Debug)]
+pub(crate) struct DeflateLease(Arc<DeflateBudget>);
+
+impl Drop for DeflateLease {
+    fn drop(&mut self) { self.0.used.fetch_sub(DEFLATE_MEMORY, Ordering::AcqRel); }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lease_until_spent() {
+        let budget = DeflateBudget::new(2 * DEFLATE_MEMORY + 1);
+        let first = budget.lease().unwrap();
+        let _second = budget.lease().unwrap();
+        assert!(budget.lease().is_none());
+        assert_eq!(budget.used(), 2 * DEFLATE_MEMORY);
+
+        drop(first);
+        assert!(budget.lease().is_some());
+    }
+}
@@ -46,3 +46,42 @@ fn mask_lin(payload: &mut [u8], mask_key: [u8; 4]) {
         *b ^= mask_key[i % 4];
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use proptest::{collection::vec, prelude::*};
+
+    use super::*;
+
+    type MaskFn = fn(&mut [u8], [u8; 4]);
+
+    // Every masking implementation available on this machine
+    fn implementations() -> Vec<(&'static str, MaskFn)> {
+        #[allow(unused_mut)]
+        let mut impls: Vec<(&'static str, MaskFn)> = vec![("lin", mask_lin), ("dispatch", mask)];
+        #[cfg(all(target_arch = "x86_64", feature = "simd_masking"))]
+        if is_x86_feature_detected!("avx2") {
+            impls.push(("avx2", mask_avx2));
+        }
+        impls
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(1000))]
+
+        // lengths past several SIMD widths exercise every tail length
+        #[test]
+        fn implementations_agree(payload in vec(any::<u8>(), 0..300), key in any::<[u8; 4]>()) {
+            let expected: Vec<u8> =
+                payload.iter().enumerate().map(|(i, b)| b ^ key[i % 4]).collect();
+
+            for (name, imp) in implementations() {
+                let mut buf = payload.clone();
+                imp(&mut buf, key);
+                prop_assert_eq!(&buf, &expected, "{} differs from scalar", name);
+                imp(&mut buf, key);
+                prop_assert_eq!(&buf, &payload, "{} does not round-trip", name);
+            }
+        }
+    }
+}
@@ -1,5 +1,12 @@
+mod budget;
 mod mask;
 mod ping;
+mod rate;
+mod utf8;
 
+pub(crate) use budget::{DEFLATE_MEMORY, DeflateBudget, DeflateLease};
 pub(crate) use mask::mask;
-pub(crate) use ping::{PingStats, PongError};
+pub use ping::LatencyStats;
+pub(crate) use ping::{MAX_WINDOW, PingStats, PongError, ping_expiry};
+pub(crate) use rate::TokenBucket;
+pub(crate) use utf8::Utf8Incremental;
@@ -1,60 +1,249 @@
-use std::time::Instant;
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
 
-const N: usize = 5;
+// How many latencies are kept, the largest window that can be summarised
+pub(crate) const MAX_WINDOW: usize = 64;
+// The window `average` covers
+const AVERAGE_WINDOW: usize = 5;
 const NONCE_LEN: usize = 8;
+// The nonce starts with the sequence number, the rest is random
+const SEQ_LEN: usize = 4;
+// How long outstanding pings are kept when the ping loop never pings
+const UNPINGED_EXPIRY: Duration = Duration::from_mins(1);
 
-// Stores and calculates average of last N latencies
-pub struct PingStats {
-    history: [Option<u16>; N],
+// Outstanding pings older than the ping loop waits for a pong are assumed lost, never
+// if that is longer than a `Duration` holds
+pub(crate) fn ping_expiry(interval: Option<Duration>, multiplier: u32) -> Duration {
+    interval.map_or(UNPINGED_EXPIRY, |every| every.saturating_mul(multiplier))
+}
+
+/// A summary of the latencies measured by the most recent pings, see
+/// [`WebSocket::latency_stats`](crate::WebSocket::latency_stats). All times are in ms.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LatencyStats {
+    last: u16,
+    min: u16,
+    max: u16,
+    average: u16,
+    jitter: f64,
+    samples: usize,
+}
+
+impl LatencyStats {
+    /// The latency measured by the most recent ping.
+    #[must_use]
+    pub fn last(&self) -> u16 { self.last }
+
+    /// The lowest latency in the window.
+    #[must_use]
+    pub fn min(&self) -> u16 { self.min }
+
+    /// The highest latency in the window.
+    #[must_use]
+    pub fn max(&self) -> u16 { self.max }
+
+    /// The mean latency over the window.
+    #[must_use]
+    pub fn average(&self) -> u16 { self.average }
+
+    /// The standard deviation of the latencies in the window.
+    #[must_use]
+    pub fn jitter(&self) -> f64 { self.jitter }
+
+    /// How many latencies the summary covers, fewer than the window until enough pings
+    /// have been answered.
+    #[must_use]
+    pub fn samples(&self) -> usize { self.samples }
+}
+
+// Stores the last N latencies and summarises them
+pub struct PingStats<const N: usize> {
+    history: [u16; N],
+    // latencies recorded, up to N
+    len: usize,
     idx: usize,
-    last_nonce: [u8; NONCE_LEN],
-    last_ping: Instant,
+    // pings awaiting a pong, oldest first
+    outstanding: VecDeque<([u8; NONCE_LEN], Instant)>,
+    max_outstanding: usize,
+    // age at which an outstanding ping is assumed lost, see `ping_expiry`
+    expiry: Duration,
+    // sequence number of the next ping, and of the pong we expect next
+    next_seq: u32,
+    expected_seq: u32,
+    // sequence number of the latest ping answered
+    answered_seq: Option<u32>,
 }
 
-impl PingStats {
-    pub(crate) fn new() -> Self {
+impl<const N: usize> PingStats<N> {
+    pub(crate) fn new(max_outstanding: usize, expiry: Duration) -> Self {
         Self {
-            history: [None; N],
+            history: [0; N],
+            len: 0,
             idx: 0,
-            last_nonce: [0; NONCE_LEN],
-            last_ping: Instant::now(),
+            outstanding: VecDeque::with_capacity(max_outstanding),
+            max_outstanding,
+            expiry,
+            next_seq: 0,
+            expected_seq: 0,
+            answered_seq: None,
         }
     }
 
-    pub(crate) fn new_nonce(&mut self) -> [u8; NONCE_LEN] {
-        self.last_ping = Instant::now();
+    // Returns `None` if too many pings are still waiting for a pong
+    pub(crate) fn new_nonce(&mut self) -> Option<[u8; NONCE_LEN]> {
+        self.prune();
+        if self.outstanding.len() >= self.max_outstanding {
+            return None;
+        }
         let mut buf = [0; NONCE_LEN];
-        rand::fill(&mut buf);
-        self.last_nonce = buf;
-        buf
-    }
-
-    pub(crate) fn on_pong(&mut self, nonce: [u8; NONCE_LEN]) -> Result<u16, PongError> {
-        if nonce == self.last_nonce {
-            let latency_ms = self.last_ping.elapsed().as_millis();
-            let latency = u16::try_from(latency_ms).map_err(|_| PongError::Late(latency_ms))?;
-            self.history[self.idx] = Some(latency);
-            self.idx = (self.idx + 1) % N;
-            Ok(latency)
-        } else {
-            Err(PongError::Nonce(self.last_nonce))
+        buf[..SEQ_LEN].copy_from_slice(&self.next_seq.to_be_bytes());
+        rand::fill(&mut buf[SEQ_LEN..]);
+        self.next_seq = self.next_seq.wrapping_add(1);
+        self.outstanding.push_back((buf, Instant::now()));
+        Some(buf)
+    }
+
+    pub(crate) fn set_expiry(&mut self, expiry: Duration) { self.expiry = expiry; }
+
+    pub(crate) fn in_flight(&mut self) -> usize {
+        self.prune();
+        self.outstanding.len()
+    }
+
+    // The most recent ping still waiting for a pong
+    pub(crate) fn newest(&mut self) -> Option<[u8; NONCE_LEN]> {
+        self.prune();
+        self.outstanding.back().map(|(nonce, _)| *nonce)
+    }
+
+    // Whether the ping sent with `nonce` has been answered, by its own pong or a later one
+    pub(crate) fn answered(&self, nonce: [u8; NONCE_LEN]) -> bool {
+        let seq = u32::from_be_bytes(nonce[..SEQ_LEN].try_into().unwrap());
+        self.answered_seq
+            .is_some_and(|answered| answered.wrapping_sub(seq) < 1 << 31)
+    }
+
+    // A peer may answer only the latest of several pings, so a pong also
+    // settles every ping sent before the one it answers
+    // Returns the latency and sequence number of the ping answered
+    pub(crate) fn on_pong(&mut self, nonce: [u8; NONCE_LEN]) -> Result<(u16, u32), PongError> {
+        let Some(i) = self.outstanding.iter().position(|(n, _)| *n == nonce) else {
+            return Err(PongError::Unsolicited);
+        };
+        let (_, sent) = self.outstanding[i];
+        self.outstanding.drain(..=i);
+
+        let seq = u32::from_be_bytes(nonce[..SEQ_LEN].try_into().unwrap());
+        if seq != self.expected_seq {
+            tracing::debug!(
+                seq,
+                expected = self.expected_seq,
+                "pongs missing before this one"
+            );
         }
+        self.expected_seq = seq.wrapping_add(1);
+        self.answered_seq = Some(seq);
+
+        let latency_ms = sent.elapsed().as_millis();
+        let latency = u16::try_from(latency_ms).map_err(|_| PongError::Late(latency_ms))?;
+        self.record(latency);
+        Ok((latency, seq))
+    }
+
+    fn record(&mut self, latency: u16) {
+        self.history[self.idx] = latency;
+        self.idx = (self.idx + 1) % N;
+        self.len = (self.len + 1).min(N);
     }
 
-    pub(crate) fn average(&self) -> Option<u16> {
-        let mut sum = 0;
-        let mut count = 0;
-        for &x in &self.history {
-            if let Some(v) = x {
-                sum += v;
-                count += 1;
-            }
+    pub(crate) fn average(&self) -> Option<u16> { self.stats(AVERAGE_WINDOW).map(|s| s.average) }
+
+    // Summarises the last `window` latencies, `None` if none have been measured
+    pub(crate) fn stats(&self, window: usize) -> Option<LatencyStats> {
+        let samples = window.min(self.len);
+        // newest first
+        let recent = (1..=samples).map(|back| self.history[(self.idx + N - back) % N]);
+        let last = recent.clone().next()?;
+        let count = u32::try_from(samples).unwrap();
+        let sum: u32 = recent.clone().map(u32::from).sum();
+        let mean = f64::from(sum) / f64::from(count);
+        let variance = recent
+            .clone()
+            .map(|v| (f64::from(v) - mean).powi(2))
+            .sum::<f64>()
+            / f64::from(count);
+        Some(LatencyStats {
+            last,
+            min: recent.clone().min()?,
+            max: recent.max()?,
+            average: u16::try_from(sum / count).unwrap(),
+            jitter: variance.sqrt(),
+            samples,
+        })
+    }
+
+    fn prune(&mut self) {
+        while let Some((_, sent)) = self.outstanding.front()
+            && sent.elapsed() >= self.expiry
+        {
+            self.outstanding.pop_front();
         }
-        sum.checked_div(count)
     }
 }
 
 pub(crate) enum PongError {
-    Nonce([u8; NONCE_LEN]),
+    Unsolicited,
     Late(u128),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summarises_recent_window() {
+        let mut stats = PingStats::<4>::new(1, UNPINGED_EXPIRY);
+        assert_eq!(stats.stats(4), None);
+        for latency in [100, 10, 20, 30, 40] {
+            stats.record(latency);
+        }
+        // the oldest has been overwritten
+        let all = stats.stats(4).unwrap();
+        assert_eq!((all.last(), all.min(), all.max()), (40, 10, 40));
+        assert_eq!((all.average(), all.samples()), (25, 4));
+        assert!((all.jitter() - 125f64.sqrt()).abs() < 1e-9);
+
+        let recent = stats.stats(2).unwrap();
+        assert_eq!(
+            (recent.min(), recent.average(), recent.samples()),
+            (30, 35, 2)
+        );
+        assert_eq!(stats.stats(10).unwrap().samples(), 4);
+    }
+
+    #[test]
+    fn unanswered_pings_expire() {
+        let expiry = ping_expiry(Some(Duration::from_millis(10)), 2);
+        assert_eq!(expiry, Duration::from_millis(20));
+        assert_eq!(ping_expiry(Some(Duration::MAX), 2), Duration::MAX);
+        let mut stats = PingStats::<4>::new(1, expiry);
+        assert!(stats.new_nonce().is_some());
+        assert!(stats.new_nonce().is_none());
+        std::thread::sleep(expiry);
+        assert_eq!(stats.in_flight(), 0);
+        assert!(stats.new_nonce().is_some());
+    }
+
+    #[test]
+    fn pong_answers_earlier_pings() {
+        let mut stats = PingStats::<4>::new(3, UNPINGED_EXPIRY);
+        let [first, second, third] = [(); 3].map(|()| stats.new_nonce().unwrap());
+        assert!(!stats.answered(first));
+        assert!(stats.on_pong(second).is_ok());
+        assert!(stats.answered(first) && stats.answered(second));
+        assert!(!stats.answered(third));
+        assert_eq!(stats.newest(), Some(third));
+    }
+}
@@ -0,0 +1,56 @@
+use std::time::Instant;
+
+// Token bucket allowing `rate` events per second, in bursts of up to `rate`
+pub(crate) struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(rate: u32) -> Self {
+        let rate = f64::from(rate.max(1));
+        Self {
+            rate,
+            tokens: rate,
+            last: Instant::now(),
+        }
+    }
+
+    // Take a token if one is available at `now`
+    pub(crate) fn try_take(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn burst_then_refill() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(4);
+        assert!((0..4).all(|_| bucket.try_take(start)));
+        assert!(!bucket.try_take(start));
+
+        // a quarter second buys one more
+        let later = start + Duration::from_millis(250);
+        assert!(bucket.try_take(later));
+        assert!(!bucket.try_take(later));
+
+        // idle time never banks more than a burst
+        let idle = later + Duration::from_secs(10);
+        assert!((0..4).all(|_| bucket.try_take(idle)));
+        assert!(!bucket.try_take(idle));
+    }
+}
@@ -0,0 +1,83 @@
+// Validates UTF-8 which arrives in pieces, e.g. the frames of a text message, so that
+// invalid text is caught at the frame it appears in rather than once the message ends
+#[derive(Debug, Default)]
+pub(crate) struct Utf8Incremental {
+    // start of a character split across pieces
+    pending: [u8; 4],
+    len: usize,
+}
+
+impl Utf8Incremental {
+    // false once the bytes so far cannot be the start of valid UTF-8
+    pub(crate) fn push(&mut self, mut bytes: &[u8]) -> bool {
+        if self.len > 0 {
+            // finish the split character first
+            let take = (width(self.pending[0]) - self.len).min(bytes.len());
+            self.pending[self.len..self.len + take].copy_from_slice(&bytes[..take]);
+            self.len += take;
+            bytes = &bytes[take..];
+            match str::from_utf8(&self.pending[..self.len]) {
+                Ok(_) => self.len = 0,
+                Err(e) if e.error_len().is_some() => return false,
+                // still incomplete, so every byte went into it
+                Err(_) => return true,
+            }
+        }
+        match str::from_utf8(bytes) {
+            Ok(_) => true,
+            Err(e) if e.error_len().is_some() => false,
+            Err(e) => {
+                let tail = &bytes[e.valid_up_to()..];
+                self.pending[..tail.len()].copy_from_slice(tail);
+                self.len = tail.len();
+                true
+            }
+        }
+    }
+
+    // whether the text ended on a character boundary
+    pub(crate) fn is_complete(&self) -> bool { self.len == 0 }
+}
+
+// Length of the character starting with `lead`, which is a valid lead byte
+fn width(lead: u8) -> usize {
+    match lead {
+        0xF0.. => 4,
+        0xE0.. => 3,
+        _ => 2,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn characters_split_across_pieces() {
+        let text = "aé€😀".as_bytes();
+        for size in 1..=3 {
+            let mut utf8 = Utf8Incremental::default();
+            for piece in text.chunks(size) {
+                assert!(utf8.push(piece), "{size}");
+            }
+            assert!(utf8.is_complete());
+        }
+    }
+
+    #[test]
+    fn invalid_bytes_caught_early() {
+        let mut utf8 = Utf8Incremental::default();
+        assert!(!utf8.push(b"ok\xFF"));
+
+        // a bad continuation in the next piece
+        let mut utf8 = Utf8Incremental::default();
+        assert!(utf8.push(b"\xE2\x82"));
+        assert!(!utf8.is_complete());
+        assert!(!utf8.push(b"A"));
+
+        // surrogates are not valid UTF-8 even when split
+        let mut utf8 = Utf8Incremental::default();
+        assert!(utf8.push(b"\xED"));
+        assert!(!utf8.push(b"\xA0\x80"));
+    }
+}
@@ -1,9 +1,23 @@
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    net::SocketAddr,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
+use base64::engine::{Engine, general_purpose::STANDARD as BASE64};
 use rustls::ServerConfig;
+// tokio sets socket options through socket2 already, it is only named here to reach
+// them on a bound listener, which tokio's own `TcpSocket` setters cannot
+use socket2::SockRef;
+#[cfg(unix)]
+use tokio::net::UnixListener;
 use tokio::{
-    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
     net::{TcpListener, ToSocketAddrs},
+    sync::watch,
 };
 use tokio_rustls::{
     TlsAcceptor,
@@ -11,18 +25,57 @@ use tokio_rustls::{
 };
 
 use crate::{
-    error::UpgradeError,
+    UpgradeConfig, WebSocketConfig,
+    error::{CloseReason, UpgradeError},
+    protocol::{DeflateBudget, TokenBucket},
     role::Server,
-    ws::{MessageHandler, WebSocket},
+    ws::{ConnectionInfo, DeflateParams, MessageHandler, WebSocket, header_values, read_headers},
 };
 
+mod hook;
+mod router;
+
+pub(crate) use hook::UpgradeHook;
+pub use hook::{HttpError, UpgradeRequest};
+use router::Router;
+
 type Result<T> = std::result::Result<T, UpgradeError>;
 
+// What a server applies to each connection it upgrades
+type Settings<'a> = (
+    &'a WebSocketConfig,
+    &'a UpgradeConfig,
+    &'a Arc<DeflateBudget>,
+);
+
 pub struct WebSocketServer {
-    listener: TcpListener,
+    listener: Listener,
     addr: SocketAddr,
     insecure: bool,
-    ssl: bool,
+    tls: Option<TlsAcceptor>,
+    config: WebSocketConfig,
+    upgrade: UpgradeConfig,
+    shutdown: Arc<watch::Sender<bool>>,
+    drain_timeout: Option<Duration>,
+    accept_rate: Option<u32>,
+    deflate_budget: Arc<DeflateBudget>,
+    routes: HashMap<String, Arc<dyn MessageHandler>>,
+}
+
+/// Stops a [`WebSocketServer`], obtained from [`WebSocketServer::shutdown_handle`].
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle(Arc<watch::Sender<bool>>);
+
+impl ShutdownHandle {
+    /// Stops the server accepting connections and closes every open connection with
+    /// 1001 Going Away, telling clients the server is restarting rather than done with them.
+    pub fn shutdown(&self) { self.0.send_replace(true); }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
 }
 
 impl WebSocketServer {
@@ -35,36 +88,288 @@ impl WebSocketServer {
     /// If addr yields multiple addresses, bind will be attempted with each of the addresses until one succeeds and returns the listener.
     ///
     /// The `insecure` parameter sets whether the server accepts insecure connections over TCP.
-    /// Similarly, the `ssl` parameter sets whether the server accepts secure connecions over TLS.
+    /// Similarly, the `ssl` parameter sets whether the server accepts secure connecions over TLS,
+    /// using the certificate chain in `certs/cert.pem` and key in `certs/cert.key.pem`. Use
+    /// [`with_tls`](WebSocketServer::with_tls) or [`with_tls_files`](WebSocketServer::with_tls_files)
+    /// to supply them otherwise. Without `ssl` no certificate files are read.
     ///
     /// # Errors
-    /// Will fail if unable to bind to any address.
+    /// Will fail if unable to bind to any address, or with [`UpgradeError::Tls`] if `ssl` is
+    /// set and the certificate files are missing or malformed.
     pub async fn bind<A: ToSocketAddrs>(addr: A, insecure: bool, ssl: bool) -> Result<Self> {
+        let tls = if ssl {
+            let (cert, key) = (Path::new("certs/cert.pem"), Path::new("certs/cert.key.pem"));
+            Some(tls_acceptor_from_files(cert, key)?)
+        } else {
+            None
+        };
         let listener = TcpListener::bind(addr)
             .await
             .map_err(|_| UpgradeError::Bind)?;
         let addr = listener.local_addr().map_err(|_| UpgradeError::Addr)?;
         tracing::info!(addr = ?addr, "Listening on");
         Ok(Self {
-            listener,
+            listener: Listener::Tcp(listener),
             addr,
             insecure,
-            ssl,
+            tls,
+            config: WebSocketConfig::default(),
+            upgrade: UpgradeConfig::default(),
+            shutdown: Arc::new(watch::channel(false).0),
+            drain_timeout: None,
+            accept_rate: None,
+            deflate_budget: DeflateBudget::new(usize::MAX),
+            routes: HashMap::new(),
+        })
+    }
+
+    /// Creates a new `WebSocketServer` which will be bound to a Unix domain socket at the specified path.
+    ///
+    /// Connections are always upgraded without TLS. Since Unix sockets have no IP address,
+    /// [`addr`](WebSocketServer::addr) and the addresses of accepted connections are unspecified.
+    ///
+    /// # Errors
+    /// Will fail if unable to bind to the path, e.g. if the file already exists.
+    #[cfg(unix)]
+    pub fn bind_unix<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let listener = UnixListener::bind(&path).map_err(|_| UpgradeError::Bind)?;
+        tracing::info!(path = ?path.as_ref(), "Listening on");
+        Ok(Self {
+            listener: Listener::Unix(listener),
+            addr: crate::UNSPECIFIED_ADDR,
+            insecure: true,
+            tls: None,
+            config: WebSocketConfig::default(),
+            upgrade: UpgradeConfig::default(),
+            shutdown: Arc::new(watch::channel(false).0),
+            drain_timeout: None,
+            accept_rate: None,
+            deflate_budget: DeflateBudget::new(usize::MAX),
+            routes: HashMap::new(),
         })
     }
 
-    // TODO: run docs
+    /// Returns a handle which shuts the server down gracefully.
+    ///
+    /// Once shut down, [`run`](WebSocketServer::run) returns and open connections finish
+    /// their closing handshakes in the background.
+    #[must_use]
+    pub fn shutdown_handle(&self) -> ShutdownHandle { ShutdownHandle(self.shutdown.clone()) }
+
+    /// Sets how long connections have to finish after shutdown, unlimited by default.
+    ///
+    /// Within this quiet period the client can finish its current exchange and complete
+    /// the closing handshake. Connections still open afterwards are terminated, those which
+    /// close sooner end immediately.
+    #[must_use]
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = Some(timeout);
+        self
+    }
+
+    /// Accepts secure connections over TLS with the given certificate chain and private key,
+    /// replacing any set previously. Has no effect on Unix sockets.
+    /// # Errors
+    /// Fails with [`UpgradeError::Tls`] if the key does not suit the certificate.
+    pub fn with_tls(
+        mut self,
+        certs: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+    ) -> Result<Self> {
+        self.tls = Some(tls_acceptor(certs, key)?);
+        Ok(self)
+    }
+
+    /// Like [`with_tls`](WebSocketServer::with_tls), reading the certificate chain and
+    /// private key from PEM files.
+    /// # Errors
+    /// Fails with [`UpgradeError::Tls`] if either file is missing or malformed.
+    pub fn with_tls_files<P: AsRef<Path>>(mut self, cert: P, key: P) -> Result<Self> {
+        self.tls = Some(tls_acceptor_from_files(cert.as_ref(), key.as_ref())?);
+        Ok(self)
+    }
+
+    /// Limits how many connections are accepted per second, unlimited by default.
+    ///
+    /// Up to `per_second` connections are accepted in a burst, refilling at that rate.
+    /// Connections beyond the limit are reset straight away, before any TLS or handshake
+    /// work is done for them. This bounds the rate of new connections, not how many are open.
+    #[must_use]
+    pub fn accept_rate(mut self, per_second: u32) -> Self {
+        self.accept_rate = Some(per_second);
+        self
+    }
+
+    /// Caps the memory all connections together may spend on permessage-deflate,
+    /// unlimited by default.
+    ///
+    /// Each compressed connection counts an estimated 300K, see
+    /// [`WebSocketConfig::max_deflate_memory`]. Once the budget is spent, new connections
+    /// are upgraded without compression until compressed ones close.
+    #[must_use]
+    pub fn deflate_memory_budget(mut self, bytes: usize) -> Self {
+        self.deflate_budget = DeflateBudget::new(bytes);
+        self
+    }
+
+    /// Returns the estimated memory open connections spend on permessage-deflate.
+    #[must_use]
+    pub fn deflate_memory(&self) -> usize { self.deflate_budget.used() }
+
+    /// Sets the [`WebSocketConfig`] applied to every accepted connection.
+    #[must_use]
+    pub fn with_config(mut self, config: WebSocketConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Sets the [`UpgradeConfig`] limiting how handshakes are read, replacing any
+    /// [`allowed_origins`](WebSocketServer::allowed_origins) set previously.
+    #[must_use]
+    pub fn with_upgrade_config(mut self, upgrade: UpgradeConfig) -> Self {
+        self.upgrade = upgrade;
+        self
+    }
+
+    /// Sets the size of the send buffer (`SO_SNDBUF`) of accepted TCP sockets, for bulk
+    /// transfers over links with a high bandwidth-delay product. Has no effect on Unix sockets.
+    ///
+    /// Setting a size turns off the kernel's automatic tuning of the buffer. Linux doubles
+    /// the value to allow for bookkeeping and caps it at `net.core.wmem_max`.
+    /// # Errors
+    /// Fails with [`UpgradeError::Bind`] if the option cannot be set on the listener.
+    pub fn send_buffer_size(self, size: usize) -> Result<Self> {
+        if let Listener::Tcp(listener) = &self.listener {
+            SockRef::from(listener)
+                .set_send_buffer_size(size)
+                .map_err(|e| {
+                    tracing::warn!(e = ?e, "could not set send buffer size");
+                    UpgradeError::Bind
+                })?;
+        }
+        Ok(self)
+    }
+
+    /// Sets the size of the receive buffer (`SO_RCVBUF`) of accepted TCP sockets, which
+    /// bounds the TCP window advertised to clients. Has no effect on Unix sockets.
+    ///
+    /// It is set on the listening socket, which accepted sockets inherit it from, so the
+    /// window can scale. Setting a size turns off the kernel's automatic tuning of the
+    /// buffer. Linux doubles the value to allow for bookkeeping and caps it at
+    /// `net.core.rmem_max`.
+    /// # Errors
+    /// Fails with [`UpgradeError::Bind`] if the option cannot be set on the listener.
+    pub fn recv_buffer_size(self, size: usize) -> Result<Self> {
+        if let Listener::Tcp(listener) = &self.listener {
+            SockRef::from(listener)
+                .set_recv_buffer_size(size)
+                .map_err(|e| {
+                    tracing::warn!(e = ?e, "could not set receive buffer size");
+                    UpgradeError::Bind
+                })?;
+        }
+        Ok(self)
+    }
+
+    /// Only accept upgrades from browsers on one of the given origins, e.g. `https://example.com`.
+    ///
+    /// This protects against Cross-Site WebSocket Hijacking, since browsers do not apply the
+    /// same-origin policy to WebSocket connections. Requests with a disallowed `Origin` header
+    /// are rejected with `403 Forbidden` before switching protocols, while requests without one
+    /// (i.e. non-browser clients) are accepted. Origins are compared case-insensitively and
+    /// must match in scheme, host and port, a trailing slash on an allowed origin is ignored.
+    #[must_use]
+    pub fn allowed_origins(mut self, origins: Vec<String>) -> Self {
+        self.upgrade = self.upgrade.allowed_origins(origins);
+        self
+    }
+
+    /// Sets the subprotocols the server speaks, e.g. `graphql-ws`, none by default.
+    ///
+    /// The first protocol in the client's `Sec-WebSocket-Protocol` list which is also in
+    /// `protocols` is chosen, compared case-sensitively, and available from
+    /// [`WebSocket::protocol`]. Clients requesting only protocols the server does not speak
+    /// are still upgraded, without a protocol, and may close the connection themselves.
+    #[must_use]
+    pub fn protocols(mut self, protocols: Vec<String>) -> Self {
+        self.upgrade = self.upgrade.protocols(protocols);
+        self
+    }
+
+    /// Routes connections for `path` to `handler`, for use with
+    /// [`run_routes`](WebSocketServer::run_routes). Replaces any handler routed for the
+    /// same path.
+    ///
+    /// The path is matched exactly against the request path without its query string,
+    /// which stays available from [`ConnectionContext::info`](crate::ConnectionContext::info).
+    #[must_use]
+    pub fn route<H: MessageHandler>(mut self, path: impl Into<String>, handler: H) -> Self {
+        self.routes.insert(path.into(), Arc::new(handler));
+        self
+    }
+
+    /// Accepts connections forever, running the handler [routed](WebSocketServer::route)
+    /// for each one's path. Upgrades for any other path are refused with `404 Not Found`.
+    pub async fn run_routes(&self) {
+        let upgrade = UpgradeConfig {
+            routes: Some(self.routes.keys().cloned().collect()),
+            ..self.upgrade.clone()
+        };
+        self.run_with(Arc::new(Router(self.routes.clone())), &upgrade)
+            .await;
+    }
+
+    /// Accepts connections forever, running `handler` on each one.
+    /// A single handler is shared by all connections.
     pub async fn run<H: MessageHandler>(&self, handler: H) {
-        let acceptor = TlsAcceptor::from(get_tls_config());
+        self.run_shared(Arc::new(handler)).await;
+    }
+
+    /// Like [`run`](WebSocketServer::run), but takes an already shared handler so that the
+    /// caller can keep a reference to it, e.g. to reach state it holds for all connections.
+    /// Handlers of different types can be chosen at runtime behind the trait object.
+    pub async fn run_shared(&self, handler: Arc<dyn MessageHandler>) {
+        self.run_with(handler, &self.upgrade).await;
+    }
+
+    async fn run_with(&self, handler: Arc<dyn MessageHandler>, upgrade: &UpgradeConfig) {
+        if upgrade.allowed_origins.is_none() {
+            tracing::warn!("no allowed origins configured, accepting upgrades from any origin");
+        }
+        match &self.listener {
+            Listener::Tcp(listener) => self.run_tcp(listener, handler, upgrade).await,
+            #[cfg(unix)]
+            Listener::Unix(listener) => self.run_unix(listener, handler, upgrade).await,
+        }
+    }
 
+    async fn run_tcp(
+        &self,
+        listener: &TcpListener,
+        handler: Arc<dyn MessageHandler>,
+        upgrade: &UpgradeConfig,
+    ) {
         let peer = self.addr;
         let insecure = self.insecure;
-        let ssl = self.ssl;
-        let handler = Arc::new(handler);
-        while let Ok((stream, addr)) = self.listener.accept().await {
+        let mut rate = self.accept_rate.map(TokenBucket::new);
+        while let Some((stream, addr)) = self.accept(listener.accept()).await {
+            if let Some(bucket) = &mut rate
+                && !bucket.try_take(Instant::now())
+            {
+                tracing::debug!(addr = ?addr, "accept rate exceeded, resetting connection");
+                // dropping with a zero linger sends RST rather than FIN
+                let _ = stream.set_zero_linger();
+                continue;
+            }
             let handler = handler.clone();
-            let acceptor = acceptor.clone();
+            let shutdown = self.shutdown.subscribe();
+            let drain = self.drain_timeout;
+            let acceptor = self.tls.clone();
+            let config = self.config.clone();
+            let upgrade = upgrade.clone();
+            let budget = self.deflate_budget.clone();
             tokio::task::spawn(async move {
+                let settings = (&config, &upgrade, &budget);
                 // check first few bytes of request.
                 let mut peeker = [0; 4];
                 match stream.peek(&mut peeker).await {
@@ -82,13 +387,13 @@ impl WebSocketServer {
                 let conn_res = if insecure && peeker.starts_with(b"GET ") {
                     // if we have "GET ", we try plain TCP
                     tracing::info!("attempting insecure upgrade");
-                    WebSocket::<Server>::try_upgrade(stream, addr, peer).await
-                } else if ssl {
+                    WebSocket::<Server>::try_upgrade(stream, addr, peer, settings).await
+                } else if let Some(acceptor) = acceptor {
                     // otherwise try to use TLS
                     match acceptor.accept(stream).await {
                         Ok(stream) => {
                             tracing::info!("attempting TLS upgrade");
-                            WebSocket::<Server>::try_upgrade(stream, addr, peer).await
+                            WebSocket::<Server>::try_upgrade(stream, addr, peer, settings).await
                         }
                         Err(e) => {
                             tracing::error!(e=?e, "tls handshake");
@@ -99,15 +404,53 @@ impl WebSocketServer {
                     Err(UpgradeError::Protocol)
                 };
 
-                let mut ws = match conn_res {
-                    Ok(ws) => ws,
-                    Err(e) => {
-                        tracing::error!(addr=?addr, e=?e, "failed to upgrade");
-                        return;
-                    }
-                };
+                serve(conn_res, addr, handler, shutdown, drain).await;
+            });
+        }
+    }
+
+    // Wait for the next connection, or None once the listener fails or we shut down
+    async fn accept<S, A>(
+        &self,
+        accept: impl Future<Output = std::io::Result<(S, A)>>,
+    ) -> Option<(S, A)> {
+        let mut shutdown = self.shutdown.subscribe();
+        tokio::select! {
+            res = accept => res.ok(),
+            _ = shutdown.wait_for(|&down| down) => {
+                tracing::info!("shutting down, no longer accepting connections");
+                None
+            }
+        }
+    }
 
-                ws.recv_loop(handler).await;
+    #[cfg(unix)]
+    async fn run_unix(
+        &self,
+        listener: &UnixListener,
+        handler: Arc<dyn MessageHandler>,
+        upgrade: &UpgradeConfig,
+    ) {
+        let addr = crate::UNSPECIFIED_ADDR;
+        let mut rate = self.accept_rate.map(TokenBucket::new);
+        while let Some((stream, _)) = self.accept(listener.accept()).await {
+            if let Some(bucket) = &mut rate
+                && !bucket.try_take(Instant::now())
+            {
+                tracing::debug!("accept rate exceeded, dropping connection");
+                continue;
+            }
+            let handler = handler.clone();
+            let shutdown = self.shutdown.subscribe();
+            let drain = self.drain_timeout;
+            let config = self.config.clone();
+            let upgrade = upgrade.clone();
+            let budget = self.deflate_budget.clone();
+            tokio::task::spawn(async move {
+                tracing::info!("attempting unix socket upgrade");
+                let settings = (&config, &upgrade, &budget);
+                let conn_res = WebSocket::<Server>::try_upgrade(stream, addr, addr, settings).await;
+                serve(conn_res, addr, handler, shutdown, drain).await;
             });
         }
     }
@@ -116,61 +459,120 @@ impl WebSocketServer {
     pub fn addr(&self) -> SocketAddr { self.addr }
 }
 
-fn get_tls_config() -> Arc<ServerConfig> {
-    let certs = CertificateDer::pem_file_iter("certs/cert.pem")
-        .unwrap()
-        .collect::<std::result::Result<Vec<_>, _>>()
-        .unwrap();
-    let key = PrivateKeyDer::from_pem_file("certs/cert.key.pem").unwrap();
+// Run the handler on a freshly upgraded connection
+async fn serve(
+    conn_res: Result<WebSocket<Server>>,
+    addr: SocketAddr,
+    handler: Arc<dyn MessageHandler>,
+    mut shutdown: watch::Receiver<bool>,
+    drain: Option<Duration>,
+) {
+    match conn_res {
+        Ok(mut ws) => {
+            // the server is going away, not the conversation, so clients may reconnect
+            let signal = async move {
+                if shutdown.wait_for(|&down| down).await.is_err() {
+                    std::future::pending::<()>().await;
+                }
+            };
+            let text = "server shutting down";
+            let going_away = ws.close_when(signal, CloseReason::GoingAway, text, drain);
+            let info = ws.recv_loop(handler).await;
+            going_away.abort();
+            tracing::info!(addr=?addr, code = info.code, reason = info.reason, "connection closed");
+        }
+        Err(e) => tracing::error!(addr=?addr, e=?e, "failed to upgrade"),
+    }
+}
 
-    let config = rustls::ServerConfig::builder()
+// Build the TLS acceptor from a certificate chain and its private key
+fn tls_acceptor(
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+) -> Result<TlsAcceptor> {
+    let config = ServerConfig::builder()
         .with_no_client_auth()
         .with_single_cert(certs, key)
-        .unwrap();
-    Arc::new(config)
+        .map_err(|e| UpgradeError::Tls(e.to_string()))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+// Read a PEM certificate chain and private key
+fn tls_acceptor_from_files(cert: &Path, key: &Path) -> Result<TlsAcceptor> {
+    let pem_error = |path: &Path, e: rustls::pki_types::pem::Error| {
+        UpgradeError::Tls(format!("{}: {e}", path.display()))
+    };
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(Iterator::collect::<std::result::Result<Vec<_>, _>>)
+        .map_err(|e| pem_error(cert, e))?;
+    if certs.is_empty() {
+        return Err(UpgradeError::Tls(format!(
+            "{}: no certificates found",
+            cert.display()
+        )));
+    }
+    let key = PrivateKeyDer::from_pem_file(key).map_err(|e| pem_error(key, e))?;
+    tls_acceptor(certs, key)
 }
 
 impl WebSocket<Server> {
-    async fn try_upgrade<S>(
+    /// Starts a connection over a stream whose opening handshake was completed elsewhere,
+    /// e.g. by an HTTP framework which hands over the upgraded IO.
+    ///
+    /// Nothing is read or written before the connection starts. `deflate` is the
+    /// permessage-deflate agreed in the handshake, `None` if it was not. The connection's
+    /// [`ConnectionInfo`] is empty, as the request was not seen.
+    pub fn from_upgraded<S>(
         stream: S,
         local_addr: SocketAddr,
         peer_addr: SocketAddr,
-    ) -> Result<Self>
+        deflate: Option<DeflateParams>,
+        config: &WebSocketConfig,
+    ) -> Self
     where
-        S: AsyncReadExt + AsyncWriteExt + Send + Unpin + 'static,
+        S: AsyncRead + AsyncWrite + Send + 'static,
     {
-        let mut reader = BufReader::new(stream);
-        let mut status_line = String::new();
-        reader
-            .read_line(&mut status_line)
-            .await
-            .map_err(|_| UpgradeError::Read)?;
+        Self::from_stream(
+            stream,
+            local_addr,
+            peer_addr,
+            deflate,
+            config,
+            ConnectionInfo::default(),
+        )
+    }
+
+    /// Returns the headers of the upgrade request, keyed by lowercased name, see
+    /// [`ConnectionInfo::headers`].
+    #[must_use]
+    pub fn request_headers(&self) -> &HashMap<String, String> { self.info.headers() }
+
+    // Read the upgrade request and check it asks for a WebSocket we can speak
+    async fn read_request<S: AsyncReadExt + Unpin>(
+        reader: &mut BufReader<S>,
+        upgrade: &UpgradeConfig,
+        received: &mut Vec<u8>,
+    ) -> Result<(ConnectionInfo, HashMap<String, String>)> {
+        let status_line = Self::read_status_line(reader, upgrade.max_request_line).await?;
+        received.extend_from_slice(status_line.as_bytes());
 
         let mut status_parts = status_line.split_whitespace();
-        if status_parts.next() != Some("GET")
-            || status_parts.next().is_none() // TODO: parse path
-            || status_parts.next() != Some("HTTP/1.1")
-        {
-            return Err(UpgradeError::StatusLine(status_line));
-        }
+        let info = match (
+            status_parts.next(),
+            status_parts.next(),
+            status_parts.next(),
+        ) {
+            (Some("GET"), Some(target), Some("HTTP/1.1")) => ConnectionInfo::from_target(target),
+            _ => return Err(UpgradeError::StatusLine(status_line)),
+        };
 
-        // collect headers in a hashmap
-        let mut headers = HashMap::new();
-        loop {
-            let mut line = String::new();
-            reader
-                .read_line(&mut line)
-                .await
-                .map_err(|_| UpgradeError::Read)?;
-            let line = line.trim_end(); // remove \r\n
-            if line.is_empty() {
-                break;
-            } // end of headers
-
-            if let Some((name, value)) = line.split_once(':') {
-                headers.insert(name.trim().to_lowercase(), value.trim().to_string());
-            }
-        }
+        let headers = read_headers(
+            reader,
+            upgrade.max_header_bytes,
+            upgrade.max_headers,
+            received,
+        )
+        .await?;
         if !headers.contains_key("host") {
             return Err(UpgradeError::MissingHeader("host"));
         }
@@ -182,6 +584,97 @@ impl WebSocket<Server> {
         let key = headers
             .get("sec-websocket-key")
             .ok_or(UpgradeError::MissingHeader("sec-websocket-key"))?;
+        if !valid_key(key) {
+            return Err(UpgradeError::Header {
+                field: "sec-websocket-key",
+                expected: "24 base64 characters encoding 16 bytes".into(),
+                got: key.clone(),
+            });
+        }
+        Ok((info, headers))
+    }
+
+    async fn try_upgrade<S>(
+        stream: S,
+        local_addr: SocketAddr,
+        peer_addr: SocketAddr,
+        settings: Settings<'_>,
+    ) -> Result<Self>
+    where
+        S: AsyncReadExt + AsyncWriteExt + Send + Unpin + 'static,
+    {
+        // bytes (received, sent) so far, kept even if the upgrade fails
+        let mut exchange = (Vec::new(), Vec::new());
+        let upgrade = settings.1;
+        let fut = Self::upgrade(stream, local_addr, peer_addr, settings, &mut exchange);
+        let res = match upgrade.handshake_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, fut)
+                .await
+                .unwrap_or_else(|_| {
+                    tracing::warn!(timeout = ?timeout, "handshake timed out");
+                    Err(UpgradeError::Timeout)
+                }),
+            None => fut.await,
+        };
+        if let Some(capture) = &upgrade.capture {
+            let (received, sent) = exchange;
+            capture.record(sent, received);
+        }
+        res
+    }
+
+    async fn upgrade<S>(
+        stream: S,
+        local_addr: SocketAddr,
+        peer_addr: SocketAddr,
+        (config, upgrade, budget): Settings<'_>,
+        (received, sent): &mut (Vec<u8>, Vec<u8>),
+    ) -> Result<Self>
+    where
+        S: AsyncReadExt + AsyncWriteExt + Send + Unpin + 'static,
+    {
+        let mut reader = BufReader::new(stream);
+        let (info, headers) = match Self::read_request(&mut reader, upgrade, received).await {
+            Ok(request) => request,
+            Err(e) => {
+                // tell HTTP clients why, unless the client is gone
+                if let Some((status, extra, body)) = refusal(&e) {
+                    reject_with(reader.get_mut(), status, extra, &body, sent).await;
+                }
+                return Err(e);
+            }
+        };
+        if let Some(routes) = &upgrade.routes
+            && !routes.iter().any(|route| route == info.path())
+        {
+            reject(reader.get_mut(), "404 Not Found", sent).await;
+            return Err(UpgradeError::NotFound(info.path().to_string()));
+        }
+
+        // reject cross-origin browser requests before switching protocols
+        if let Some(origins) = &upgrade.allowed_origins
+            && let Some(origin) = headers.get("origin")
+            && !origin_allowed(origins, origin)
+        {
+            reject(reader.get_mut(), "403 Forbidden", sent).await;
+            return Err(UpgradeError::Origin(origin.clone()));
+        }
+
+        let key = &headers["sec-websocket-key"];
+
+        let request = UpgradeRequest {
+            info: &info,
+            headers: &headers,
+            peer_addr,
+        };
+        let extra = match upgrade.hook.as_ref().map(|hook| hook.call(&request)) {
+            Some(Err(e)) => {
+                reject(reader.get_mut(), &e.status_line(), sent).await;
+                return Err(UpgradeError::Rejected(e.status()));
+            }
+            Some(Ok(extra)) => extra,
+            None => Vec::new(),
+        };
 
         let accept_key = Self::hash_key(key);
 
@@ -191,19 +684,25 @@ impl WebSocket<Server> {
              Connection: Upgrade\r\n\
              Sec-WebSocket-Accept: {accept_key}\r\n",
         );
+        hook::append_headers(&mut response, &extra);
+        let protocol = choose_protocol(&mut response, &headers, &upgrade.protocols);
 
-        let mut compressed = false;
-        let mut use_context = true;
+        let mut deflate = None;
+        let mut lease = None;
 
-        if let Some(value) = headers.get("sec-websocket-extensions") {
-            let (comp, ctx) = parse_extensions(&mut response, value);
-            compressed = comp;
-            use_context = ctx;
+        // without room for the compressor and decompressor, decline permessage-deflate
+        if let Some(value) = headers.get("sec-websocket-extensions")
+            && config.deflate_fits()
+            && let Some(reserved) = budget.lease()
+        {
+            deflate = parse_extensions(&mut response, value);
+            lease = deflate.and(Some(reserved));
         }
         response.push_str("\r\n");
         // println!("{response}");
 
         let mut stream = reader.into_inner();
+        sent.extend_from_slice(response.as_bytes());
         stream
             .write_all(response.as_bytes())
             .await
@@ -211,20 +710,96 @@ impl WebSocket<Server> {
         stream.flush().await.map_err(|_| UpgradeError::Write)?;
 
         tracing::info!(addr = ?local_addr, "upgraded client");
-        Ok(Self::from_stream(
-            stream,
-            local_addr,
-            peer_addr,
-            compressed,
-            use_context,
-        ))
+        let info = info.with_headers(headers);
+        let mut ws = Self::from_stream(stream, local_addr, peer_addr, deflate, config, info);
+        ws.deflate_lease = lease;
+        ws.protocol = protocol;
+        Ok(ws)
+    }
+}
+
+// Answer a request we will not upgrade with an empty HTTP error response
+async fn reject<S: AsyncWriteExt + Unpin>(stream: &mut S, status: &str, sent: &mut Vec<u8>) {
+    reject_with(stream, status, "", "", sent).await;
+}
+
+// Answer with an HTTP error response, adding `headers` (each ending in CRLF) and a
+// plain text `body` explaining the refusal
+async fn reject_with<S: AsyncWriteExt + Unpin>(
+    stream: &mut S,
+    status: &str,
+    headers: &str,
+    body: &str,
+    sent: &mut Vec<u8>,
+) {
+    let mut response = format!("HTTP/1.1 {status}\r\n{headers}");
+    if !body.is_empty() {
+        response.push_str("Content-Type: text/plain; charset=utf-8\r\n");
     }
+    let _ = write!(response, "Content-Length: {}\r\n\r\n{body}", body.len());
+    sent.extend_from_slice(response.as_bytes());
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.flush().await;
 }
 
-fn parse_extensions(response: &mut String, value: &str) -> (bool, bool) {
-    let mut compressed = false;
-    let mut use_context = true;
+// The status, extra headers and body refusing a request which failed with `e`, or
+// `None` if there is no request to answer, e.g. the client went away
+fn refusal(e: &UpgradeError) -> Option<(&'static str, &'static str, String)> {
+    let bad = |body: String| Some(("400 Bad Request", "", body));
+    match e {
+        UpgradeError::Header {
+            field: "sec-websocket-version",
+            ..
+        } => Some((
+            "426 Upgrade Required",
+            "Sec-WebSocket-Version: 13\r\n",
+            "unsupported WebSocket version, only 13 is supported".into(),
+        )),
+        UpgradeError::Header {
+            field, expected, ..
+        } => bad(format!("invalid {field} header, expected {expected}")),
+        UpgradeError::MissingHeader(field) => bad(format!("missing {field} header")),
+        UpgradeError::StatusLine(_) => bad("expected a GET request over HTTP/1.1".into()),
+        UpgradeError::HeadersTooLarge => Some((
+            "431 Request Header Fields Too Large",
+            "",
+            "request headers too large".into(),
+        )),
+        _ => None,
+    }
+}
+
+// Pick the first subprotocol requested by the client which the server speaks
+fn choose_protocol(
+    response: &mut String,
+    headers: &HashMap<String, String>,
+    supported: &[String],
+) -> Option<String> {
+    let protocol = header_values(headers, "sec-websocket-protocol")
+        .find(|requested| supported.iter().any(|s| s == requested))?;
+    response.push_str("Sec-WebSocket-Protocol: ");
+    response.push_str(protocol);
+    response.push_str("\r\n");
+    Some(protocol.to_string())
+}
+
+// Browsers send the origin as `scheme://host[:port]`, compared case-insensitively. An
+// allowed origin written with a trailing slash, as URLs often are, still matches.
+fn origin_allowed(origins: &[String], origin: &str) -> bool {
+    origins.iter().any(|allowed| {
+        let allowed = allowed.strip_suffix('/').unwrap_or(allowed);
+        allowed.eq_ignore_ascii_case(origin)
+    })
+}
+
+// The key must be a base64-encoded 16 byte nonce, RFC 6455 section 4.1
+fn valid_key(key: &str) -> bool {
+    key.len() == 24 && BASE64.decode(key).is_ok_and(|nonce| nonce.len() == 16)
+}
 
+// Accept the first permessage-deflate offer we can, echoing the context takeover it asks
+// for in each direction
+fn parse_extensions(response: &mut String, value: &str) -> Option<DeflateParams> {
     let offers = value.split(',');
     for offer in offers {
         let mut options = offer.split(';');
@@ -236,12 +811,15 @@ fn parse_extensions(response: &mut String, value: &str) -> (bool, bool) {
             continue;
         }
 
+        let mut params = DeflateParams::symmetric(true);
         let mut client_max_bits = false;
         let mut server_max_bits = false;
         for option in options {
             let option = option.trim();
-            if option == "client_no_context_takeover" || option == "server_no_context_takeover" {
-                use_context = false;
+            if option == "server_no_context_takeover" {
+                params.server_no_context_takeover = true;
+            } else if option == "client_no_context_takeover" {
+                params.client_no_context_takeover = true;
             } else if option.starts_with("client_max_window_bits") {
                 match option.split_once('=') {
                     None | Some((_, "15")) => client_max_bits = true,
@@ -256,11 +834,12 @@ fn parse_extensions(response: &mut String, value: &str) -> (bool, bool) {
         }
 
         // if we get here we have a compatible offer
-        compressed = true;
         response.push_str("Sec-WebSocket-Extensions: permessage-deflate");
-        if !use_context {
-            response.push_str("; server_no_context_takeover; client_no_context_takeover");
-            use_context = false;
+        if params.server_no_context_takeover {
+            response.push_str("; server_no_context_takeover");
+        }
+        if params.client_no_context_takeover {
+            response.push_str("; client_no_context_takeover");
         }
         if client_max_bits {
             response.push_str("; client_max_window_bits=15");
@@ -270,7 +849,343 @@ fn parse_extensions(response: &mut String, value: &str) -> (bool, bool) {
         }
 
         response.push_str("\r\n");
-        break;
+        return Some(params);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::{ConnectionContext, Event, HandshakeCapture, Message, Text};
+
+    #[test]
+    fn key_validation() {
+        assert!(valid_key("dGhlIHNhbXBsZSBub25jZQ=="));
+        // wrong length, bad characters, and 24 characters decoding to 18 bytes
+        for key in [
+            "dGhlIHNhbXBsZSBub25jZQ",
+            "dGhlIHNhbXBsZSBub25jZ!==",
+            "dGhlIHNhbXBsZSBub25jZQAB",
+        ] {
+            assert!(!valid_key(key), "{key}");
+        }
+    }
+
+    #[tokio::test]
+    async fn tls_files_checked() {
+        // without ssl no certificate files are needed
+        let server = WebSocketServer::bind("127.0.0.1:0", true, false)
+            .await
+            .unwrap();
+
+        let missing = server.with_tls_files("no/such/cert.pem", "no/such/key.pem");
+        assert!(matches!(missing, Err(UpgradeError::Tls(_))));
+
+        let path = std::env::temp_dir().join(format!("wust-garbage-{}.pem", std::process::id()));
+        std::fs::write(&path, "not a certificate").unwrap();
+        let server = WebSocketServer::bind("127.0.0.1:0", true, false)
+            .await
+            .unwrap();
+        let garbage = server.with_tls_files(&path, &path);
+        let _ = std::fs::remove_file(&path);
+        assert!(matches!(garbage, Err(UpgradeError::Tls(_))));
+    }
+
+    #[tokio::test]
+    async fn socket_buffers_applied() {
+        let server = WebSocketServer::bind("127.0.0.1:0", true, false)
+            .await
+            .unwrap()
+            .send_buffer_size(64 * 1024)
+            .unwrap()
+            .recv_buffer_size(32 * 1024)
+            .unwrap();
+        let Listener::Tcp(listener) = &server.listener else {
+            panic!("not a TCP listener");
+        };
+        // Linux reports the doubled size
+        let socket = SockRef::from(listener);
+        let send = socket.send_buffer_size().unwrap();
+        assert!([64 * 1024, 128 * 1024].contains(&send), "{send}");
+        let recv = socket.recv_buffer_size().unwrap();
+        assert!([32 * 1024, 64 * 1024].contains(&recv), "{recv}");
+    }
+
+    // the upgraded connection and raw response to a request with an extra header line
+    async fn respond(upgrade: &UpgradeConfig, header: &str) -> (Result<WebSocket<Server>>, String) {
+        let request = format!(
+            "GET /chat HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+             Connection: Upgrade\r\nSec-WebSocket-Version: 13\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n{header}\r\n\r\n"
+        );
+        respond_raw(upgrade, &request).await
+    }
+
+    async fn respond_raw(
+        upgrade: &UpgradeConfig,
+        request: &str,
+    ) -> (Result<WebSocket<Server>>, String) {
+        let (mut client, server) = tokio::io::duplex(4096);
+        client.write_all(request.as_bytes()).await.unwrap();
+        let config = WebSocketConfig::default();
+        let budget = DeflateBudget::new(usize::MAX);
+        let addr = crate::UNSPECIFIED_ADDR;
+        let res = WebSocket::try_upgrade(server, addr, addr, (&config, upgrade, &budget)).await;
+
+        let mut buf = vec![0; 1024];
+        let n = client.read(&mut buf).await.unwrap();
+        (res, String::from_utf8_lossy(&buf[..n]).into_owned())
+    }
+
+    #[tokio::test]
+    async fn handshake_captured() {
+        let capture = HandshakeCapture::new();
+        let upgrade = UpgradeConfig::default().capture_handshake(&capture);
+
+        let (res, response) = respond(&upgrade, "Origin: https://example.com").await;
+        assert!(res.is_ok());
+        let (sent, received) = capture.last_handshake_exchange().unwrap();
+        assert_eq!(String::from_utf8(sent).unwrap(), response);
+        let request = String::from_utf8(received).unwrap();
+        assert!(request.starts_with("GET /chat HTTP/1.1\r\n"));
+        assert!(request.ends_with("Origin: https://example.com\r\n\r\n"));
+
+        // a refused upgrade is recorded up to the refusal
+        let (res, response) = respond_raw(&upgrade, "GET /chat HTTP/1.1\r\n\r\n").await;
+        assert!(matches!(res, Err(UpgradeError::MissingHeader("host"))));
+        let (sent, received) = capture.last_handshake_exchange().unwrap();
+        assert!(response.starts_with("HTTP/1.1 400"));
+        assert_eq!(String::from_utf8(sent).unwrap(), response);
+        assert_eq!(received, b"GET /chat HTTP/1.1\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn request_headers_kept() {
+        let (res, _) = respond(&UpgradeConfig::default(), "Cookie: a=1\r\nCookie: b=2").await;
+        let ws = res.unwrap();
+        assert_eq!(ws.request_headers()["cookie"], "a=1, b=2");
+        assert_eq!(ws.info().header("HOST"), Some("localhost"));
+    }
+
+    #[tokio::test]
+    async fn upgrade_hook() {
+        let upgrade = UpgradeConfig::default().on_upgrade(|req| {
+            assert_eq!(req.info().path(), "/chat");
+            match req.header("Authorization") {
+                Some("Bearer secret") => Ok(vec![
+                    ("Set-Cookie".into(), "session=1".into()),
+                    ("X-Bad".into(), "a\r\nInjected: 1".into()),
+                ]),
+                _ => Err(HttpError::new(401, "Unauthorized")),
+            }
+        });
+
+        let (res, response) = respond(&upgrade, "Authorization: Bearer secret").await;
+        assert!(res.is_ok());
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(response.contains("\r\nSet-Cookie: session=1\r\n"));
+        assert!(!response.contains("Injected"));
+
+        let (res, response) = respond(&upgrade, "Authorization: Bearer wrong").await;
+        assert!(res.is_err());
+        assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+    }
+
+    #[tokio::test]
+    async fn protocol_negotiated() {
+        let upgrade = UpgradeConfig::default().protocols(vec!["superchat".into(), "chat".into()]);
+
+        // the client's preference wins
+        let (res, response) = respond(&upgrade, "Sec-WebSocket-Protocol: chat, superchat").await;
+        assert_eq!(res.unwrap().protocol(), Some("chat"));
+        assert!(response.contains("\r\nSec-WebSocket-Protocol: chat\r\n"));
+
+        // nothing in common still upgrades, without a protocol
+        let (res, response) = respond(&upgrade, "Sec-WebSocket-Protocol: mqtt").await;
+        assert_eq!(res.unwrap().protocol(), None);
+        assert!(!response.contains("Sec-WebSocket-Protocol"));
+    }
+
+    #[tokio::test]
+    async fn context_takeover_negotiated_per_direction() {
+        let upgrade = UpgradeConfig::default();
+        let offer = "Sec-WebSocket-Extensions: permessage-deflate; server_no_context_takeover";
+        let (res, response) = respond(&upgrade, offer).await;
+        assert!(response.contains(
+            "\r\nSec-WebSocket-Extensions: permessage-deflate; server_no_context_takeover\r\n"
+        ));
+        let deflate = res
+            .unwrap()
+            .negotiated_extensions()
+            .permessage_deflate
+            .unwrap();
+        assert!(deflate.server_no_context_takeover && !deflate.client_no_context_takeover);
+
+        let offer = "Sec-WebSocket-Extensions: permessage-deflate; client_no_context_takeover";
+        let (res, response) = respond(&upgrade, offer).await;
+        assert!(response.contains(
+            "\r\nSec-WebSocket-Extensions: permessage-deflate; client_no_context_takeover\r\n"
+        ));
+        let ws = res.unwrap();
+        let deflate = ws.negotiated_extensions().permessage_deflate.unwrap();
+        assert!(!deflate.server_no_context_takeover && deflate.client_no_context_takeover);
+        assert!(ws.compression().unwrap().context_takeover);
+    }
+
+    #[tokio::test]
+    async fn refusals_explained() {
+        let upgrade = UpgradeConfig::default();
+        let request = "GET /chat HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+            Connection: Upgrade\r\nSec-WebSocket-Version: 8\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+        let (res, response) = respond_raw(&upgrade, request).await;
+        assert!(res.is_err());
+        assert!(response.starts_with("HTTP/1.1 426 Upgrade Required\r\n"));
+        assert!(response.contains("\r\nSec-WebSocket-Version: 13\r\n"));
+
+        // a plain HTTP request is told what is missing
+        let request = "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let (res, response) = respond_raw(&upgrade, request).await;
+        assert!(matches!(res, Err(UpgradeError::MissingHeader("upgrade"))));
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(response.ends_with("\r\n\r\nmissing upgrade header"));
+
+        let (res, response) = respond_raw(&upgrade, "POST / HTTP/1.1\r\n\r\n").await;
+        assert!(matches!(res, Err(UpgradeError::StatusLine(_))));
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+
+    #[tokio::test]
+    async fn origin_allowlist() {
+        // permissive until an allowlist is set
+        let (res, _) = respond(&UpgradeConfig::default(), "Origin: https://evil.test").await;
+        assert!(res.is_ok());
+
+        let origins = vec!["https://Example.com/".to_string()];
+        let upgrade = UpgradeConfig::default().allowed_origins(origins);
+        let (res, response) = respond(&upgrade, "Origin: https://evil.test").await;
+        assert!(matches!(res, Err(UpgradeError::Origin(o)) if o == "https://evil.test"));
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+
+        let (res, _) = respond(&upgrade, "Origin: HTTPS://example.COM").await;
+        assert!(res.is_ok());
+        // a different port is a different origin
+        let (res, _) = respond(&upgrade, "Origin: https://example.com:8443").await;
+        assert!(res.is_err());
+        // non-browser clients send no origin
+        let (res, _) = respond(&upgrade, "User-Agent: test").await;
+        assert!(res.is_ok());
+    }
+
+    #[tokio::test]
+    async fn upgraded_stream_skips_handshake() {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let addr = crate::UNSPECIFIED_ADDR;
+        let config = WebSocketConfig::default();
+        let deflate = Some(DeflateParams::symmetric(true));
+        let mut server = WebSocket::from_upgraded(server, addr, addr, deflate, &config);
+        let mut client = crate::WebSocketClient::over_stream(client, deflate, &config);
+        assert!(server.negotiated_extensions().permessage_deflate.is_some());
+
+        client.send_text("already upgraded").await.unwrap();
+        let Some(Event::Text(t)) = server.recv().await else {
+            panic!("no message");
+        };
+        assert_eq!(t.as_str(), "already upgraded");
+    }
+
+    struct Tag(&'static str);
+    #[async_trait::async_trait]
+    impl MessageHandler for Tag {
+        async fn on_text(&self, conn: &ConnectionContext, s: Text) -> Option<Message> {
+            let room = conn.info().query_param("room").unwrap_or("-");
+            Some(Message::Text(format!("{} {room} {s}", self.0)))
+        }
+        async fn on_binary(&self, _conn: &ConnectionContext, _b: Bytes) -> Option<Message> { None }
+        async fn on_close(&self, _conn: &ConnectionContext) {}
+        async fn on_error(&self, _conn: &ConnectionContext) {}
+        async fn on_pong(&self, _conn: &ConnectionContext, _latency: u16) {}
+    }
+
+    #[tokio::test]
+    async fn routes_dispatch_by_path() {
+        let server = WebSocketServer::bind("127.0.0.1:0", true, false)
+            .await
+            .unwrap()
+            .route("/chat", Tag("chat"))
+            .route("/telemetry", Tag("telemetry"));
+        let addr = server.addr();
+        tokio::spawn(async move { server.run_routes().await });
+
+        for (path, reply) in [
+            ("/chat?room=7", "chat 7 hi"),
+            ("/telemetry", "telemetry - hi"),
+        ] {
+            let url = format!("ws://{addr}{path}");
+            let mut client = crate::WebSocketClient::connect_url(&url).await.unwrap();
+            client.send_text("hi").await.unwrap();
+            match client.recv().await {
+                Some(Event::Text(t)) => assert_eq!(t.as_str(), reply),
+                e => panic!("unexpected event {e:?}"),
+            }
+        }
+
+        let url = format!("ws://{addr}/other");
+        assert!(crate::WebSocketClient::connect_url(&url).await.is_err());
+        let upgrade = UpgradeConfig {
+            routes: Some(Arc::new(["/telemetry".to_string()])),
+            ..UpgradeConfig::default()
+        };
+        let (res, response) = respond(&upgrade, "").await;
+        assert!(matches!(res, Err(UpgradeError::NotFound(path)) if path == "/chat"));
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[tokio::test]
+    async fn shutdown_closes_with_going_away() {
+        use tokio::net::TcpStream;
+
+        let drain = Duration::from_millis(300);
+        let server = WebSocketServer::bind("127.0.0.1:0", true, false)
+            .await
+            .unwrap()
+            .drain_timeout(drain);
+        let addr = server.addr();
+        let handle = server.shutdown_handle();
+        let running = tokio::spawn(async move { server.run(Tag("echo")).await });
+
+        let mut client = crate::WebSocketClient::connect_url(&format!("ws://{addr}/"))
+            .await
+            .unwrap();
+        // a peer which never answers the close, so only the drain timeout ends it
+        let mut silent = TcpStream::connect(addr).await.unwrap();
+        let request = "GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+                       Connection: Upgrade\r\nSec-WebSocket-Version: 13\r\n\
+                       Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+        silent.write_all(request.as_bytes()).await.unwrap();
+        let mut buf = [0; 1024];
+        let n = silent.read(&mut buf).await.unwrap();
+        assert!(buf[..n].starts_with(b"HTTP/1.1 101"));
+
+        let start = Instant::now();
+        handle.shutdown();
+        tokio::time::timeout(Duration::from_secs(1), running)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let closed = tokio::time::timeout(drain, client.recv()).await.unwrap();
+        assert!(matches!(closed, Some(Event::Closed(info)) if info.code == 1001));
+
+        let mut received = Vec::new();
+        let read = tokio::time::timeout(drain * 3, silent.read_to_end(&mut received));
+        read.await.unwrap().unwrap();
+        // an unmasked close frame carrying 1001, then the connection ends once drained
+        assert_eq!(received[0], 0x88);
+        assert_eq!(received[2..4], 1001u16.to_be_bytes());
+        assert!(start.elapsed() >= drain);
     }
-    (compressed, use_context)
 }
@@ -0,0 +1,101 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Write},
+    net::SocketAddr,
+    sync::Arc,
+};
+
+use crate::ws::ConnectionInfo;
+
+/// The upgrade request as seen by [`UpgradeConfig::on_upgrade`](crate::UpgradeConfig::on_upgrade).
+#[derive(Debug)]
+pub struct UpgradeRequest<'a> {
+    pub(crate) info: &'a ConnectionInfo,
+    pub(crate) headers: &'a HashMap<String, String>,
+    pub(crate) peer_addr: SocketAddr,
+}
+
+impl UpgradeRequest<'_> {
+    /// Returns the request path and query parameters.
+    #[must_use]
+    pub fn info(&self) -> &ConnectionInfo { self.info }
+
+    /// Returns the value of the header `name`, which is matched case-insensitively.
+    #[must_use]
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get(&name.to_ascii_lowercase())
+            .map(String::as_str)
+    }
+
+    /// Returns the address of the client.
+    #[must_use]
+    pub fn peer_addr(&self) -> SocketAddr { self.peer_addr }
+}
+
+/// An HTTP error response refusing an upgrade, e.g. `HttpError::new(401, "Unauthorized")`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpError {
+    status: u16,
+    reason: String,
+}
+
+impl HttpError {
+    /// Creates an error response with the given status code and reason phrase.
+    pub fn new(status: u16, reason: impl Into<String>) -> Self {
+        Self {
+            status,
+            reason: reason.into(),
+        }
+    }
+
+    /// Returns the status code.
+    #[must_use]
+    pub fn status(&self) -> u16 { self.status }
+
+    // status line without the HTTP version, e.g. "401 Unauthorized"
+    pub(crate) fn status_line(&self) -> String {
+        // the reason ends up in the response, keep it on one line
+        let reason: String = self.reason.chars().filter(|c| !c.is_control()).collect();
+        format!("{} {reason}", self.status)
+    }
+}
+
+// Headers added to the 101 response, or the error to refuse with
+pub(crate) type HookResult = Result<Vec<(String, String)>, HttpError>;
+
+// Shared callback, wrapped so that the config can still derive Debug
+#[derive(Clone)]
+pub(crate) struct UpgradeHook(Arc<dyn Fn(&UpgradeRequest) -> HookResult + Send + Sync>);
+
+impl UpgradeHook {
+    pub(crate) fn new<F>(f: F) -> Self
+    where
+        F: Fn(&UpgradeRequest) -> HookResult + Send + Sync + 'static,
+    {
+        Self(Arc::new(f))
+    }
+
+    pub(crate) fn call(&self, request: &UpgradeRequest) -> HookResult { (self.0)(request) }
+}
+
+impl fmt::Debug for UpgradeHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { f.write_str("UpgradeHook") }
+}
+
+// Add headers to a response, leaving out any which would break its framing
+pub(crate) fn append_headers(response: &mut String, headers: &[(String, String)]) {
+    for (name, value) in headers {
+        if valid_header(name, value) {
+            let _ = write!(response, "{name}: {value}\r\n");
+        } else {
+            tracing::warn!(name = ?name, "leaving out invalid upgrade response header");
+        }
+    }
+}
+
+fn valid_header(name: &str, value: &str) -> bool {
+    !name.is_empty()
+        && name.bytes().all(|b| b.is_ascii_graphic() && b != b':')
+        && !value.bytes().any(|b| b == b'\r' || b == b'\n')
+}
@@ -0,0 +1,50 @@
+use std::{collections::HashMap, sync::Arc};
+
+use bytes::Bytes;
+
+use crate::{ConnectionContext, Message, MessageHandler, Text};
+
+// Dispatches each connection to the handler routed for its path, see
+// `WebSocketServer::route`. Unmatched paths are refused during the upgrade.
+pub(crate) struct Router(pub(crate) HashMap<String, Arc<dyn MessageHandler>>);
+
+impl Router {
+    fn handler(&self, conn: &ConnectionContext) -> Option<&Arc<dyn MessageHandler>> {
+        self.0.get(conn.info().path())
+    }
+}
+
+#[async_trait::async_trait]
+impl MessageHandler for Router {
+    async fn on_open(&self, conn: &ConnectionContext) {
+        if let Some(handler) = self.handler(conn) {
+            handler.on_open(conn).await;
+        }
+    }
+
+    async fn on_text(&self, conn: &ConnectionContext, s: Text) -> Option<Message> {
+        self.handler(conn)?.on_text(conn, s).await
+    }
+
+    async fn on_binary(&self, conn: &ConnectionContext, b: Bytes) -> Option<Message> {
+        self.handler(conn)?.on_binary(conn, b).await
+    }
+
+    async fn on_close(&self, conn: &ConnectionContext) {
+        if let Some(handler) = self.handler(conn) {
+            handler.on_close(conn).await;
+        }
+    }
+
+    async fn on_error(&self, conn: &ConnectionContext) {
+        if let Some(handler) = self.handler(conn) {
+            handler.on_error(conn).await;
+        }
+    }
+
+    async fn on_pong(&self, conn: &ConnectionContext, latency: u16) {
+        if let Some(handler) = self.handler(conn) {
+            handler.on_pong(conn, latency).await;
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use std::net::SocketAddr;
+
+use crate::{
+    Event, PingError, SendError, WebSocket,
+    role::{Client, Server},
+};
+
+/// A connection of either role, for holding client and server connections together.
+///
+/// Proxies and gateways which accept inbound connections and open outbound ones can keep
+/// both in one collection. Framing stays specific to the underlying role.
+pub enum BoxedWebSocket {
+    /// A connection we opened.
+    Client(WebSocket<Client>),
+    /// A connection we accepted.
+    Server(WebSocket<Server>),
+}
+
+// Forward a call to the underlying connection, whichever role it has
+macro_rules! delegate {
+    ($self:ident, $ws:ident => $call:expr) => {
+        match $self {
+            Self::Client($ws) => $call,
+            Self::Server($ws) => $call,
+        }
+    };
+}
+
+impl BoxedWebSocket {
+    /// Returns true if this is a client-role connection.
+    #[must_use]
+    pub fn is_client(&self) -> bool { matches!(self, Self::Client(_)) }
+
+    /// Sends text to the connected endpoint, see [`WebSocket::send_text`].
+    /// # Errors
+    /// Fails as [`WebSocket::send_text`] does.
+    pub async fn send_text(&mut self, text: &str) -> Result<(), SendError> {
+        delegate!(self, ws => ws.send_text(text).await)
+    }
+
+    /// Sends bytes to the connected endpoint, see [`WebSocket::send_bytes`].
+    /// # Errors
+    /// Fails as [`WebSocket::send_text`] does.
+    pub async fn send_bytes(&mut self, bytes: &[u8]) -> Result<(), SendError> {
+        delegate!(self, ws => ws.send_bytes(bytes).await)
+    }
+
+    /// Receives the next event, see [`WebSocket::recv`].
+    pub async fn recv(&mut self) -> Option<Event> { delegate!(self, ws => ws.recv().await) }
+
+    /// Closes the connection, see [`WebSocket::close`].
+    pub async fn close(&mut self) { delegate!(self, ws => ws.close().await) }
+
+    /// Sends a ping, see [`WebSocket::ping`].
+    /// # Errors
+    /// Fails as [`WebSocket::ping`] does.
+    pub async fn ping(&self) -> Result<(), PingError> { delegate!(self, ws => ws.ping().await) }
+
+    /// Returns the address of the peer.
+    #[must_use]
+    pub fn peer_addr(&self) -> SocketAddr { delegate!(self, ws => ws.peer_addr()) }
+
+    /// Returns the local address of the connection.
+    #[must_use]
+    pub fn local_addr(&self) -> SocketAddr { delegate!(self, ws => ws.local_addr()) }
+}
+
+impl From<WebSocket<Client>> for BoxedWebSocket {
+    fn from(ws: WebSocket<Client>) -> Self { Self::Client(ws) }
+}
+
+impl From<WebSocket<Server>> for BoxedWebSocket {
+    fn from(ws: WebSocket<Server>) -> Self { Self::Server(ws) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::WebSocketClient;
+
+    #[tokio::test]
+    async fn mixed_roles() {
+        let (client, server) = WebSocketClient::pair();
+        let mut conns: Vec<BoxedWebSocket> = vec![client.into(), server.into()];
+        assert!(conns[0].is_client() && !conns[1].is_client());
+
+        conns[0].send_text("ping").await.unwrap();
+        assert!(matches!(conns[1].recv().await, Some(Event::Text(t)) if t.as_str() == "ping"));
+        conns[1].send_bytes(b"pong").await.unwrap();
+        assert!(matches!(conns[0].recv().await, Some(Event::Binary(b)) if b == "pong"[..]));
+    }
+}
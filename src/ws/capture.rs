@@ -0,0 +1,35 @@
+use std::sync::{Arc, Mutex};
+
+// Bytes sent and received during one handshake
+type Exchange = (Vec<u8>, Vec<u8>);
+
+/// Records the raw bytes of the most recent handshake, for debugging upgrades which
+/// fail for reasons the [`UpgradeError`](crate::UpgradeError) does not explain.
+///
+/// Pass a clone to [`ClientBuilder::capture_handshake`](crate::ClientBuilder::capture_handshake)
+/// or [`UpgradeConfig::capture_handshake`](crate::UpgradeConfig::capture_handshake) and keep
+/// the original to read it. On a server every connection records into the same capture.
+#[derive(Debug, Clone, Default)]
+pub struct HandshakeCapture {
+    last: Arc<Mutex<Option<Exchange>>>,
+}
+
+impl HandshakeCapture {
+    /// Creates an empty capture.
+    #[must_use]
+    pub fn new() -> Self { Self::default() }
+
+    /// Returns the bytes sent and received during the last handshake, in that order.
+    ///
+    /// If the handshake failed, this holds whatever was exchanged before the failure.
+    #[must_use]
+    pub fn last_handshake_exchange(&self) -> Option<(Vec<u8>, Vec<u8>)> {
+        self.last.lock().ok()?.clone()
+    }
+
+    pub(crate) fn record(&self, sent: Vec<u8>, received: Vec<u8>) {
+        if let Ok(mut last) = self.last.lock() {
+            *last = Some((sent, received));
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use std::{collections::VecDeque, sync::Arc};
+
+use bytes::Bytes;
+use tokio::task::JoinHandle;
+
+use super::{ConnectionContext, Message, MessageHandler, Text, WebSocket};
+use crate::{CloseInfo, Event, role::RolePolicy};
+
+// Handler calls still running, oldest first
+type InFlight = VecDeque<JoinHandle<Option<Message>>>;
+
+impl<R: RolePolicy> WebSocket<R> {
+    /// Starts a recv loop like [`recv_loop`](WebSocket::recv_loop), but lets up to
+    /// `max_in_flight` text and binary messages be handled at once, each on its own task,
+    /// e.g. for handlers which wait on a database.
+    ///
+    /// Replies are sent in the order their messages arrived. Once `max_in_flight` calls
+    /// are running no more events are read until the oldest finishes. Pings are still
+    /// answered meanwhile, as the pong is queued before the ping's event, until about
+    /// [`read_buffer_size`](crate::WebSocketConfig::read_buffer_size) of later messages
+    /// has been read ahead, after which reading pauses.
+    ///
+    /// Returns the [`CloseInfo`] which ended the connection, after every running call has
+    /// finished and [`on_close`](MessageHandler::on_close) has been called.
+    pub async fn recv_loop_concurrent<H: MessageHandler + ?Sized>(
+        &mut self,
+        handler: Arc<H>,
+        max_in_flight: usize,
+    ) -> CloseInfo {
+        let conn = Arc::new(self.context());
+        let mut in_flight = InFlight::new();
+        let max_in_flight = max_in_flight.max(1);
+        handler.on_open(&conn).await;
+        let info = loop {
+            let event = tokio::select! {
+                reply = next_reply(&mut in_flight) => {
+                    self.handle_ws_message(reply).await;
+                    continue;
+                }
+                event = self.next_event(), if in_flight.len() < max_in_flight => event,
+            };
+            let Some(event) = event else {
+                break CloseInfo::abnormal();
+            };
+            let (data, text) = match event {
+                Event::Text(s) => (s.inner, true),
+                Event::Binary(b) => (b, false),
+                Event::Message { data, text, .. } => (data, text),
+                Event::Closed(info) => break info,
+                Event::Pong { latency, .. } => {
+                    handler.on_pong(&conn, latency).await;
+                    continue;
+                }
+                Event::Ping(_) | Event::Drained => continue,
+            };
+            in_flight.push_back(spawn_call(&handler, &conn, data, text));
+        };
+        // the connection is gone, so replies still to come have nowhere to go
+        while !in_flight.is_empty() {
+            next_reply(&mut in_flight).await;
+        }
+        handler.on_close(&conn).await;
+        info
+    }
+}
+
+fn spawn_call<H: MessageHandler + ?Sized>(
+    handler: &Arc<H>,
+    conn: &Arc<ConnectionContext>,
+    data: Bytes,
+    text: bool,
+) -> JoinHandle<Option<Message>> {
+    let handler = handler.clone();
+    let conn = conn.clone();
+    tokio::spawn(async move {
+        if text {
+            handler.on_text(&conn, Text { inner: data }).await
+        } else {
+            handler.on_binary(&conn, data).await
+        }
+    })
+}
+
+// The reply of the oldest call once it finishes, never if none are running
+async fn next_reply(in_flight: &mut InFlight) -> Option<Message> {
+    let Some(call) = in_flight.front_mut() else {
+        return std::future::pending().await;
+    };
+    let reply = call.await;
+    in_flight.pop_front();
+    reply.unwrap_or_else(|e| {
+        tracing::error!(e = ?e, "message handler failed");
+        None
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::Notify;
+
+    use super::*;
+    use crate::WebSocketClient;
+
+    // "first" only replies once "second" has been handled
+    #[derive(Default)]
+    struct Waiting(Notify);
+    #[async_trait::async_trait]
+    impl MessageHandler for Waiting {
+        async fn on_text(&self, _conn: &ConnectionContext, s: Text) -> Option<Message> {
+            if s.as_str() == "first" {
+                self.0.notified().await;
+            } else {
+                self.0.notify_one();
+            }
+            Some(Message::Text(s.as_str().to_uppercase()))
+        }
+        async fn on_binary(&self, _conn: &ConnectionContext, _b: Bytes) -> Option<Message> { None }
+        async fn on_close(&self, _conn: &ConnectionContext) {}
+        async fn on_error(&self, _conn: &ConnectionContext) {}
+        async fn on_pong(&self, _conn: &ConnectionContext, _latency: u16) {}
+    }
+
+    #[tokio::test]
+    async fn handles_messages_concurrently_in_order() {
+        let (mut client, mut server) = WebSocketClient::pair();
+        let handler = Arc::new(Waiting::default());
+        let running = tokio::spawn(async move { server.recv_loop_concurrent(handler, 2).await });
+
+        client.send_text("first").await.unwrap();
+        client.send_text("second").await.unwrap();
+        assert!(matches!(client.recv().await, Some(Event::Text(t)) if t.as_str() == "FIRST"));
+        assert!(matches!(client.recv().await, Some(Event::Text(t)) if t.as_str() == "SECOND"));
+
+        client.close().await;
+        running.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn pings_answered_while_handler_waits() {
+        let (mut client, mut server) = WebSocketClient::pair();
+        let handler = Arc::new(Waiting::default());
+        // "first" waits for a message the loop never gets to
+        let running = tokio::spawn(async move { server.recv_loop_concurrent(handler, 1).await });
+
+        client.send_text("first").await.unwrap();
+        client.send_text("held").await.unwrap();
+        let rtt = client.ping_rtt(std::time::Duration::from_secs(1)).await;
+        assert!(rtt.is_ok(), "{rtt:?}");
+        running.abort();
+    }
+}
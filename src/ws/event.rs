@@ -1,28 +1,88 @@
-use std::{fmt::Display, io::Write};
+use std::{
+    fmt::{self, Debug, Display},
+    io::Write,
+};
 
 use bytes::{Bytes, BytesMut};
 use flate2::write::DeflateDecoder;
 
-use crate::MAX_MESSAGE_SIZE;
+use crate::{
+    MAX_MESSAGE_SIZE,
+    error::CloseReason,
+    frames::{DEFLATE_TAIL, prime_inflater},
+    protocol::Utf8Incremental,
+};
+
+/// Payloads longer than this are truncated in `Debug` output.
+const DEBUG_PAYLOAD_LEN: usize = 64;
 
 /// `Event`s are produced by [`WebSocketClient::recv`](crate::WebSocketClient::recv)
 /// and [`WebSocketClient::recv_timeout`](crate::WebSocketClient::recv_timeout)
-#[derive(Debug)]
+#[derive(PartialEq)]
 pub enum Event {
-    /// Pong event with its latency in milliseconds.
-    Pong(u16),
+    /// A pong answered our ping.
+    Pong {
+        /// Round-trip time in milliseconds.
+        latency: u16,
+        /// Sequence number of the ping answered, counting from 0 for each connection.
+        /// A gap means pongs were lost or the peer answered only the latest of
+        /// several pings.
+        seq: u32,
+    },
+    /// The peer pinged us, carrying the ping's payload. A pong is sent automatically
+    /// unless [`WebSocketConfig::auto_pong`](crate::WebSocketConfig::auto_pong) is off.
+    /// Dropped if the event queue is full.
+    Ping(Bytes),
     /// Valid UTF-8 message.
     Text(Text),
     /// Binary message bytes.
     Binary(Bytes),
     /// The connection to the websocket has been closed.
-    Closed,
+    Closed(CloseInfo),
+    /// A message along with how it arrived, produced instead of [`Event::Text`] and
+    /// [`Event::Binary`] when [`WebSocketConfig::message_metadata`](crate::WebSocketConfig::message_metadata)
+    /// is enabled.
+    Message {
+        /// The message payload, already inflated. Valid UTF-8 if `text` is set.
+        data: Bytes,
+        /// Whether the message was sent as text rather than binary.
+        text: bool,
+        /// Whether the peer compressed the message.
+        compressed: bool,
+        /// How many frames the message was assembled from.
+        fragments: usize,
+    },
+    /// The send queue has drained to the low-water mark after filling to the high-water
+    /// mark, see [`WebSocketConfig::drain_events`](crate::WebSocketConfig::drain_events).
+    Drained,
+}
+
+/// The close code and reason which ended a connection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CloseInfo {
+    /// Close code sent by the peer, 1005 if their close frame had no code,
+    /// or 1006 if the connection was dropped without a close frame.
+    pub code: u16,
+    /// Reason text sent by the peer, may be empty.
+    pub reason: String,
+}
+
+impl CloseInfo {
+    pub(crate) fn new(code: u16, reason: &str) -> Self {
+        Self {
+            code,
+            reason: reason.to_string(),
+        }
+    }
+
+    // connection ended without receiving a close frame
+    pub(crate) fn abnormal() -> Self { Self::new(CloseReason::Abnormal as u16, "") }
 }
 
 // UTF-8 validated bytes
-#[derive(Debug)]
+#[derive(PartialEq)]
 pub struct Text {
-    inner: Bytes,
+    pub(super) inner: Bytes,
 }
 
 impl Text {
@@ -38,8 +98,75 @@ impl Text {
 }
 
 impl Display for Text {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.as_str())
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { write!(f, "{}", self.as_str()) }
+}
+
+impl Debug for Text {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        DebugPayload::Text(self.as_str()).fmt(f)
+    }
+}
+
+impl Debug for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Pong { latency, seq } => f
+                .debug_struct("Pong")
+                .field("latency", latency)
+                .field("seq", seq)
+                .finish(),
+            Self::Ping(b) => f
+                .debug_tuple("Ping")
+                .field(&DebugPayload::Binary(b))
+                .finish(),
+            Self::Text(t) => f.debug_tuple("Text").field(t).finish(),
+            Self::Binary(b) => f
+                .debug_tuple("Binary")
+                .field(&DebugPayload::Binary(b))
+                .finish(),
+            Self::Message {
+                data,
+                text,
+                compressed,
+                fragments,
+            } => f
+                .debug_struct("Message")
+                .field("data", &DebugPayload::Binary(data))
+                .field("text", text)
+                .field("compressed", compressed)
+                .field("fragments", fragments)
+                .finish(),
+            Self::Closed(info) => f.debug_tuple("Closed").field(info).finish(),
+            Self::Drained => f.write_str("Drained"),
+        }
+    }
+}
+
+// Formats a payload, truncating anything longer than DEBUG_PAYLOAD_LEN bytes
+// so logging large messages stays readable
+pub(crate) enum DebugPayload<'a> {
+    Text(&'a str),
+    Binary(&'a [u8]),
+}
+
+impl Debug for DebugPayload<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let len = match self {
+            Self::Text(s) => {
+                let end = s.floor_char_boundary(DEBUG_PAYLOAD_LEN);
+                write!(f, "{:?}", &s[..end])?;
+                s.len()
+            }
+            Self::Binary(b) => {
+                let end = b.len().min(DEBUG_PAYLOAD_LEN);
+                write!(f, "b\"{}\"", b[..end].escape_ascii())?;
+                b.len()
+            }
+        };
+        if len > DEBUG_PAYLOAD_LEN {
+            write!(f, "...({len} bytes)")?;
+        }
+        Ok(())
     }
 }
 
@@ -47,16 +174,21 @@ impl Event {
     pub(crate) fn len(&self) -> usize {
         match self {
             Self::Text(s) => s.len(),
-            Self::Binary(b) => b.len(),
+            Self::Binary(b) | Self::Message { data: b, .. } => b.len(),
             _ => 0,
         }
     }
 }
 
 #[derive(Debug)]
-pub(crate) enum PartialMessage {
-    Text(BytesMut),
-    Binary(BytesMut),
+pub(crate) struct PartialMessage {
+    data: BytesMut,
+    text: bool,
+    // RSV1 was set on the first frame, peers may send any message uncompressed
+    pub(crate) compressed: bool,
+    fragments: usize,
+    // uncompressed text is validated frame by frame, compressed once inflated
+    utf8: Option<Utf8Incremental>,
 }
 
 #[derive(Debug)]
@@ -66,56 +198,98 @@ pub(crate) enum MessageError {
 }
 
 impl PartialMessage {
-    pub(crate) fn text() -> Self { Self::Text(BytesMut::with_capacity(MAX_MESSAGE_SIZE)) }
+    pub(crate) fn text(compressed: bool) -> Self { Self::new(true, compressed) }
 
-    pub(crate) fn binary() -> Self { Self::Binary(BytesMut::with_capacity(MAX_MESSAGE_SIZE)) }
+    pub(crate) fn binary(compressed: bool) -> Self { Self::new(false, compressed) }
 
-    pub(crate) fn push_bytes(&mut self, bytes: &[u8]) {
-        match self {
-            Self::Text(v) | Self::Binary(v) => v.extend_from_slice(bytes),
+    fn new(text: bool, compressed: bool) -> Self {
+        Self {
+            data: BytesMut::with_capacity(MAX_MESSAGE_SIZE),
+            text,
+            compressed,
+            fragments: 0,
+            utf8: (text && !compressed).then(Utf8Incremental::default),
         }
     }
 
-    pub(crate) fn len(&self) -> usize {
-        match self {
-            Self::Text(v) | Self::Binary(v) => v.len(),
+    // append the payload of the next frame, failing as soon as text is invalid
+    pub(crate) fn push_bytes(&mut self, bytes: &[u8]) -> Result<(), MessageError> {
+        if let Some(utf8) = &mut self.utf8
+            && !utf8.push(bytes)
+        {
+            return Err(MessageError::Utf8);
         }
+        self.data.extend_from_slice(bytes);
+        self.fragments += 1;
+        Ok(())
     }
 
+    pub(crate) fn len(&self) -> usize { self.data.len() }
+
     pub(crate) fn into_message(
         self,
         inflater: &mut Option<DeflateDecoder<Vec<u8>>>,
         use_context: bool,
+        dictionary: Option<&[u8]>,
+        ratio_warning: Option<usize>,
+        metadata: bool,
     ) -> Result<Event, MessageError> {
-        let (mut data, text) = match self {
-            Self::Text(v) => (v, true),
-            Self::Binary(v) => (v, false),
-        };
+        let Self {
+            mut data,
+            text,
+            compressed,
+            fragments,
+            utf8,
+        } = self;
 
-        if let Some(inflater) = inflater {
+        if let Some(inflater) = inflater.as_mut().filter(|_| compressed) {
             let init_size = data.len();
 
-            let end = if use_context {
-                inflater.get_ref().len()
+            if use_context {
+                // the window lives in the inflater, earlier output is not needed
+                inflater.get_mut().clear();
             } else {
-                data.extend_from_slice(&[0, 0, 0xFF, 0xFF]);
                 let _ = inflater.reset(vec![]);
-                0
-            };
+            }
+            if let Some(dictionary) = dictionary {
+                prime_inflater(inflater, dictionary);
+            }
+            data.extend_from_slice(&DEFLATE_TAIL);
 
             if inflater.write_all(&data).is_err() || inflater.flush().is_err() {
                 return Err(MessageError::Deflate);
             }
             data.clear();
-            data.extend_from_slice(&inflater.get_ref()[end..]);
+            data.extend_from_slice(inflater.get_ref());
             tracing::trace!("inflated {init_size} => {}", data.len());
+
+            if let Some(ratio) = ratio_warning
+                && data.len() / init_size.max(1) >= ratio
+            {
+                tracing::warn!(
+                    compressed = init_size,
+                    inflated = data.len(),
+                    "message exceeded inflation ratio, possible deflate bomb"
+                );
+            }
         }
         let data = data.freeze();
 
-        if text {
-            if str::from_utf8(&data).is_err() {
-                return Err(MessageError::Utf8);
-            }
+        let valid = match utf8 {
+            Some(utf8) => utf8.is_complete(),
+            None => !text || str::from_utf8(&data).is_ok(),
+        };
+        if !valid {
+            return Err(MessageError::Utf8);
+        }
+        if metadata {
+            Ok(Event::Message {
+                data,
+                text,
+                compressed,
+                fragments,
+            })
+        } else if text {
             Ok(Event::Text(Text { inner: data }))
         } else {
             Ok(Event::Binary(data))
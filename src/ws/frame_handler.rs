@@ -1,10 +1,14 @@
-use std::sync::{Arc, atomic::Ordering};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, atomic::Ordering},
+};
 
 use flate2::write::DeflateDecoder;
+use tokio::sync::mpsc::error::TrySendError;
 
-use super::{Inner, PartialMessage};
+use super::{CloseInfo, Inner, Observation, PartialMessage};
 use crate::{
-    Event, MAX_MESSAGE_SIZE,
+    ClosePolicy, Event, MAX_MESSAGE_SIZE, PingPolicy, WebSocketConfig,
     error::CloseReason,
     frames::{DecodedFrame, Opcode, control},
     protocol::PongError,
@@ -12,13 +16,51 @@ use crate::{
     ws::{event::MessageError, websocket::WsSender},
 };
 
+// What the reader keeps between frames
+#[derive(Default)]
+pub(super) struct ReadState {
+    // message being assembled from fragments
+    pub(super) partial: Option<PartialMessage>,
+    // finished message waiting for room in the event queue
+    pub(super) stalled: Option<Event>,
+    // frames read while a message is stalled, in order, and their payload size
+    held: VecDeque<DecodedFrame>,
+    pub(super) held_bytes: usize,
+    // the peer's close has arrived, nothing may follow it
+    pub(super) peer_closed: bool,
+}
+
+impl ReadState {
+    // While a message is stalled pings and pongs are still answered, anything else
+    // waits its turn so messages and the close stay in order
+    pub(super) fn hold(&mut self, frame: DecodedFrame) -> Option<DecodedFrame> {
+        if self.stalled.is_none() || matches!(frame.opcode, Opcode::Ping | Opcode::Pong) {
+            return Some(frame);
+        }
+        self.held_bytes += frame.payload.len();
+        self.held.push_back(frame);
+        None
+    }
+
+    // The next held frame, once nothing is stalled
+    pub(super) fn resume(&mut self) -> Option<DecodedFrame> {
+        if self.stalled.is_some() {
+            return None;
+        }
+        let frame = self.held.pop_front()?;
+        self.held_bytes -= frame.payload.len();
+        Some(frame)
+    }
+}
+
 pub(super) async fn handle_frame<R: RolePolicy>(
     frame: &DecodedFrame,
     inner: &Arc<Inner>,
-    partial_msg: &mut Option<PartialMessage>,
+    state: &mut ReadState,
     sender: &WsSender,
     inflater: &mut Option<DeflateDecoder<Vec<u8>>>,
     use_context: bool,
+    config: &WebSocketConfig,
 ) -> Option<()> {
     tracing::trace!(
         "got frame {:?} {} fin={}",
@@ -27,35 +69,116 @@ pub(super) async fn handle_frame<R: RolePolicy>(
         frame.is_fin
     );
     match frame.opcode {
+        // once we have sent a close only the peer's close matters
+        Opcode::Text | Opcode::Bin | Opcode::Cont if inner.closing.load(Ordering::Acquire) => {
+            tracing::trace!(opcode = ?frame.opcode, "closing, dropping data frame");
+        }
         Opcode::Text | Opcode::Bin | Opcode::Cont => {
-            handle_data::<R>(frame, partial_msg, sender, inflater, use_context).await?;
+            handle_data::<R>(frame, inner, state, sender, inflater, use_context, config).await?;
         }
-        Opcode::Pong => handle_pong::<R>(frame, sender, inner).await,
-        Opcode::Ping => handle_ping::<R>(frame, sender).await,
+        Opcode::Pong => handle_pong::<R>(frame, sender, inner, config.ping_policy).await?,
+        Opcode::Ping => handle_ping::<R>(frame, sender, inner, config).await?,
         Opcode::Close => {
-            handle_close::<R>(frame, inner, sender).await;
+            handle_close::<R>(frame, inner, sender, config.close_policy).await;
+            state.peer_closed = true;
             return None;
         }
     }
     Some(())
 }
 
-// Reply with pong
-async fn handle_ping<R: RolePolicy>(frame: &DecodedFrame, sender: &WsSender) {
-    tracing::info!("received PING, scheduling PONG");
-    let bytes = control::pong::<R>(&frame.payload);
-    let _ = sender.ctrl(bytes).await;
+// Reply with pong and report the ping. The pong is queued first so it never waits on
+// the application
+async fn handle_ping<R: RolePolicy>(
+    frame: &DecodedFrame,
+    sender: &WsSender,
+    inner: &Arc<Inner>,
+    config: &WebSocketConfig,
+) -> Option<()> {
+    if config.auto_pong {
+        queue_pong::<R>(frame, sender, inner, config.ping_policy).await;
+    }
+    // never wait on the event or control queue here, a ping flood would stall the reader
+    match sender.try_event(Event::Ping(frame.payload.clone())) {
+        Ok(()) => {}
+        Err(TrySendError::Full(_)) => tracing::debug!("event queue full, dropping ping event"),
+        Err(TrySendError::Closed(_)) => {
+            receiver_dropped::<R>(inner, sender).await;
+            return None;
+        }
+    }
+    Some(())
+}
+
+// When lenient only one pong is queued at a time and it carries the payload of the
+// latest ping
+async fn queue_pong<R: RolePolicy>(
+    frame: &DecodedFrame,
+    sender: &WsSender,
+    inner: &Arc<Inner>,
+    policy: PingPolicy,
+) {
+    tracing::debug!("received PING, scheduling PONG");
+    match policy {
+        PingPolicy::Strict => {
+            if let Err(TrySendError::Full(_)) = sender.try_ctrl(control::pong::<R>(&frame.payload))
+            {
+                tracing::debug!("control queue full, dropping PONG");
+            }
+        }
+        PingPolicy::Lenient => {
+            if inner
+                .pending_pong
+                .lock()
+                .await
+                .replace(frame.payload.clone())
+                .is_some()
+            {
+                tracing::trace!("pong already queued, coalescing ping");
+                return;
+            }
+            // the writer fills in the pending pong
+            if let Err(TrySendError::Full(_)) = sender.try_ctrl(Vec::new()) {
+                // let the next ping queue its own marker
+                tracing::debug!("control queue full, dropping PONG");
+                inner.pending_pong.lock().await.take();
+            }
+        }
+    }
 }
 
 // Try to parse payload as nonce and check it matches,
 // otherwise if latency exceeds u16::MAX ms, we close the connection
 // else its unsolicited and we ignore
-async fn handle_pong<R: RolePolicy>(frame: &DecodedFrame, sender: &WsSender, inner: &Arc<Inner>) {
+async fn handle_pong<R: RolePolicy>(
+    frame: &DecodedFrame,
+    sender: &WsSender,
+    inner: &Arc<Inner>,
+    policy: PingPolicy,
+) -> Option<()> {
     tracing::debug!("received PONG");
     if let Ok(bytes) = (*frame.payload).try_into() {
-        match inner.ping_stats.lock().await.on_pong(bytes) {
-            Ok(latency) => {
-                let _ = sender.event(Event::Pong(latency)).await;
+        if let Some((sent, tx)) = inner.rtt_waiters.lock().await.remove(&bytes) {
+            let rtt = sent.elapsed();
+            tracing::trace!(rtt = ?rtt, "pong answered ping_rtt");
+            let _ = tx.send(rtt);
+            return Some(());
+        }
+        let res = inner.ping_stats.lock().await.on_pong(bytes);
+        match res {
+            Ok((latency, seq)) => {
+                inner.observe(|| Observation::PongReceived { latency });
+                // the latency is still recorded if the application is behind
+                match sender.try_event(Event::Pong { latency, seq }) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(_)) => {
+                        tracing::debug!("event queue full, dropping pong event");
+                    }
+                    Err(TrySendError::Closed(_)) => {
+                        receiver_dropped::<R>(inner, sender).await;
+                        return None;
+                    }
+                }
             }
             Err(PongError::Late(latency)) => {
                 tracing::warn!(latency = latency, "late pong");
@@ -63,26 +186,39 @@ async fn handle_pong<R: RolePolicy>(frame: &DecodedFrame, sender: &WsSender, inn
                     .close(control::close::<R>(CloseReason::Policy, "ping timeout"))
                     .await;
             }
-            Err(PongError::Nonce(expected)) => {
-                tracing::warn!(
-                    got = ?bytes,
-                    expected = ?expected,
-                    "mismatched pong nonce"
-                );
+            Err(PongError::Unsolicited) if policy == PingPolicy::Lenient => {
+                tracing::trace!("ignoring unsolicited pong");
+            }
+            Err(PongError::Unsolicited) => {
+                tracing::warn!(got = ?bytes, "pong does not match any ping in flight");
             }
         }
     }
+    Some(())
 }
 
 // If closing, shutdown; otherwise, reply with close frame
-async fn handle_close<R: RolePolicy>(frame: &DecodedFrame, inner: &Arc<Inner>, sender: &WsSender) {
+async fn handle_close<R: RolePolicy>(
+    frame: &DecodedFrame,
+    inner: &Arc<Inner>,
+    sender: &WsSender,
+    policy: ClosePolicy,
+) {
     // Here we parse the close reason in order to give the appropriate response.
-    // If empty, treat as normal. otherwise we validate close payload
-    let code = if frame.payload.is_empty() {
+    // If empty, treat as normal. The decoder has already failed payloads of one byte
+    // or with a code which may not be sent, so a code is always present
+    let (code, raw) = if frame.payload.is_empty() {
         tracing::info!("recieved empty Close frame");
-        CloseReason::Normal
+        *inner.close_info.lock().await = Some(CloseInfo::new(CloseReason::NoneGiven as u16, ""));
+        (CloseReason::Normal, CloseReason::NoneGiven as u16)
     } else {
         let Ok(text) = str::from_utf8(&frame.payload[2..]) else {
+            // still a protocol error, but show operators what the peer tried to say
+            tracing::warn!(
+                code = u16::from_be_bytes([frame.payload[0], frame.payload[1]]),
+                text = %String::from_utf8_lossy(&frame.payload[2..]),
+                "received Close frame with invalid UTF-8 reason"
+            );
             let _ = sender
                 .close(control::close::<R>(
                     CloseReason::ProtoError,
@@ -91,25 +227,36 @@ async fn handle_close<R: RolePolicy>(frame: &DecodedFrame, inner: &Arc<Inner>, s
                 .await;
             return;
         };
-        let code = CloseReason::from([frame.payload[0], frame.payload[1]]);
+        let bytes = [frame.payload[0], frame.payload[1]];
+        let raw = u16::from_be_bytes(bytes);
+        *inner.close_info.lock().await = Some(CloseInfo::new(raw, text));
+        let code = CloseReason::from(bytes);
         tracing::info!(reason=?code, text=text, "recieved Close frame");
-        code
+        (code, raw)
     };
 
-    let reason = match code {
+    let reply = match code {
         // codes that should never touch the wire
         CloseReason::Rsv | CloseReason::NoneGiven | CloseReason::Abnormal | CloseReason::Tls => {
-            CloseReason::ProtoError
+            CloseReason::ProtoError as u16
         }
-        // we dont echo any codes back, jsut reply with normal
-        _ => CloseReason::Normal,
+        _ => match policy {
+            ClosePolicy::AlwaysNormal => CloseReason::Normal as u16,
+            ClosePolicy::EchoPeerCode => raw,
+            ClosePolicy::Map(f) => f(raw),
+        },
+    };
+    let reply = if control::sendable(reply) {
+        reply
+    } else {
+        CloseReason::Normal as u16
     };
 
     // if not already closing try to send close frame, log err
     if !inner.closing.swap(true, Ordering::AcqRel) {
-        tracing::trace!(reason=?reason, "sending Close frame");
+        tracing::trace!(code = reply, "sending Close frame");
         if sender
-            .close(control::close::<R>(reason, "peer closed"))
+            .close(control::close_code::<R>(reply, "peer closed"))
             .await
             .is_err()
         {
@@ -121,22 +268,25 @@ async fn handle_close<R: RolePolicy>(frame: &DecodedFrame, inner: &Arc<Inner>, s
 // Build message out of frames
 async fn handle_data<R: RolePolicy>(
     frame: &DecodedFrame,
-    partial_msg: &mut Option<PartialMessage>,
+    inner: &Arc<Inner>,
+    state: &mut ReadState,
     sender: &WsSender,
     inflater: &mut Option<DeflateDecoder<Vec<u8>>>,
     use_context: bool,
+    config: &WebSocketConfig,
 ) -> Option<()> {
     // TODO: Leniency
     // allow overwriting partial messages
     // if we get a new TEXT or BINARY
+    let partial_msg = &mut state.partial;
     tracing::trace!(
         partial = partial_msg.is_some(),
         opcode = ?frame.opcode,
         "handling message"
     );
     let partial = match (partial_msg.as_mut(), frame.opcode) {
-        (None, Opcode::Text) => partial_msg.insert(PartialMessage::text()),
-        (None, Opcode::Bin) => partial_msg.insert(PartialMessage::binary()),
+        (None, Opcode::Text) => partial_msg.insert(PartialMessage::text(frame.compressed)),
+        (None, Opcode::Bin) => partial_msg.insert(PartialMessage::binary(frame.compressed)),
         // CONT frames must NEVER set RSV1
         (Some(p), Opcode::Cont) if !frame.compressed => p,
         _ => {
@@ -154,11 +304,10 @@ async fn handle_data<R: RolePolicy>(
     };
 
     if partial.len() + frame.payload.len() > MAX_MESSAGE_SIZE {
+        // tell the peer what to stay under
+        let text = format!("Message exceeded maximum size of {MAX_MESSAGE_SIZE} bytes");
         let _ = sender
-            .close(control::close::<R>(
-                CloseReason::TooBig,
-                "Message exceeded maximum size",
-            ))
+            .close(control::close::<R>(CloseReason::TooBig, &text))
             .await;
         return None;
     }
@@ -168,38 +317,228 @@ async fn handle_data<R: RolePolicy>(
         added = frame.payload.len(),
         "message fragment appended"
     );
-    partial.push_bytes(&frame.payload);
+    if let Err(e) = partial.push_bytes(&frame.payload) {
+        return message_failed::<R>(sender, e).await;
+    }
 
     if frame.is_fin {
-        match partial_msg
-            .take()
-            .unwrap()
-            .into_message(inflater, use_context)
-        {
+        let partial = partial_msg.take().unwrap();
+        // with context takeover the dictionary joins the history once, otherwise it
+        // starts every message
+        let dictionary = if inflater.is_some() && partial.compressed {
+            let mut dictionary = inner
+                .inflate_dictionary
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner);
+            if use_context {
+                dictionary.take()
+            } else {
+                dictionary.clone()
+            }
+        } else {
+            None
+        };
+        match partial.into_message(
+            inflater,
+            use_context,
+            dictionary.as_deref(),
+            config.inflate_ratio_warning,
+            config.message_metadata,
+        ) {
             Ok(msg) => {
                 tracing::trace!(
                     opcode = ?frame.opcode,
                     total_len = msg.len(),
                     "message assembly complete"
                 );
-                let _ = sender.event(msg).await;
+                inner.observe(|| Observation::MessageReceived { len: msg.len() });
+                match sender.try_event(msg) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(msg)) => {
+                        tracing::debug!("event queue full, holding message");
+                        state.stalled = Some(msg);
+                    }
+                    Err(TrySendError::Closed(_)) => {
+                        receiver_dropped::<R>(inner, sender).await;
+                        return None;
+                    }
+                }
             }
-            Err(MessageError::Utf8) => {
-                let _ = sender
-                    .close(control::close::<R>(CloseReason::DataError, "Invalid UTF-8"))
-                    .await;
-                return None;
+            Err(e) => return message_failed::<R>(sender, e).await,
+        }
+    }
+    Some(())
+}
+
+// Close the connection over a message which cannot be delivered
+async fn message_failed<R: RolePolicy>(sender: &WsSender, e: MessageError) -> Option<()> {
+    let (reason, text) = match e {
+        MessageError::Utf8 => (CloseReason::DataError, "Invalid UTF-8"),
+        MessageError::Deflate => (CloseReason::ProtoError, "bad deflate stream"),
+    };
+    let _ = sender.close(control::close::<R>(reason, text)).await;
+    None
+}
+
+// Enter the closing state and close the connection with the given reason
+pub(super) async fn fail<R: RolePolicy>(
+    inner: &Arc<Inner>,
+    sender: &WsSender,
+    reason: CloseReason,
+    text: &str,
+) {
+    inner.closing.store(true, Ordering::Release);
+    let _ = sender.close(control::close::<R>(reason, text)).await;
+}
+
+// The application dropped its event receiver so nobody is listening anymore,
+// close the connection and stop reading
+pub(super) async fn receiver_dropped<R: RolePolicy>(inner: &Arc<Inner>, sender: &WsSender) {
+    tracing::debug!("event receiver dropped, closing connection");
+    fail::<R>(inner, sender, CloseReason::GoingAway, "").await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{WebSocketClient, role::Server, ws::WebSocket};
+
+    #[tokio::test]
+    async fn invalid_utf8_fails_first_fragment() {
+        use std::time::Duration;
+
+        use tokio::io::AsyncWriteExt;
+
+        let (mut client, mut server) = WebSocketClient::pair();
+        let mut writer = client.text_writer();
+        writer.write_all(b"ok \xFF").await.unwrap();
+
+        // rejected without waiting for the rest of the message
+        let event = tokio::time::timeout(Duration::from_secs(1), server.recv()).await;
+        assert!(matches!(event, Ok(Some(Event::Closed(_)))));
+        drop(writer);
+        assert!(matches!(client.recv().await, Some(Event::Closed(info)) if info.code == 1007));
+    }
+
+    #[tokio::test]
+    async fn lenient_pong_sent_once_control_queue_has_room() {
+        use std::time::Duration;
+
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        use crate::{
+            PingPolicy,
+            frames::{FrameDecoder, FrameState, Opcode, control},
+        };
+
+        // nobody reads the client end yet, so the server's writer stalls
+        let (mut client, server) = tokio::io::duplex(64);
+        let config = WebSocketConfig::default()
+            .ping_policy(PingPolicy::Lenient)
+            .max_queued_control_frames(1)
+            .ping_interval(None);
+        let mut server = WebSocket::<Server>::over_stream(server, None, &config);
+        // the writer blocks on the first pong, the second fills the control queue
+        server.pong(&[1; 125]).await.unwrap();
+        server.pong(&[2; 125]).await.unwrap();
+
+        // no room for the marker of either ping
+        for payload in [b"lost", b"sync"] {
+            let ping = control::ping::<crate::role::Client>(payload);
+            client.write_all(&ping).await.unwrap();
+            assert!(matches!(server.recv().await, Some(Event::Ping(p)) if p[..] == payload[..]));
+        }
+
+        let mut decoder = FrameDecoder::<crate::role::Client>::new(false);
+        let mut buf = [0; 1024];
+        let mut pongs = 0;
+        let answered = async {
+            loop {
+                let n = client.read(&mut buf).await.unwrap();
+                assert!(n > 0, "pipe closed before the pong");
+                decoder.push_bytes(&buf[..n]);
+                while let Ok(Some(FrameState::Complete(frame))) = decoder.next_frame() {
+                    if frame.opcode != Opcode::Pong {
+                        continue;
+                    }
+                    if frame.payload[..] == *b"after" {
+                        return;
+                    }
+                    pongs += 1;
+                    // both queued pongs are written, so the control queue has room
+                    if pongs == 2 {
+                        let ping = control::ping::<crate::role::Client>(b"after");
+                        client.write_all(&ping).await.unwrap();
+                    }
+                }
             }
-            Err(MessageError::Deflate) => {
-                let _ = sender
-                    .close(control::close::<R>(
-                        CloseReason::ProtoError,
-                        "bad deflate stream",
-                    ))
-                    .await;
-                return None;
+        };
+        tokio::time::timeout(Duration::from_secs(1), answered)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn ping_answered_manually() {
+        use std::time::Duration;
+
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let mut client = WebSocketClient::over_stream(client, None, &WebSocketConfig::default());
+        let mut server = WebSocket::<Server>::over_stream(
+            server,
+            None,
+            &WebSocketConfig::default().auto_pong(false),
+        );
+
+        client.ping().await.unwrap();
+        let Some(Event::Ping(payload)) = server.recv().await else {
+            panic!("expected a ping event");
+        };
+        let tick = Duration::from_millis(100);
+        assert!(tokio::time::timeout(tick, client.recv()).await.is_err());
+
+        server.pong(&payload).await.unwrap();
+        let event = tokio::time::timeout(tick, client.recv()).await;
+        assert!(matches!(event, Ok(Some(Event::Pong { seq: 0, .. }))));
+    }
+
+    #[tokio::test]
+    async fn message_metadata_counts_fragments() {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let config = WebSocketConfig::default().max_send_frame_size(16 * 1024);
+        let mut client = WebSocketClient::over_stream(client, None, &config);
+        let config = WebSocketConfig::default().message_metadata(true);
+        let mut server = WebSocket::<Server>::over_stream(server, None, &config);
+
+        client.send_text(&"a".repeat(40_000)).await.unwrap();
+        match server.recv().await {
+            Some(Event::Message {
+                data,
+                text,
+                compressed,
+                fragments,
+            }) => {
+                assert_eq!(data.len(), 40_000);
+                assert!(text && !compressed);
+                assert_eq!(fragments, 3);
             }
+            e => panic!("unexpected event {e:?}"),
         }
     }
-    Some(())
+
+    #[tokio::test]
+    async fn close_code_echoed() {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let config = WebSocketConfig::default();
+        let mut client = WebSocketClient::over_stream(client, None, &config);
+        let config = config.close_policy(crate::ClosePolicy::EchoPeerCode);
+        let mut server = WebSocket::<Server>::over_stream(server, None, &config);
+
+        client.close_reason(CloseReason::GoingAway, "bye").await;
+        match client.recv().await {
+            Some(Event::Closed(info)) => assert_eq!(info.code, 1001),
+            e => panic!("unexpected event {e:?}"),
+        }
+        assert!(matches!(server.recv().await, Some(Event::Closed(info)) if info.code == 1001));
+    }
 }
@@ -0,0 +1,189 @@
+use std::{
+    fmt,
+    sync::{Arc, atomic::Ordering},
+};
+
+use bytes::Bytes;
+use tokio::sync::{Mutex, mpsc::Sender};
+
+use super::{
+    Inner, Observation, WebSocket,
+    websocket::{Closing, SendState, queue_data},
+};
+use crate::{
+    error::{CloseReason, SendError},
+    frames::{DataChannel, Opcode, Outgoing, control},
+    role::{Client, RolePolicy, Server},
+};
+
+/// A cloneable handle for sending to a connection from other tasks, e.g. to broadcast to
+/// every client of a server. Created with [`WebSocket::handle`], or taken from the
+/// [`ConnectionContext`](crate::ConnectionContext) given to a
+/// [`MessageHandler`](crate::MessageHandler).
+///
+/// Messages are queued for the writer as [`WebSocket::send_text`] queues them, so they
+/// are compressed like any other and sent whole, never interleaved with the frames of
+/// another message. This works however the connection is received from.
+#[derive(Clone)]
+pub struct WsHandle {
+    data_tx: Sender<Outgoing>,
+    close_tx: Sender<Closing>,
+    inner: Arc<Inner>,
+    send_state: Arc<Mutex<SendState>>,
+    send_context: bool,
+    // the handle is not generic, so the role which decides masking is kept here
+    client: bool,
+}
+
+impl WsHandle {
+    /// Sends a text message.
+    /// # Errors
+    /// Fails as [`WebSocket::send_text`] does.
+    pub async fn send_text(&self, text: impl Into<String>) -> Result<(), SendError> {
+        self.send(text.into().as_bytes(), Opcode::Text).await
+    }
+
+    /// Sends a binary message.
+    /// # Errors
+    /// Fails as [`WebSocket::send_bytes`] does.
+    pub async fn send_bytes(&self, bytes: impl Into<Bytes>) -> Result<(), SendError> {
+        self.send(&bytes.into(), Opcode::Bin).await
+    }
+
+    /// Closes the connection once the messages sent before it are written, see
+    /// [`WebSocket::close`].
+    pub async fn close(&self) {
+        if !self.inner.closing.swap(true, Ordering::AcqRel) {
+            let close = if self.client {
+                control::close::<Client>(CloseReason::Normal, "")
+            } else {
+                control::close::<Server>(CloseReason::Normal, "")
+            };
+            let _ = self.close_tx.send(close.into()).await;
+        }
+    }
+
+    async fn send(&self, bytes: &[u8], opcode: Opcode) -> Result<(), SendError> {
+        self.inner.check_send(bytes.len())?;
+        let chan = DataChannel {
+            tx: &self.data_tx,
+            buffered: &self.inner.buffered_bytes,
+            window: self.inner.send_window.as_ref(),
+        };
+        let state = &self.send_state;
+        let context = self.send_context;
+        let res = if self.client {
+            queue_data::<Client>(chan, state, context, bytes, opcode, true).await
+        } else {
+            queue_data::<Server>(chan, state, context, bytes, opcode, true).await
+        };
+        res.map_err(|_| self.inner.send_error())?;
+        let len = bytes.len();
+        self.inner.observe(|| Observation::MessageSent { len });
+        Ok(())
+    }
+}
+
+impl fmt::Debug for WsHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WsHandle").finish_non_exhaustive()
+    }
+}
+
+impl<R: RolePolicy> WebSocket<R> {
+    /// Returns a handle for sending to this connection from other tasks, see [`WsHandle`].
+    #[must_use]
+    pub fn handle(&self) -> WsHandle {
+        WsHandle {
+            data_tx: self.data_tx.clone(),
+            close_tx: self.close_tx.clone(),
+            inner: self.inner.clone(),
+            send_state: self.send_state.clone(),
+            send_context: self.send_context,
+            client: R::CLIENT,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ConnectionContext, DeflateParams, Event, Message, MessageHandler, Text, WebSocketClient,
+        WebSocketConfig,
+    };
+
+    struct Silent;
+    #[async_trait::async_trait]
+    impl MessageHandler for Silent {
+        async fn on_text(&self, _conn: &ConnectionContext, _s: Text) -> Option<Message> { None }
+        async fn on_binary(&self, _conn: &ConnectionContext, _b: Bytes) -> Option<Message> { None }
+        async fn on_close(&self, _conn: &ConnectionContext) {}
+        async fn on_error(&self, _conn: &ConnectionContext) {}
+        async fn on_pong(&self, _conn: &ConnectionContext, _latency: u16) {}
+    }
+
+    #[tokio::test]
+    async fn sends_from_outside_recv_loop() {
+        let (mut client, server) = WebSocketClient::pair();
+        let handle = server.handle();
+        let running = tokio::spawn(async move {
+            let mut server = server;
+            server.recv_loop(Arc::new(Silent)).await
+        });
+
+        handle.send_text("pushed").await.unwrap();
+        handle.clone().send_bytes(vec![1, 2]).await.unwrap();
+        assert!(matches!(client.recv().await, Some(Event::Text(t)) if t.as_str() == "pushed"));
+        assert!(matches!(client.recv().await, Some(Event::Binary(b)) if b[..] == [1, 2]));
+
+        handle.close().await;
+        assert!(matches!(client.recv().await, Some(Event::Closed(_))));
+        running.await.unwrap();
+        assert!(handle.send_text("late").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn sends_without_recv_loop() {
+        let (mut client, server) = WebSocketClient::pair();
+        let handle = server.handle();
+
+        // more than any queue between the handle and the writer would hold
+        for i in 0..200 {
+            handle.send_text(i.to_string()).await.unwrap();
+        }
+        for i in 0..200 {
+            let expected = i.to_string();
+            assert!(matches!(client.recv().await, Some(Event::Text(t)) if t.as_str() == expected));
+        }
+    }
+
+    #[tokio::test]
+    async fn fragmented_messages_never_interleave() {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let config = WebSocketConfig::default().max_send_frame_size(1);
+        let deflate = Some(DeflateParams::symmetric(true));
+        let mut client = WebSocketClient::over_stream(client, deflate, &config);
+        let mut server = WebSocket::<Server>::over_stream(server, deflate, &config);
+        let handle = server.handle();
+
+        let pushing = tokio::spawn(async move {
+            for _ in 0..50 {
+                handle.send_text("h".repeat(500)).await.unwrap();
+            }
+        });
+        for _ in 0..50 {
+            server.send_text(&"s".repeat(500)).await.unwrap();
+        }
+        pushing.await.unwrap();
+
+        let (mut ours, mut theirs) = (0, 0);
+        while ours + theirs < 100 {
+            match client.recv().await {
+                Some(Event::Text(t)) if t.as_str() == "h".repeat(500) => ours += 1,
+                Some(Event::Text(t)) if t.as_str() == "s".repeat(500) => theirs += 1,
+                e => panic!("unexpected event {e:?}"),
+            }
+        }
+    }
+}
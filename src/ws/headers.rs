@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
+
+use crate::UpgradeError;
+
+/// Reads handshake headers up to the blank line which ends them, failing with
+/// [`UpgradeError::HeadersTooLarge`] past `max_bytes` in total or `max_headers` lines.
+/// The raw lines are appended to `raw`.
+///
+/// Names are lowercased. Repeated headers are joined with `", "`, which HTTP defines as
+/// equivalent, and obsolete line folding is unfolded, so list values such as
+/// `Sec-WebSocket-Protocol` can be read with [`header_values`].
+pub(crate) async fn read_headers<S: AsyncRead + Unpin>(
+    reader: &mut BufReader<S>,
+    max_bytes: usize,
+    max_headers: usize,
+    raw: &mut Vec<u8>,
+) -> Result<HashMap<String, String>, UpgradeError> {
+    let mut headers: HashMap<String, String> = HashMap::new();
+    let mut last = None;
+    let mut remaining = max_bytes;
+    for _ in 0..=max_headers {
+        let mut line = String::new();
+        let n = (&mut *reader)
+            .take(remaining as u64)
+            .read_line(&mut line)
+            .await
+            .map_err(|_| UpgradeError::Read)?;
+        raw.extend_from_slice(line.as_bytes());
+        if !line.ends_with('\n') {
+            if n == remaining {
+                tracing::warn!(
+                    limit = max_bytes,
+                    "handshake headers exceeded maximum length"
+                );
+                return Err(UpgradeError::HeadersTooLarge);
+            }
+            // the stream ended before the headers did
+            return Err(UpgradeError::Read);
+        }
+        remaining -= n;
+
+        let line = line.trim_end(); // remove \r\n
+        if line.is_empty() {
+            return Ok(headers);
+        } // end of headers
+
+        // a line starting with whitespace continues the previous header
+        if line.starts_with([' ', '\t']) {
+            if let Some(value) = last.as_ref().and_then(|name| headers.get_mut(name)) {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+            continue;
+        }
+
+        if let Some((name, value)) = line.split_once(':') {
+            let name = name.trim().to_lowercase();
+            let value = value.trim();
+            headers
+                .entry(name.clone())
+                .and_modify(|v| {
+                    v.push_str(", ");
+                    v.push_str(value);
+                })
+                .or_insert_with(|| value.to_string());
+            last = Some(name);
+        }
+    }
+    tracing::warn!(limit = max_headers, "too many handshake headers");
+    Err(UpgradeError::HeadersTooLarge)
+}
+
+/// Returns the comma separated values of a header, e.g. each offered subprotocol.
+pub(crate) fn header_values<'a>(
+    headers: &'a HashMap<String, String>,
+    field: &str,
+) -> impl Iterator<Item = &'a str> {
+    headers
+        .get(field)
+        .into_iter()
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn parse(raw: &str) -> HashMap<String, String> {
+        read_headers(
+            &mut BufReader::new(raw.as_bytes()),
+            1024,
+            8,
+            &mut Vec::new(),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn joins_repeated_headers() {
+        let headers = parse(
+            "Sec-WebSocket-Protocol: a\r\n\
+             Host: example.com\r\n\
+             sec-websocket-protocol: b, c\r\n\r\n",
+        )
+        .await;
+        let protocols: Vec<_> = header_values(&headers, "sec-websocket-protocol").collect();
+        assert_eq!(protocols, ["a", "b", "c"]);
+        assert_eq!(headers["host"], "example.com");
+    }
+
+    #[tokio::test]
+    async fn rejects_too_many_or_too_long_headers() {
+        let raw = "A: 1\r\nB: 2\r\nC: 3\r\n\r\n";
+        let mut lines = Vec::new();
+        let res = read_headers(&mut BufReader::new(raw.as_bytes()), 1024, 2, &mut lines).await;
+        assert!(matches!(res, Err(UpgradeError::HeadersTooLarge)));
+        let res = read_headers(&mut BufReader::new(raw.as_bytes()), 10, 8, &mut lines).await;
+        assert!(matches!(res, Err(UpgradeError::HeadersTooLarge)));
+        let res = read_headers(&mut BufReader::new(raw.as_bytes()), 1024, 3, &mut lines).await;
+        assert!(res.is_ok());
+    }
+
+    #[tokio::test]
+    async fn unfolds_continuation_lines() {
+        let headers = parse("Connection: keep-alive,\r\n  Upgrade\r\n\r\n").await;
+        let values: Vec<_> = header_values(&headers, "connection").collect();
+        assert_eq!(values, ["keep-alive", "Upgrade"]);
+    }
+}
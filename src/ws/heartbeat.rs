@@ -0,0 +1,115 @@
+use std::{sync::atomic::Ordering, time::Duration};
+
+use tokio::time::Instant;
+
+use super::{Event, Message, WebSocket};
+use crate::{error::CloseReason, role::RolePolicy};
+
+/// An application-level heartbeat sent as a data message.
+///
+/// Some protocols define their own keepalive rather than relying on WebSocket pings,
+/// e.g. Socket.IO's `2`/`3` packets. Pass a `Heartbeat` to
+/// [`recv_heartbeat`](WebSocket::recv_heartbeat) to send it on an interval and close the
+/// connection if the peer does not reply in time.
+pub struct Heartbeat {
+    message: Message,
+    is_reply: Box<dyn Fn(&Event) -> bool + Send + Sync>,
+    interval: Duration,
+    timeout: Duration,
+    next_send: Instant,
+    sent_at: Option<Instant>,
+}
+
+impl Heartbeat {
+    /// Creates a heartbeat which sends `message` every `interval`, expecting an [`Event`]
+    /// matching `is_reply` within `timeout` of each send.
+    pub fn new<F>(message: Message, interval: Duration, timeout: Duration, is_reply: F) -> Self
+    where
+        F: Fn(&Event) -> bool + Send + Sync + 'static,
+    {
+        Self {
+            message,
+            is_reply: Box::new(is_reply),
+            interval,
+            timeout,
+            next_send: Instant::now() + interval,
+            sent_at: None,
+        }
+    }
+
+    // when we next need to act, either to send or to give up waiting for a reply
+    fn deadline(&self) -> Instant {
+        match self.sent_at {
+            Some(sent) => sent + self.timeout,
+            None => self.next_send,
+        }
+    }
+}
+
+impl<R: RolePolicy> WebSocket<R> {
+    /// Wait for and return the next [`Event`], while driving a [`Heartbeat`].
+    ///
+    /// The heartbeat message is sent when due and replies to it are consumed rather than
+    /// returned. If a reply does not arrive in time, the connection is closed with a policy
+    /// violation and the resulting [`Event::Closed`] is returned as usual. No heartbeats
+    /// are sent once the connection is closing.
+    pub async fn recv_heartbeat(&mut self, heartbeat: &mut Heartbeat) -> Option<Event> {
+        loop {
+            let closing = self.inner.closing.load(Ordering::Acquire);
+            tokio::select! {
+                event = self.next_event() => match event {
+                    Some(e) if heartbeat.sent_at.is_some() && (heartbeat.is_reply)(&e) => {
+                        tracing::trace!("heartbeat reply received");
+                        heartbeat.sent_at = None;
+                    }
+                    event => return event,
+                },
+                () = tokio::time::sleep_until(heartbeat.deadline()), if !closing => {
+                    if heartbeat.sent_at.take().is_some() {
+                        tracing::warn!("heartbeat timed out");
+                        self.close_reason(CloseReason::Policy, "heartbeat timed out").await;
+                        continue;
+                    }
+
+                    tracing::trace!("sending heartbeat");
+                    let now = Instant::now();
+                    heartbeat.sent_at = Some(now);
+                    heartbeat.next_send = now + heartbeat.interval;
+                    let res = match &heartbeat.message {
+                        Message::Text(s) => self.send_text(s).await,
+                        Message::Binary(b) => self.send_bytes(b).await,
+                        Message::Ping(b) => self.send_message(Message::Ping(b.clone())).await,
+                        Message::Pong(b) => self.send_message(Message::Pong(b.clone())).await,
+                    };
+                    if let Err(e) = res {
+                        tracing::warn!(e = ?e, "failed to send heartbeat");
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::WebSocketClient;
+
+    #[tokio::test]
+    async fn unanswered_heartbeat_closes() {
+        let (mut client, mut server) = WebSocketClient::pair();
+        let every = Duration::from_millis(20);
+        let is_reply = |e: &Event| matches!(e, Event::Text(t) if t.as_str() == "3");
+        let mut heartbeat = Heartbeat::new(Message::Text("2".into()), every, every * 10, is_reply);
+        let driving = tokio::spawn(async move { client.recv_heartbeat(&mut heartbeat).await });
+
+        for _ in 0..3 {
+            assert!(matches!(server.recv().await, Some(Event::Text(t)) if t.as_str() == "2"));
+            server.send_text("3").await.unwrap();
+        }
+        // the next goes unanswered, and nothing follows the close
+        assert!(matches!(server.recv().await, Some(Event::Text(t)) if t.as_str() == "2"));
+        assert!(matches!(server.recv().await, Some(Event::Closed(info)) if info.code == 1008));
+        assert!(matches!(driving.await.unwrap(), Some(Event::Closed(_))));
+    }
+}
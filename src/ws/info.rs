@@ -0,0 +1,121 @@
+use std::{collections::HashMap, net::SocketAddr};
+
+use super::WsHandle;
+
+/// Details of the opening handshake of a connection.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionInfo {
+    path: String,
+    query: HashMap<String, String>,
+    // the peer's half of the handshake, keyed by lowercased name
+    headers: HashMap<String, String>,
+}
+
+impl ConnectionInfo {
+    // parse a request target such as "/ws?room=42&token=abc"
+    pub(crate) fn from_target(target: &str) -> Self {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        Self {
+            path: path.to_string(),
+            // last value wins for duplicate keys
+            query: url::form_urlencoded::parse(query.as_bytes())
+                .into_owned()
+                .collect(),
+            headers: HashMap::new(),
+        }
+    }
+
+    // keep the headers the peer sent in the handshake
+    pub(crate) fn with_headers(mut self, headers: HashMap<String, String>) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// Returns the request path without the query string, e.g. `/chat`.
+    #[must_use]
+    pub fn path(&self) -> &str { &self.path }
+
+    /// Returns the URL-decoded value of the query parameter `key`.
+    /// If the key was repeated, the last value is returned.
+    #[must_use]
+    pub fn query_param(&self, key: &str) -> Option<&str> { self.query.get(key).map(String::as_str) }
+
+    /// Returns all URL-decoded query parameters.
+    #[must_use]
+    pub fn query(&self) -> &HashMap<String, String> { &self.query }
+
+    /// Returns the headers the peer sent in the opening handshake, keyed by lowercased
+    /// name: the request's on a server, the response's on a client. Repeated headers
+    /// are joined with commas.
+    #[must_use]
+    pub fn headers(&self) -> &HashMap<String, String> { &self.headers }
+
+    /// Returns the value of the handshake header `name`, which is matched ignoring case,
+    /// see [`headers`](ConnectionInfo::headers).
+    #[must_use]
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get(&name.to_ascii_lowercase())
+            .map(String::as_str)
+    }
+}
+
+/// Details of an open connection, passed to each [`MessageHandler`](crate::MessageHandler)
+/// callback so that a handler shared by many connections can tell them apart.
+#[derive(Debug, Clone)]
+pub struct ConnectionContext {
+    pub(crate) peer_addr: SocketAddr,
+    pub(crate) local_addr: SocketAddr,
+    pub(crate) protocol: Option<String>,
+    pub(crate) compressed: bool,
+    pub(crate) info: ConnectionInfo,
+    pub(crate) handle: WsHandle,
+}
+
+impl ConnectionContext {
+    /// Returns the address of the peer.
+    #[must_use]
+    pub fn peer_addr(&self) -> SocketAddr { self.peer_addr }
+
+    /// Returns the local address of the connection.
+    #[must_use]
+    pub fn local_addr(&self) -> SocketAddr { self.local_addr }
+
+    /// Returns the negotiated subprotocol, if any.
+    #[must_use]
+    pub fn protocol(&self) -> Option<&str> { self.protocol.as_deref() }
+
+    /// Returns whether messages are compressed with permessage-deflate.
+    #[must_use]
+    pub fn is_compressed(&self) -> bool { self.compressed }
+
+    /// Returns the request path and query parameters.
+    #[must_use]
+    pub fn info(&self) -> &ConnectionInfo { &self.info }
+
+    /// Returns a handle for sending to this connection from other tasks, e.g. to keep in a
+    /// map of connected clients from [`on_open`](crate::MessageHandler::on_open).
+    #[must_use]
+    pub fn handle(&self) -> WsHandle { self.handle.clone() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_path_and_query() {
+        let info = ConnectionInfo::from_target("/ws?room=42&name=a%20b+c&room=7");
+        assert_eq!(info.path(), "/ws");
+        assert_eq!(info.query_param("room"), Some("7"));
+        assert_eq!(info.query_param("name"), Some("a b c"));
+        assert_eq!(info.query_param("token"), None);
+    }
+
+    #[test]
+    fn parses_path_without_query() {
+        let info = ConnectionInfo::from_target("/chat");
+        assert_eq!(info.path(), "/chat");
+        assert!(info.query().is_empty());
+    }
+}
@@ -1,8 +1,30 @@
+mod boxed;
+mod capture;
+mod concurrent;
 mod event;
 mod frame_handler;
+mod handle;
+mod headers;
+mod heartbeat;
+mod info;
+mod observer;
+mod split;
 mod websocket;
+mod writer;
 
-pub(crate) use event::PartialMessage;
-pub use event::{Event, Text};
+pub use boxed::BoxedWebSocket;
+pub use capture::HandshakeCapture;
+pub use event::{CloseInfo, Event, Text};
+pub(crate) use event::{DebugPayload, PartialMessage};
+pub use handle::WsHandle;
+pub(crate) use headers::{header_values, read_headers};
+pub use heartbeat::Heartbeat;
+pub use info::{ConnectionContext, ConnectionInfo};
+pub use observer::Observation;
+pub(crate) use observer::Observer;
+pub use split::{WsSink, WsStream};
 pub(crate) use websocket::Inner;
-pub use websocket::{Message, MessageHandler, WebSocket};
+pub use websocket::{
+    CompressionInfo, DeflateParams, Message, MessageHandler, NegotiatedExtensions, WebSocket,
+};
+pub use writer::{BinaryWriter, MessageWriter};
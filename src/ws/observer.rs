@@ -0,0 +1,39 @@
+use std::{fmt, sync::Arc};
+
+use super::CloseInfo;
+
+/// Connection events reported to an observer set with
+/// [`WebSocketConfig::observer`](crate::WebSocketConfig::observer).
+///
+/// This lets applications which do not use `tracing` feed connection activity
+/// into their own logging or metrics.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum Observation {
+    /// A message was queued to be sent, with its payload length before compression.
+    MessageSent { len: usize },
+    /// A complete message was received, with its payload length after decompression.
+    MessageReceived { len: usize },
+    /// A ping was sent to the peer.
+    PingSent,
+    /// A pong answering one of our pings was received, with its latency in milliseconds.
+    PongReceived { latency: u16 },
+    /// The connection has closed.
+    Closed(CloseInfo),
+}
+
+// Shared callback, wrapped so that the config can still derive Debug
+#[derive(Clone)]
+pub(crate) struct Observer(Arc<dyn Fn(&Observation) + Send + Sync>);
+
+impl Observer {
+    pub(crate) fn new<F: Fn(&Observation) + Send + Sync + 'static>(f: F) -> Self {
+        Self(Arc::new(f))
+    }
+
+    pub(crate) fn notify(&self, observation: &Observation) { (self.0)(observation) }
+}
+
+impl fmt::Debug for Observer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { f.write_str("Observer") }
+}
@@ -0,0 +1,384 @@
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    sync::{Arc, atomic::Ordering},
+    task::{Context, Poll, ready},
+};
+
+use futures_core::Stream;
+use futures_sink::Sink;
+use tokio::sync::{
+    mpsc::{Receiver, channel},
+    oneshot,
+};
+use tokio_util::sync::PollSender;
+
+use super::{
+    Inner, Message, Observation, WebSocket,
+    websocket::{Closing, SendLock},
+};
+use crate::{
+    Event,
+    error::{CloseReason, SendError},
+    frames::{Opcode, Outgoing, control, encode},
+    role::RolePolicy,
+};
+
+/// The sending half of a connection, created with [`split`](WebSocket::split).
+///
+/// Besides the async methods, it implements [`Sink<Message>`](Sink) for use with `futures`
+/// combinators. Closing the sink closes the connection, while dropping it only stops
+/// sending, the [`WsStream`] keeps receiving until the connection closes.
+pub struct WsSink<R: RolePolicy> {
+    ws: WebSocket<R>,
+    data: PollSender<Outgoing>,
+    ctrl: PollSender<Vec<u8>>,
+    close: PollSender<Closing>,
+    // frames of the message taken by `start_send` which are not queued yet, and its length
+    pending: VecDeque<Outgoing>,
+    pending_len: usize,
+    pending_ctrl: Option<Vec<u8>>,
+    // held from `poll_ready` until the frames of the message taken are queued
+    lock: SendLock,
+    // waits for the send window to take the first pending frame, done once it has
+    room: Option<Pin<Box<dyn Future<Output = bool> + Send>>>,
+    has_room: bool,
+    flushed: Option<oneshot::Receiver<()>>,
+}
+
+/// The receiving half of a connection, created with [`split`](WebSocket::split).
+///
+/// Besides [`recv`](WsStream::recv), it implements [`Stream<Item = Event>`](Stream).
+pub struct WsStream {
+    events: Receiver<Event>,
+    inner: Arc<Inner>,
+}
+
+impl<R: RolePolicy> WebSocket<R> {
+    /// Splits the connection into halves which send and receive independently, e.g. from
+    /// separate tasks.
+    ///
+    /// The connection stays open until the sink is closed or the peer closes. Dropping
+    /// the stream closes the connection with 1001 (going away) as soon as the next event
+    /// can't be delivered, after which sends on the sink fail, so keep the stream alive
+    /// for as long as the sink is used.
+    #[must_use]
+    pub fn split(mut self) -> (WsSink<R>, WsStream) {
+        // the sink's connection never receives, give it a queue nothing is sent to
+        let (_, closed) = channel(1);
+        let events = std::mem::replace(&mut self.event_rx, closed);
+        let inner = self.inner.clone();
+        self.sink_half = true;
+        let lock = SendLock::new(self.send_state.clone());
+        let sink = WsSink {
+            lock,
+            data: PollSender::new(self.data_tx.clone()),
+            ctrl: PollSender::new(self.ctrl_tx.clone()),
+            close: PollSender::new(self.close_tx.clone()),
+            ws: self,
+            pending: VecDeque::new(),
+            pending_len: 0,
+            pending_ctrl: None,
+            room: None,
+            has_room: false,
+            flushed: None,
+        };
+        (sink, WsStream { events, inner })
+    }
+}
+
+impl<R: RolePolicy> WsSink<R> {
+    /// Sends a message, see [`send_text`](WebSocket::send_text) and
+    /// [`send_bytes`](WebSocket::send_bytes).
+    /// # Errors
+    /// Fails as [`send_text`](WebSocket::send_text) does.
+    pub async fn send(&mut self, message: Message) -> Result<(), SendError> {
+        std::future::poll_fn(|cx| self.poll_queue(cx)).await?;
+        self.ws.send_message(message).await
+    }
+
+    /// Waits until every message sent so far has been flushed, see [`WebSocket::flush`].
+    /// # Errors
+    /// Fails with [`SendError::Closed`] if the connection has been shut down.
+    pub async fn flush(&mut self) -> Result<(), SendError> {
+        std::future::poll_fn(|cx| self.poll_queue(cx)).await?;
+        self.ws.flush().await
+    }
+
+    /// Closes the connection, see [`WebSocket::close`]. The peer's close arrives on the
+    /// [`WsStream`] as [`Event::Closed`].
+    pub async fn close(&mut self) {
+        let _ = std::future::poll_fn(|cx| self.poll_queue(cx)).await;
+        self.ws.close().await;
+    }
+
+    // Queue what `start_send` took, waiting for room as `send_text` does
+    fn poll_queue(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), SendError>> {
+        if self.pending_ctrl.is_some() {
+            ready!(self.ctrl.poll_reserve(cx)).map_err(|_| self.ws.inner.send_error())?;
+            let frame = self.pending_ctrl.take().expect("checked above");
+            let _ = self.ctrl.send_item(frame);
+        }
+        while let Some(frame) = self.pending.front() {
+            let len = frame.len();
+            let inner = &self.ws.inner;
+            if inner.send_window.is_some() && !self.has_room {
+                let room = self.room.get_or_insert_with(|| {
+                    let (inner, tx) = (inner.clone(), self.ws.data_tx.clone());
+                    Box::pin(async move {
+                        let window = inner.send_window.as_ref().expect("checked above");
+                        window.acquire(len, &tx).await
+                    })
+                });
+                let acquired = ready!(room.as_mut().poll(cx));
+                self.room = None;
+                if !acquired {
+                    self.pending.clear();
+                    return Poll::Ready(Err(self.ws.inner.send_error()));
+                }
+                self.has_room = true;
+            }
+            if ready!(self.data.poll_reserve(cx)).is_err() {
+                if let (true, Some(window)) = (self.has_room, &self.ws.inner.send_window) {
+                    window.release(len);
+                }
+                self.has_room = false;
+                self.pending.clear();
+                return Poll::Ready(Err(self.ws.inner.send_error()));
+            }
+            let frame = self.pending.pop_front().expect("checked above");
+            self.has_room = false;
+            self.ws
+                .inner
+                .buffered_bytes
+                .fetch_add(len, Ordering::Relaxed);
+            let _ = self.data.send_item(frame);
+            if self.pending.is_empty() {
+                let len = self.pending_len;
+                self.ws.inner.observe(|| Observation::MessageSent { len });
+            }
+        }
+        // the message is queued whole, others may follow
+        self.lock.unlock();
+        Poll::Ready(Ok(()))
+    }
+}
+
+// nothing is pinned structurally, the role is only a marker
+impl<R: RolePolicy> Unpin for WsSink<R> {}
+
+impl<R: RolePolicy> Sink<Message> for WsSink<R> {
+    type Error = SendError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), SendError>> {
+        let this = self.get_mut();
+        ready!(this.poll_queue(cx))?;
+        // taken now so the next message can be encoded as soon as it is given
+        ready!(this.lock.poll_lock(cx));
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, message: Message) -> Result<(), SendError> {
+        let this = self.get_mut();
+        let ws = &mut this.ws;
+        let (bytes, opcode) = match &message {
+            Message::Text(s) => (s.as_bytes(), Opcode::Text),
+            Message::Binary(b) => (&b[..], Opcode::Bin),
+            Message::Ping(b) | Message::Pong(b) => {
+                this.pending_ctrl = Some(ws.control_frame(&message, b)?);
+                return Ok(());
+            }
+        };
+        ws.inner.check_send(bytes.len())?;
+        let state = this
+            .lock
+            .try_lock()
+            .expect("`poll_ready` must succeed before `start_send`");
+        this.pending_len = bytes.len();
+        this.pending = encode::<R>(
+            bytes,
+            opcode,
+            &mut state.deflater,
+            ws.send_context,
+            state.dictionary.as_deref(),
+            &mut state.encoder,
+        )
+        .into();
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), SendError>> {
+        let this = self.get_mut();
+        ready!(this.poll_queue(cx))?;
+        if this.flushed.is_none() {
+            ready!(this.data.poll_reserve(cx)).map_err(|_| SendError::Closed)?;
+            let (ack_tx, ack_rx) = oneshot::channel();
+            this.ws.inner.queued_flushes.fetch_add(1, Ordering::Relaxed);
+            if this.data.send_item(Outgoing::Flush(ack_tx)).is_err() {
+                this.ws.inner.queued_flushes.fetch_sub(1, Ordering::Relaxed);
+            }
+            this.flushed = Some(ack_rx);
+        }
+        let flushed = this.flushed.as_mut().expect("set above");
+        let res = ready!(Pin::new(flushed).poll(cx)).map_err(|_| SendError::Closed);
+        this.flushed = None;
+        Poll::Ready(res)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), SendError>> {
+        let this = self.get_mut();
+        ready!(this.poll_queue(cx))?;
+        if !this.ws.inner.closing.load(Ordering::Acquire) {
+            // closing already once the close frame goes out, as `close` does
+            ready!(this.close.poll_reserve(cx)).map_err(|_| SendError::Closed)?;
+            if this.ws.inner.closing.swap(true, Ordering::AcqRel) {
+                this.close.abort_send();
+            } else {
+                let _ = this
+                    .close
+                    .send_item(control::close::<R>(CloseReason::Normal, "").into());
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl WsStream {
+    /// Receives the next event, see [`WebSocket::recv`].
+    pub async fn recv(&mut self) -> Option<Event> {
+        std::future::poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    /// Polls for the next event, `None` once the connection has closed, as
+    /// [`Stream::poll_next`] does.
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<Event>> {
+        self.events.poll_recv(cx).map(|event| {
+            let event = event?;
+            self.inner
+                .buffered_bytes
+                .fetch_sub(event.len(), Ordering::Relaxed);
+            Some(event)
+        })
+    }
+}
+
+impl Stream for WsStream {
+    type Item = Event;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Event>> {
+        self.get_mut().poll_recv(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{future::poll_fn, pin::Pin};
+
+    use futures_core::Stream;
+    use futures_sink::Sink;
+
+    use crate::{Event, Message, WebSocket, WebSocketClient, WebSocketConfig, role::Server};
+
+    #[tokio::test]
+    async fn halves_used_from_separate_tasks() {
+        let (client, mut server) = WebSocketClient::pair();
+        let (mut sink, mut stream) = client.split();
+
+        let sending = tokio::spawn(async move {
+            sink.send(Message::Text("hello".into())).await.unwrap();
+            sink.send(Message::Binary(vec![1, 2].into())).await.unwrap();
+            sink
+        });
+        assert!(matches!(server.recv().await, Some(Event::Text(t)) if t.as_str() == "hello"));
+        assert!(matches!(server.recv().await, Some(Event::Binary(b)) if b[..] == [1, 2]));
+
+        server.send_text("back").await.unwrap();
+        assert!(matches!(stream.recv().await, Some(Event::Text(t)) if t.as_str() == "back"));
+
+        let mut sink = sending.await.unwrap();
+        sink.close().await;
+        assert!(matches!(stream.recv().await, Some(Event::Closed(_))));
+    }
+
+    #[tokio::test]
+    async fn sink_and_stream_traits() {
+        let (client, mut server) = WebSocketClient::pair();
+        let (mut sink, mut stream) = client.split();
+
+        for text in ["one", "two"] {
+            poll_fn(|cx| Pin::new(&mut sink).poll_ready(cx))
+                .await
+                .unwrap();
+            Pin::new(&mut sink)
+                .start_send(Message::Text(text.into()))
+                .unwrap();
+        }
+        poll_fn(|cx| Pin::new(&mut sink).poll_flush(cx))
+            .await
+            .unwrap();
+        assert!(matches!(server.recv().await, Some(Event::Text(t)) if t.as_str() == "one"));
+        assert!(matches!(server.recv().await, Some(Event::Text(t)) if t.as_str() == "two"));
+
+        server.send_text("back").await.unwrap();
+        let event = poll_fn(|cx| Pin::new(&mut stream).poll_next(cx)).await;
+        assert!(matches!(event, Some(Event::Text(t)) if t.as_str() == "back"));
+
+        poll_fn(|cx| Pin::new(&mut sink).poll_close(cx))
+            .await
+            .unwrap();
+        assert!(matches!(server.recv().await, Some(Event::Closed(info)) if info.code == 1000));
+        let event = poll_fn(|cx| Pin::new(&mut stream).poll_next(cx)).await;
+        assert!(matches!(event, Some(Event::Closed(_))));
+    }
+
+    #[tokio::test]
+    async fn sink_waits_for_send_window() {
+        let (client, server) = tokio::io::duplex(64);
+        let config = WebSocketConfig::default().send_window(256);
+        let client = WebSocketClient::over_stream(client, None, &config);
+        let mut server = WebSocket::<Server>::over_stream(server, None, &config);
+        let (mut sink, _stream) = client.split();
+
+        // far more than the window holds, each send waits for the writer to catch up
+        let sending = tokio::spawn(async move {
+            for _ in 0..20 {
+                poll_fn(|cx| Pin::new(&mut sink).poll_ready(cx))
+                    .await
+                    .unwrap();
+                Pin::new(&mut sink)
+                    .start_send(Message::Binary(vec![7; 100].into()))
+                    .unwrap();
+            }
+            poll_fn(|cx| Pin::new(&mut sink).poll_flush(cx))
+                .await
+                .unwrap();
+            sink
+        });
+        for _ in 0..20 {
+            assert!(matches!(server.recv().await, Some(Event::Binary(b)) if b[..] == [7; 100]));
+        }
+        let sink = sending.await.unwrap();
+        assert_eq!(sink.ws.buffered_bytes(), 0);
+    }
+
+    #[tokio::test]
+    async fn dropped_sink_leaves_stream_receiving() {
+        let (client, mut server) = WebSocketClient::pair();
+        let (sink, mut stream) = client.split();
+        drop(sink);
+
+        server.send_text("still heard").await.unwrap();
+        assert!(matches!(stream.recv().await, Some(Event::Text(t)) if t.as_str() == "still heard"));
+    }
+
+    #[tokio::test]
+    async fn dropped_stream_closes_connection() {
+        let (client, mut server) = WebSocketClient::pair();
+        let (mut sink, stream) = client.split();
+        drop(stream);
+
+        server.send_text("unheard").await.unwrap();
+        assert!(matches!(server.recv().await, Some(Event::Closed(info)) if info.code == 1001));
+        assert!(sink.send(Message::Text("late".into())).await.is_err());
+    }
+}
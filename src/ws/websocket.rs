@@ -1,11 +1,15 @@
 use std::{
     collections::HashMap,
+    fmt,
+    io::IoSlice,
     marker::PhantomData,
     net::SocketAddr,
+    pin::Pin,
     sync::{
-        Arc,
-        atomic::{AtomicBool, Ordering},
+        Arc, OnceLock,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
     },
+    task::{Context, Poll, ready},
     time::{Duration, Instant},
 };
 
@@ -15,48 +19,295 @@ use flate2::{
     write::{DeflateDecoder, DeflateEncoder},
 };
 use tokio::{
-    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf},
+    io::{
+        AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, ReadHalf,
+        WriteHalf,
+    },
     sync::{
-        Mutex,
-        mpsc::{Receiver, Sender, channel, error::SendError},
+        Mutex, Notify, OwnedMutexGuard,
+        mpsc::{
+            Receiver, Sender, channel,
+            error::{SendError as ChannelError, TrySendError as TryChannelError},
+        },
+        oneshot, watch,
     },
-    time::interval,
+    task::{AbortHandle, JoinHandle},
 };
 
-use super::{event::Text, frame_handler::handle_frame};
+use super::{
+    CloseInfo, ConnectionContext, ConnectionInfo, DebugPayload, Observation, Observer,
+    event::Text,
+    frame_handler::{ReadState, fail, handle_frame, receiver_dropped},
+    header_values,
+};
 use crate::{
-    Event, MAX_FRAME_PAYLOAD, UpgradeError,
-    error::CloseReason,
-    frames::{FrameDecoder, FrameParseError, FrameState, Opcode, control, data},
-    protocol::PingStats,
+    Event, MAX_FRAME_PAYLOAD, MAX_MESSAGE_SIZE, UpgradeError, WebSocketConfig,
+    error::{CloseReason, PingError, SendError, TrySendError},
+    frames::{
+        DataChannel, FrameDecoder, FrameEncoder, FrameParseError, FrameState, Opcode, Outgoing,
+        SendWindow, TryDataError, control, control::sendable, data, data_shared, prime_dictionary,
+        try_data,
+    },
+    protocol::{DEFLATE_MEMORY, DeflateLease, LatencyStats, MAX_WINDOW, PingStats, ping_expiry},
     role::RolePolicy,
 };
 
 /// Generic WebSocket connection that applies masking according to role R, either client or server.
 pub struct WebSocket<R: RolePolicy> {
     pub(crate) inner: Arc<Inner>,
-    pub(crate) close_tx: Sender<Vec<u8>>,
+    pub(crate) close_tx: Sender<Closing>,
     pub(crate) ctrl_tx: Sender<Vec<u8>>,
-    pub(crate) data_tx: Sender<Bytes>,
+    pub(crate) data_tx: Sender<Outgoing>,
     pub(crate) event_rx: Receiver<Event>,
     pub(crate) local_addr: SocketAddr,
     pub(crate) peer_addr: SocketAddr,
-    pub(crate) deflater: Option<DeflateEncoder<Vec<u8>>>,
-    pub(crate) use_context: bool,
+    pub(crate) send_state: Arc<Mutex<SendState>>,
+    // the permessage-deflate parameters agreed in the handshake
+    pub(crate) deflate: Option<DeflateParams>,
+    // whether the compression context is kept between the messages we send, and those we receive
+    pub(crate) send_context: bool,
+    pub(crate) recv_context: bool,
+    pub(crate) max_inflight_pings: usize,
+    pub(crate) info: ConnectionInfo,
+    // this connection's share of the server's deflate budget
+    pub(crate) deflate_lease: Option<DeflateLease>,
+    pub(crate) protocol: Option<String>,
+    // set on the sink of a split connection, whose drop leaves the stream receiving
+    pub(crate) sink_half: bool,
     pub(crate) _role: PhantomData<R>,
 }
 
+// The compressor and frame encoder, shared by the connection and its handles. Locked for
+// the whole of a message, so the frames of different messages never interleave.
+pub(crate) struct SendState {
+    pub(crate) deflater: Option<DeflateEncoder<Vec<u8>>>,
+    pub(crate) dictionary: Option<Bytes>,
+    pub(crate) encoder: FrameEncoder,
+}
+
+// Takes the send state and holds it across polls, for senders which queue a message
+// over several calls
+pub(crate) struct SendLock {
+    state: Arc<Mutex<SendState>>,
+    lock: Option<Pin<Box<dyn Future<Output = OwnedMutexGuard<SendState>> + Send>>>,
+    guard: Option<OwnedMutexGuard<SendState>>,
+}
+
+impl SendLock {
+    pub(crate) fn new(state: Arc<Mutex<SendState>>) -> Self {
+        Self {
+            state,
+            lock: None,
+            guard: None,
+        }
+    }
+
+    pub(crate) fn poll_lock(&mut self, cx: &mut Context<'_>) -> Poll<&mut SendState> {
+        if self.guard.is_none() {
+            let state = self.state.clone();
+            let lock = self
+                .lock
+                .get_or_insert_with(|| Box::pin(state.lock_owned()));
+            self.guard = Some(ready!(lock.as_mut().poll(cx)));
+            self.lock = None;
+        }
+        Poll::Ready(self.guard.as_deref_mut().expect("set above"))
+    }
+
+    // the state if held, or if it can be taken without waiting
+    pub(crate) fn try_lock(&mut self) -> Option<&mut SendState> {
+        if self.guard.is_none() {
+            self.guard = self.state.clone().try_lock_owned().ok();
+        }
+        self.guard.as_deref_mut()
+    }
+
+    pub(crate) fn unlock(&mut self) { self.guard = None; }
+}
+
+// Queue a data message, as both the connection and its handles do
+pub(crate) async fn queue_data<R: RolePolicy>(
+    chan: DataChannel<'_>,
+    state: &Mutex<SendState>,
+    use_context: bool,
+    bytes: &[u8],
+    opcode: Opcode,
+    compress: bool,
+) -> std::result::Result<(), ChannelError<Outgoing>> {
+    let mut state = state.lock().await;
+    let state = &mut *state;
+    // without the deflater RSV1 stays clear, which the peer accepts on any message
+    let mut uncompressed = None;
+    let deflater = if compress {
+        &mut state.deflater
+    } else {
+        &mut uncompressed
+    };
+    data::<R>(
+        chan,
+        bytes,
+        opcode,
+        deflater,
+        use_context,
+        state.dictionary.as_deref(),
+        &mut state.encoder,
+    )
+    .await
+}
+
+// when a `ping_rtt` ping was sent, and where to report its round-trip time
+pub(crate) type RttWaiter = (Instant, oneshot::Sender<Duration>);
+
 pub(crate) struct Inner {
-    pub(crate) ping_stats: Mutex<PingStats>,
+    pub(crate) ping_stats: Mutex<PingStats<MAX_WINDOW>>,
     pub(crate) last_seen: Mutex<Instant>,
     pub(crate) closed: AtomicBool,
     pub(crate) closing: AtomicBool,
+    pub(crate) close_info: Mutex<Option<CloseInfo>>,
+    // payload bytes queued for the writer or waiting to be received
+    pub(crate) buffered_bytes: AtomicUsize,
+    // flush markers queued among the data frames, which the drain marks do not count
+    pub(crate) queued_flushes: AtomicUsize,
+    pub(crate) observer: Option<Observer>,
+    // payload of the latest ping awaiting a coalesced pong, see `PingPolicy::Lenient`
+    pub(crate) pending_pong: Mutex<Option<Bytes>>,
+    // pings sent by `ping_rtt`, keyed by nonce, awaiting their pong
+    pub(crate) rtt_waiters: Mutex<HashMap<[u8; 8], RttWaiter>>,
+    // stops the reader without waiting for the peer, e.g. once a drain timeout elapses
+    pub(crate) terminate: Notify,
+    // the reader has stopped, after the peer's close or otherwise, so the writer need
+    // not wait for it before shutting down the stream
+    pub(crate) read_closed: Notify,
+    // bytes sent but not yet flushed, see `WebSocketConfig::send_window`
+    pub(crate) send_window: Option<SendWindow>,
+    // stops the writer even in the middle of a write the peer is not reading
+    pub(crate) writer: OnceLock<AbortHandle>,
+    // idle time before the ping loop pings the peer, `None` to never ping
+    pub(crate) ping_interval: watch::Sender<Option<Duration>>,
+    // the peer's preset dictionary, see `set_inflate_dictionary`
+    pub(crate) inflate_dictionary: std::sync::Mutex<Option<Bytes>>,
+}
+
+impl Inner {
+    pub(crate) fn check_send(&self, len: usize) -> SendResult {
+        if self.closing.load(Ordering::Acquire) {
+            return Err(self.send_error());
+        }
+        if len > MAX_MESSAGE_SIZE {
+            return Err(SendError::TooLarge);
+        }
+        Ok(())
+    }
+
+    // why nothing can be sent, once the writer has gone
+    pub(crate) fn send_error(&self) -> SendError {
+        let closing = self.closing.load(Ordering::Acquire);
+        if closing && !self.closed.load(Ordering::Acquire) {
+            SendError::Closing
+        } else {
+            SendError::Closed
+        }
+    }
+
+    // report to the observer, if any, building the observation only when needed
+    pub(crate) fn observe(&self, f: impl FnOnce() -> Observation) {
+        if let Some(observer) = &self.observer {
+            observer.notify(&f());
+        }
+    }
+}
+
+/// The permessage-deflate parameters negotiated for a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompressionInfo {
+    /// Whether the compression context is kept between the messages this side sends.
+    /// Without it each message is compressed on its own, which costs ratio but saves memory.
+    pub context_takeover: bool,
+    /// The base-2 logarithm of the LZ77 window this side compresses with, always 15 as
+    /// smaller windows are neither offered nor accepted.
+    pub window_bits: u8,
+}
+
+/// The extensions agreed in the opening handshake, see [`WebSocket::negotiated_extensions`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NegotiatedExtensions {
+    /// The permessage-deflate parameters, or `None` if messages are not compressed.
+    pub permessage_deflate: Option<DeflateParams>,
+}
+
+/// The permessage-deflate parameters for each direction, as defined in
+/// [RFC 7692](https://www.rfc-editor.org/rfc/rfc7692.html#section-7.1).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeflateParams {
+    /// Whether the server starts a fresh compression context for every message it sends.
+    pub server_no_context_takeover: bool,
+    /// Whether the client starts a fresh compression context for every message it sends.
+    pub client_no_context_takeover: bool,
+    /// The base-2 logarithm of the LZ77 window the server compresses with.
+    pub server_max_window_bits: u8,
+    /// The base-2 logarithm of the LZ77 window the client compresses with.
+    pub client_max_window_bits: u8,
+}
+
+impl DeflateParams {
+    // the full window, with context takeover in both directions or neither
+    pub(crate) fn symmetric(context_takeover: bool) -> Self {
+        Self {
+            server_no_context_takeover: !context_takeover,
+            client_no_context_takeover: !context_takeover,
+            server_max_window_bits: 15,
+            client_max_window_bits: 15,
+        }
+    }
+
+    // whether the sender `R` keeps its context, and whether its peer does
+    fn context_takeover<R: RolePolicy>(self) -> (bool, bool) {
+        let (ours, theirs) = if R::CLIENT {
+            (
+                self.client_no_context_takeover,
+                self.server_no_context_takeover,
+            )
+        } else {
+            (
+                self.server_no_context_takeover,
+                self.client_no_context_takeover,
+            )
+        };
+        (!ours, !theirs)
+    }
 }
 
 /// Message to be sent over the websocket.
+#[derive(PartialEq)]
 pub enum Message {
     Text(String),
     Binary(Bytes),
+    /// A ping with an application chosen payload of at most 125 bytes, e.g. for a
+    /// keepalive sent by a [`MessageHandler`]. Its pong answers none of our measured
+    /// pings, so it is not reported, see [`PingPolicy`](crate::PingPolicy).
+    Ping(Bytes),
+    /// An unsolicited pong, e.g. as a one-way heartbeat, of at most 125 bytes.
+    Pong(Bytes),
+}
+
+impl fmt::Debug for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Text(s) => f.debug_tuple("Text").field(&DebugPayload::Text(s)).finish(),
+            Self::Binary(b) => f
+                .debug_tuple("Binary")
+                .field(&DebugPayload::Binary(b))
+                .finish(),
+            Self::Ping(b) => f
+                .debug_tuple("Ping")
+                .field(&DebugPayload::Binary(b))
+                .finish(),
+            Self::Pong(b) => f
+                .debug_tuple("Pong")
+                .field(&DebugPayload::Binary(b))
+                .finish(),
+        }
+    }
 }
 
 impl Message {
@@ -69,64 +320,177 @@ impl Message {
 
 #[async_trait::async_trait]
 pub trait MessageHandler: Send + Sync + 'static {
-    async fn on_text(&self, s: Text) -> Option<Message>;
-    async fn on_binary(&self, b: Bytes) -> Option<Message>;
-    async fn on_close(&self);
-    async fn on_error(&self);
-    async fn on_pong(&self, latency: u16);
+    /// Called once before any other events. Each callback is given the connection it is
+    /// for, e.g. to map the peer to a session.
+    async fn on_open(&self, _conn: &ConnectionContext) {}
+    async fn on_text(&self, conn: &ConnectionContext, s: Text) -> Option<Message>;
+    async fn on_binary(&self, conn: &ConnectionContext, b: Bytes) -> Option<Message>;
+    async fn on_close(&self, conn: &ConnectionContext);
+    async fn on_error(&self, conn: &ConnectionContext);
+    async fn on_pong(&self, conn: &ConnectionContext, latency: u16);
+}
+
+// What the writer's close channel carries
+pub(crate) enum Closing {
+    // our close frame, after which the stream is shut down once the peer has closed,
+    // unless `teardown` is off and it is left to `Shutdown`
+    Frame { frame: Vec<u8>, teardown: bool },
+    // shut the stream down, without a close frame if none was sent
+    Shutdown,
+}
+
+impl From<Vec<u8>> for Closing {
+    fn from(frame: Vec<u8>) -> Self {
+        Self::Frame {
+            frame,
+            teardown: true,
+        }
+    }
 }
 
 #[derive(Clone)]
 pub(crate) struct WsSender {
     ctrl: Sender<Vec<u8>>,
-    close: Sender<Vec<u8>>,
+    close: Sender<Closing>,
     event: Sender<Event>,
+    inner: Arc<Inner>,
 }
 
 impl WsSender {
-    pub fn new(ctrl: Sender<Vec<u8>>, close: Sender<Vec<u8>>, event: Sender<Event>) -> Self {
-        Self { ctrl, close, event }
+    pub fn new(
+        ctrl: Sender<Vec<u8>>,
+        close: Sender<Closing>,
+        event: Sender<Event>,
+        inner: Arc<Inner>,
+    ) -> Self {
+        Self {
+            ctrl,
+            close,
+            event,
+            inner,
+        }
+    }
+
+    /// Queues a control frame without waiting, failing if the control queue is full.
+    pub fn try_ctrl(&self, data: Vec<u8>) -> std::result::Result<(), TryChannelError<Vec<u8>>> {
+        self.ctrl.try_send(data)
+    }
+
+    pub async fn close(&self, data: Vec<u8>) -> Result<Closing> {
+        self.close.send(data.into()).await
     }
 
-    pub async fn ctrl(&self, data: Vec<u8>) -> Result<Vec<u8>> { self.ctrl.send(data).await }
+    pub async fn event(&self, event: Event) -> Result<Event> {
+        let len = event.len();
+        self.inner.buffered_bytes.fetch_add(len, Ordering::Relaxed);
+        let res = self.event.send(event).await;
+        if res.is_err() {
+            self.inner.buffered_bytes.fetch_sub(len, Ordering::Relaxed);
+        }
+        res
+    }
 
-    pub async fn close(&self, data: Vec<u8>) -> Result<Vec<u8>> { self.close.send(data).await }
+    // Queue `event` only if there is room, so the reader never waits on a slow consumer
+    pub fn try_event(&self, event: Event) -> std::result::Result<(), TryChannelError<Event>> {
+        let len = event.len();
+        self.inner.buffered_bytes.fetch_add(len, Ordering::Relaxed);
+        let res = self.event.try_send(event);
+        if res.is_err() {
+            self.inner.buffered_bytes.fetch_sub(len, Ordering::Relaxed);
+        }
+        res
+    }
 
-    pub async fn event(&self, event: Event) -> Result<Event> { self.event.send(event).await }
+    // Wait for room and queue the event in `slot`. Cancel safe, the event stays in
+    // `slot` until it is queued.
+    pub async fn event_from(&self, slot: &mut Option<Event>) -> Result<()> {
+        let permit = self.event.reserve().await?;
+        if let Some(event) = slot.take() {
+            self.inner
+                .buffered_bytes
+                .fetch_add(event.len(), Ordering::Relaxed);
+            permit.send(event);
+        }
+        Ok(())
+    }
 }
 
 /// Best-effort close if user forgets to call [`WebSocket::close`].
 impl<R: RolePolicy> Drop for WebSocket<R> {
-    fn drop(&mut self) { self.inner.closing.store(true, Ordering::Release); }
+    fn drop(&mut self) {
+        if !self.sink_half {
+            self.inner.closing.store(true, Ordering::Release);
+        }
+    }
 }
 
-type Result<T> = std::result::Result<(), SendError<T>>;
+type Result<T> = std::result::Result<(), ChannelError<T>>;
+type SendResult<T = ()> = std::result::Result<T, SendError>;
 
 impl<R: RolePolicy> WebSocket<R> {
+    // A connection over `stream` as if the handshake had agreed `deflate`, for tests
+    #[cfg(test)]
+    pub(crate) fn over_stream<S>(
+        stream: S,
+        deflate: Option<DeflateParams>,
+        config: &WebSocketConfig,
+    ) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let addr = crate::UNSPECIFIED_ADDR;
+        Self::from_stream(
+            stream,
+            addr,
+            addr,
+            deflate,
+            config,
+            ConnectionInfo::default(),
+        )
+    }
+
     pub(crate) fn from_stream<S>(
         stream: S,
         local_addr: SocketAddr,
         peer_addr: SocketAddr,
-        compressed: bool,
-        use_context: bool,
+        deflate: Option<DeflateParams>,
+        config: &WebSocketConfig,
+        info: ConnectionInfo,
     ) -> Self
     where
         S: AsyncRead + AsyncWrite + Send + 'static,
     {
         // create channels
-        const CHAN_BUF: usize = 64;
-        let (event_tx, event_rx) = channel(CHAN_BUF);
-        let (close_tx, close_rx) = channel(CHAN_BUF);
-        let (ctrl_tx, ctrl_rx) = channel(CHAN_BUF);
-        let (data_tx, data_rx) = channel(CHAN_BUF);
+        let (event_tx, event_rx) = channel(config.channel_capacity);
+        let (close_tx, close_rx) = channel(config.channel_capacity);
+        let (ctrl_tx, ctrl_rx) = channel(config.max_queued_control_frames);
+        let (data_tx, data_rx) = channel(config.channel_capacity);
+
+        let (send_context, recv_context) =
+            deflate.map_or((true, true), DeflateParams::context_takeover::<R>);
 
         // create WebSocket struct
         let ws = Self {
             inner: Arc::new(Inner {
-                ping_stats: Mutex::new(PingStats::new()),
+                ping_stats: Mutex::new(PingStats::new(
+                    config.max_inflight_pings,
+                    ping_expiry(config.ping_interval, config.ping_timeout_multiplier),
+                )),
                 last_seen: Mutex::new(Instant::now()),
                 closed: AtomicBool::new(false),
                 closing: AtomicBool::new(false),
+                close_info: Mutex::new(None),
+                buffered_bytes: AtomicUsize::new(0),
+                queued_flushes: AtomicUsize::new(0),
+                observer: config.observer.clone(),
+                pending_pong: Mutex::new(None),
+                rtt_waiters: Mutex::new(HashMap::new()),
+                terminate: Notify::new(),
+                read_closed: Notify::new(),
+                send_window: config.send_window.map(SendWindow::new),
+                writer: OnceLock::new(),
+                ping_interval: watch::Sender::new(config.ping_interval),
+                inflate_dictionary: std::sync::Mutex::new(None),
             }),
             close_tx: close_tx.clone(),
             ctrl_tx: ctrl_tx.clone(),
@@ -134,75 +498,518 @@ impl<R: RolePolicy> WebSocket<R> {
             event_rx,
             local_addr,
             peer_addr,
-            deflater: if compressed {
-                Some(DeflateEncoder::new(vec![], Compression::fast()))
-            } else {
-                None
-            },
-            use_context,
+            send_state: Arc::new(Mutex::new(SendState {
+                deflater: deflate.map(|_| DeflateEncoder::new(vec![], Compression::fast())),
+                dictionary: None,
+                encoder: FrameEncoder::new(config.max_send_frame_size),
+            })),
+            deflate,
+            send_context,
+            recv_context,
+            max_inflight_pings: config.max_inflight_pings,
+            info,
+            deflate_lease: None,
+            protocol: None,
+            sink_half: false,
             _role: PhantomData,
         };
 
         // initiate background loops
         let (reader, writer) = tokio::io::split(stream);
-        let sender = WsSender::new(ctrl_tx, close_tx, event_tx);
+        let drain = config
+            .drain_marks
+            .map(|(high, low)| DrainWatch::new(high, low, event_tx.clone()));
+        let sender = WsSender::new(
+            ctrl_tx.clone(),
+            close_tx.clone(),
+            event_tx,
+            ws.inner.clone(),
+        );
 
-        Self::writer_loop(close_rx, ctrl_rx, data_rx, writer);
-        ws.ping_loop(30, sender.clone());
+        Self::writer_loop(
+            close_rx,
+            ctrl_rx,
+            data_rx,
+            writer,
+            ws.inner.clone(),
+            drain,
+            config.close_timeout,
+        );
+        ws.ping_loop(ctrl_tx, close_tx, config.ping_timeout_multiplier);
         ws.reader_loop(
             reader,
             sender,
-            if compressed {
-                Some(DeflateDecoder::new(vec![]))
-            } else {
-                None
-            },
+            deflate.map(|_| DeflateDecoder::new(vec![])),
+            config.clone(),
         );
         ws
     }
 
     /// Sends text to the connected endpoint.
     /// # Errors
-    /// If the peer has disconnected or we are currently closing, this function returns an error.
-    /// The error includes the value passed.
-    pub async fn send_text(&mut self, text: &str) -> Result<Bytes> {
-        self.send_data(text.as_bytes(), Opcode::Text).await
+    /// Fails with [`SendError::Closed`] if the connection has closed, [`SendError::Closing`]
+    /// if we are closing it, or [`SendError::TooLarge`] if the message is over 16M.
+    pub async fn send_text(&mut self, text: &str) -> SendResult {
+        self.send_data(text.as_bytes(), Opcode::Text, true).await
     }
 
     /// Sends bytes to the connected endpoint.
     /// # Errors
-    /// If the peer has disconnected or we are currently closing, this function returns an error.
-    pub async fn send_bytes(&mut self, bytes: &[u8]) -> Result<Bytes> {
-        self.send_data(bytes, Opcode::Bin).await
+    /// Fails as [`send_text`](WebSocket::send_text) does.
+    pub async fn send_bytes(&mut self, bytes: &[u8]) -> SendResult {
+        self.send_data(bytes, Opcode::Bin, true).await
+    }
+
+    /// Sends bytes to the connected endpoint without compressing them, even if compression
+    /// was negotiated. Saves CPU on data known not to compress, e.g. encrypted payloads.
+    /// Other messages are still compressed.
+    /// # Errors
+    /// Fails as [`send_text`](WebSocket::send_text) does.
+    pub async fn send_bytes_uncompressed(&mut self, bytes: &[u8]) -> SendResult {
+        self.send_data(bytes, Opcode::Bin, false).await
     }
 
-    async fn send_data(&mut self, bytes: &[u8], opcode: Opcode) -> Result<Bytes> {
-        data::<R>(
-            &self.data_tx,
+    async fn send_data(&mut self, bytes: &[u8], opcode: Opcode, compress: bool) -> SendResult {
+        self.check_send(bytes.len())?;
+        let chan = DataChannel {
+            tx: &self.data_tx,
+            buffered: &self.inner.buffered_bytes,
+            window: self.inner.send_window.as_ref(),
+        };
+        queue_data::<R>(
+            chan,
+            &self.send_state,
+            self.send_context,
             bytes,
             opcode,
-            &mut self.deflater,
-            self.use_context,
+            compress,
         )
         .await
+        .map_err(|_| self.send_error())?;
+        self.inner
+            .observe(|| Observation::MessageSent { len: bytes.len() });
+        Ok(())
+    }
+
+    /// Sends text without waiting, for real-time feeds which would rather drop or coalesce
+    /// messages than fall behind a slow peer.
+    ///
+    /// The message is queued whole or not at all. Each frame of it takes a slot in the
+    /// send queue, see [`WebSocketConfig::channel_capacity`], and its bytes must fit any
+    /// [`send_window`](WebSocketConfig::send_window).
+    /// # Errors
+    /// Fails with [`TrySendError::Full`], handing the message back, if there is no room
+    /// for it now or a [`WsHandle`](crate::WsHandle) is queuing another, or with [`SendError::TooLarge`] if it takes more frames than the send
+    /// queue holds, as there never will be. Otherwise fails as
+    /// [`send_text`](WebSocket::send_text) does.
+    pub fn try_send_text(
+        &mut self,
+        text: impl Into<String>,
+    ) -> std::result::Result<(), TrySendError> {
+        self.try_send_message(Message::Text(text.into()))
+    }
+
+    /// Sends bytes without waiting, see [`try_send_text`](WebSocket::try_send_text).
+    /// # Errors
+    /// Fails as [`try_send_text`](WebSocket::try_send_text) does.
+    pub fn try_send_bytes(
+        &mut self,
+        bytes: impl Into<Bytes>,
+    ) -> std::result::Result<(), TrySendError> {
+        self.try_send_message(Message::Binary(bytes.into()))
+    }
+
+    fn try_send_message(&mut self, message: Message) -> std::result::Result<(), TrySendError> {
+        let (bytes, opcode) = match &message {
+            Message::Text(s) => (s.as_bytes(), Opcode::Text),
+            Message::Binary(b) => (&b[..], Opcode::Bin),
+            Message::Ping(b) | Message::Pong(b) => {
+                let frame = self.control_frame(&message, b)?;
+                return match self.ctrl_tx.try_send(frame) {
+                    Ok(()) => Ok(()),
+                    Err(TryChannelError::Full(_)) => Err(TrySendError::Full(message)),
+                    Err(TryChannelError::Closed(_)) => Err(self.send_error().into()),
+                };
+            }
+        };
+        self.check_send(bytes.len())?;
+        let chan = DataChannel {
+            tx: &self.data_tx,
+            buffered: &self.inner.buffered_bytes,
+            window: self.inner.send_window.as_ref(),
+        };
+        // another message is still being queued, by a handle or the sink
+        let Ok(mut state) = self.send_state.try_lock() else {
+            return Err(TrySendError::Full(message));
+        };
+        let state = &mut *state;
+        let queued = try_data::<R>(
+            chan,
+            bytes,
+            opcode,
+            &mut state.deflater,
+            self.send_context,
+            state.dictionary.as_deref(),
+            &mut state.encoder,
+        );
+        match queued {
+            Ok(()) => {
+                let len = bytes.len();
+                self.inner.observe(|| Observation::MessageSent { len });
+                Ok(())
+            }
+            Err(TryDataError::Full) => Err(TrySendError::Full(message)),
+            Err(TryDataError::Closed) => Err(self.send_error().into()),
+            Err(TryDataError::TooLarge) => Err(SendError::TooLarge.into()),
+        }
+    }
+
+    // the writer stops at the close frame, so anything queued after it is lost
+    fn check_send(&self, len: usize) -> SendResult { self.inner.check_send(len) }
+
+    fn send_error(&self) -> SendError { self.inner.send_error() }
+
+    pub(super) async fn send_message(&mut self, message: Message) -> SendResult {
+        match message {
+            Message::Text(s) => self.send_data(s.as_bytes(), Opcode::Text, true).await,
+            Message::Binary(b) => self.send_shared(b).await,
+            Message::Ping(ref b) | Message::Pong(ref b) => {
+                let frame = self.control_frame(&message, b)?;
+                self.ctrl_tx
+                    .send(frame)
+                    .await
+                    .map_err(|_| self.send_error())
+            }
+        }
+    }
+
+    // Encode a ping or pong given by the application
+    pub(super) fn control_frame(&self, message: &Message, payload: &[u8]) -> SendResult<Vec<u8>> {
+        self.check_send(payload.len())?;
+        if payload.len() > 125 {
+            return Err(SendError::TooLarge);
+        }
+        Ok(match message {
+            Message::Ping(_) => control::ping::<R>(payload),
+            _ => control::pong::<R>(payload),
+        })
+    }
+
+    // Send binary we already own, on a server without compression this avoids copying
+    // the payload into the frames
+    async fn send_shared(&mut self, bytes: Bytes) -> SendResult {
+        if R::CLIENT || self.deflate.is_some() {
+            return self.send_bytes(&bytes).await;
+        }
+        self.check_send(bytes.len())?;
+        let len = bytes.len();
+        let chan = DataChannel {
+            tx: &self.data_tx,
+            buffered: &self.inner.buffered_bytes,
+            window: self.inner.send_window.as_ref(),
+        };
+        let mut state = self.send_state.lock().await;
+        data_shared::<R>(chan, bytes, Opcode::Bin, &mut state.encoder)
+            .await
+            .map_err(|_| self.send_error())?;
+        self.inner.observe(|| Observation::MessageSent { len });
+        Ok(())
+    }
+
+    /// Waits until every message sent before this call has been written and flushed to
+    /// the underlying stream.
+    ///
+    /// Useful at the end of a burst of sends, before doing something which depends on
+    /// the peer having been sent the data.
+    /// # Errors
+    /// Fails with [`SendError::Closed`] if the connection has been shut down.
+    pub async fn flush(&self) -> SendResult {
+        let (ack_tx, ack_rx) = oneshot::channel();
+        self.inner.queued_flushes.fetch_add(1, Ordering::Relaxed);
+        if self.data_tx.send(Outgoing::Flush(ack_tx)).await.is_err() {
+            self.inner.queued_flushes.fetch_sub(1, Ordering::Relaxed);
+            return Err(SendError::Closed);
+        }
+        ack_rx.await.map_err(|_| SendError::Closed)
+    }
+
+    /// Seeds the compressor with a preset dictionary, improving the compression of messages
+    /// which share content with it, e.g. JSON with repeated keys.
+    ///
+    /// Only the last 32K of the dictionary is used. The peer must be told about the dictionary
+    /// out-of-band and prime its decompressor with the same bytes at the same point in the
+    /// stream, see [`set_inflate_dictionary`](WebSocket::set_inflate_dictionary). With
+    /// context takeover, the dictionary is added to the history of the messages sent so far,
+    /// otherwise it is applied to every message from now on.
+    /// Has no effect if compression was not negotiated.
+    pub async fn set_deflate_dictionary(&self, dictionary: &[u8]) {
+        let mut state = self.send_state.lock().await;
+        let state = &mut *state;
+        let Some(deflater) = &mut state.deflater else {
+            return;
+        };
+        if self.send_context {
+            prime_dictionary(deflater, dictionary);
+        } else {
+            state.dictionary = Some(Bytes::copy_from_slice(dictionary));
+        }
+    }
+
+    /// Seeds the decompressor with the preset dictionary the peer passes to
+    /// [`set_deflate_dictionary`](WebSocket::set_deflate_dictionary), so its messages can
+    /// be decompressed.
+    ///
+    /// Applies from the next compressed message the connection decodes, so set it before
+    /// the peer sends with the dictionary, e.g. straight after connecting. With the peer's
+    /// context takeover, the dictionary is added to the history once, otherwise it is
+    /// applied to every message. Has no effect if compression was not negotiated.
+    pub fn set_inflate_dictionary(&self, dictionary: &[u8]) {
+        if self.deflate.is_some() {
+            let mut preset = self
+                .inner
+                .inflate_dictionary
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner);
+            *preset = Some(Bytes::copy_from_slice(dictionary));
+        }
     }
 
     /// Request close from peer and close the connection.
     pub async fn close(&mut self) { self.close_reason(CloseReason::Normal, "").await; }
 
-    async fn close_reason(&mut self, reason: CloseReason, text: &'static str) {
+    /// Close with `code` and `reason`, e.g. `close_with(CloseReason::Policy, &reason)` or a
+    /// private code such as 4001. The reason is cut to the 123 bytes a close frame has room
+    /// for, at a character boundary.
+    /// # Errors
+    /// Fails with [`SendError::InvalidCloseCode`] if `code` may not be sent in a close
+    /// frame, e.g. 1005 or 1006. Codes 1000-1003, 1007-1014 and 3000-4999 may be sent.
+    pub async fn close_with(&mut self, code: impl Into<u16>, reason: &str) -> SendResult {
+        let close = close_frame::<R>(code, reason)?;
+        if !self.inner.closing.swap(true, Ordering::AcqRel) {
+            let _ = self.close_tx.send(close.into()).await;
+        }
+        Ok(())
+    }
+
+    /// Close with `code` and `reason`, then wait up to `deadline` for the peer's close.
+    ///
+    /// If the peer has not closed by then, the connection is torn down without waiting
+    /// for it, even in the middle of a write, so the connection and its tasks are gone
+    /// within a bounded time however the peer behaves. Events received while waiting
+    /// are discarded.
+    ///
+    /// Returns the [`CloseInfo`] which ended the connection, code 1006 if the peer did
+    /// not close in time.
+    /// # Errors
+    /// Fails with [`SendError::InvalidCloseCode`] if `code` may not be sent in a close
+    /// frame, e.g. 1005 or 1006, without closing.
+    pub async fn close_with_deadline(
+        &mut self,
+        code: impl Into<u16>,
+        reason: &str,
+        deadline: Duration,
+    ) -> SendResult<CloseInfo> {
+        self.close_with(code, reason).await?;
+        if let Ok(info) = tokio::time::timeout(deadline, self.closed_event()).await {
+            return Ok(info);
+        }
+
+        tracing::debug!(timeout = ?deadline, "peer did not close in time, terminating");
+        if let Some(writer) = self.inner.writer.get() {
+            writer.abort();
+        }
+        self.inner.terminate.notify_one();
+        Ok(self.closed_event().await)
+    }
+
+    // discard events until the connection has closed
+    async fn closed_event(&mut self) -> CloseInfo {
+        while let Some(event) = self.next_event().await {
+            if let Event::Closed(info) = event {
+                return info;
+            }
+        }
+        CloseInfo::abnormal()
+    }
+
+    pub(crate) async fn close_reason(&mut self, reason: CloseReason, text: &str) {
         if !self.inner.closing.swap(true, Ordering::AcqRel) {
-            let _ = self.close_tx.send(control::close::<R>(reason, text)).await;
+            let _ = self
+                .close_tx
+                .send(control::close::<R>(reason, text).into())
+                .await;
+        }
+    }
+
+    // Close with `reason` once `signal` completes, unless we are closing already. Lets
+    // another task end the connection while this one is busy receiving. If the peer has
+    // not finished closing within `drain`, the connection is terminated.
+    pub(crate) fn close_when<F>(
+        &self,
+        signal: F,
+        reason: CloseReason,
+        text: &str,
+        drain: Option<Duration>,
+    ) -> JoinHandle<()>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let inner = self.inner.clone();
+        let close_tx = self.close_tx.clone();
+        // encoded up front so the task does not borrow `text`
+        let close = control::close::<R>(reason, text);
+        tokio::spawn(async move {
+            signal.await;
+            if !inner.closing.swap(true, Ordering::AcqRel) {
+                let _ = close_tx.send(close.into()).await;
+            }
+            if let Some(drain) = drain {
+                tokio::time::sleep(drain).await;
+                if !inner.closed.load(Ordering::Acquire) {
+                    tracing::debug!(timeout = ?drain, "peer did not close in time, terminating");
+                    inner.terminate.notify_one();
+                }
+            }
+        })
+    }
+
+    /// Send a close frame with `code` and `reason` without tearing down the connection,
+    /// e.g. to echo the code of a peer which closed first.
+    ///
+    /// Unlike [`close`](WebSocket::close), the stream stays open after the close frame is
+    /// written so the peer's close can still be read, after which [`Event::Closed`] is
+    /// produced. Use [`shutdown`](WebSocket::shutdown) to tear down the connection
+    /// afterwards. Nothing more is sent once this has been called.
+    /// # Errors
+    /// Fails with [`SendError::InvalidCloseCode`] if `code` may not be sent in a close
+    /// frame, [`SendError::Closing`] if a close frame has already been sent, or
+    /// [`SendError::Closed`] if the connection has closed.
+    pub async fn send_close(&self, code: impl Into<u16>, reason: &str) -> SendResult {
+        let frame = close_frame::<R>(code, reason)?;
+        if self.inner.closing.swap(true, Ordering::AcqRel) {
+            return Err(SendError::Closing);
+        }
+        let close = Closing::Frame {
+            frame,
+            teardown: false,
+        };
+        self.close_tx
+            .send(close)
+            .await
+            .map_err(|_| SendError::Closed)
+    }
+
+    /// Tear down the connection, without sending a close frame if none has been sent.
+    ///
+    /// Pairs with [`send_close`](WebSocket::send_close) once the closing handshake has completed.
+    pub async fn shutdown(&self) {
+        self.inner.closing.store(true, Ordering::Release);
+        let _ = self.close_tx.send(Closing::Shutdown).await;
+    }
+
+    /// Send an unsolicited pong carrying `payload`, e.g. as a one-way keepalive.
+    ///
+    /// The peer does not answer it. Pongs for the peer's pings are sent automatically
+    /// unless [`WebSocketConfig::auto_pong`] is off, in which case answer each
+    /// [`Event::Ping`] with this, echoing its payload.
+    /// # Errors
+    /// Fails with [`SendError::TooLarge`] if `payload` is longer than 125 bytes, the limit
+    /// for control frames, [`SendError::Closing`] once a close frame has been sent, or
+    /// [`SendError::Closed`] if the connection has closed.
+    pub async fn pong(&self, payload: &[u8]) -> SendResult {
+        self.check_send(payload.len())?;
+        if payload.len() > 125 {
+            return Err(SendError::TooLarge);
         }
+        self.ctrl_tx
+            .send(control::pong::<R>(payload))
+            .await
+            .map_err(|_| self.send_error())
     }
 
     /// Send a ping to the peer. The associated latency measurement will appear
     /// as an [`Event::Pong`].
     /// # Errors
-    /// If the peer has disconnected or we are currently closing, this function returns an error.
-    pub async fn ping(&self) -> Result<Vec<u8>> {
-        let nonce = self.inner.ping_stats.lock().await.new_nonce();
-        self.ctrl_tx.send(control::ping::<R>(&nonce)).await
+    /// Returns [`PingError::TooMany`] if too many pings are awaiting a pong, see
+    /// [`WebSocketConfig::max_inflight_pings`], or [`PingError::Closed`] if the connection
+    /// has closed.
+    pub async fn ping(&self) -> std::result::Result<(), PingError> {
+        let nonce = self
+            .inner
+            .ping_stats
+            .lock()
+            .await
+            .new_nonce()
+            .ok_or(PingError::TooMany)?;
+        self.ctrl_tx
+            .send(control::ping::<R>(&nonce))
+            .await
+            .map_err(|_| PingError::Closed)?;
+        self.inner.observe(|| Observation::PingSent);
+        Ok(())
+    }
+
+    /// Like [`ping`](WebSocket::ping), but never waits, skipping the ping instead, e.g. so a
+    /// latency monitor is not stalled by a backed-up writer.
+    /// # Errors
+    /// Returns [`PingError::Full`] if the control channel is full or the ping statistics
+    /// are momentarily locked, [`PingError::TooMany`] if too many pings are awaiting a
+    /// pong, or [`PingError::Closed`] if the connection has closed.
+    pub fn try_ping(&self) -> std::result::Result<(), PingError> {
+        // reserve first so no nonce is recorded for a ping which is never sent
+        let permit = self.ctrl_tx.try_reserve().map_err(|e| match e {
+            TryChannelError::Full(()) => PingError::Full,
+            TryChannelError::Closed(()) => PingError::Closed,
+        })?;
+        let mut stats = self
+            .inner
+            .ping_stats
+            .try_lock()
+            .map_err(|_| PingError::Full)?;
+        let Some(nonce) = stats.new_nonce() else {
+            return Err(PingError::TooMany);
+        };
+        drop(stats);
+        permit.send(control::ping::<R>(&nonce));
+        self.inner.observe(|| Observation::PingSent);
+        Ok(())
+    }
+
+    /// Sends a ping and waits for its pong, returning the round-trip time.
+    ///
+    /// Unlike [`ping`](WebSocket::ping), no [`Event::Pong`] is produced and the latency is
+    /// not included in [`latency`](WebSocket::latency). Several may be in flight at once.
+    /// # Errors
+    /// Returns [`PingError::Timeout`] if no pong arrives within `timeout`, or
+    /// [`PingError::Closed`] if the connection closes first.
+    pub async fn ping_rtt(&self, timeout: Duration) -> std::result::Result<Duration, PingError> {
+        let mut nonce = [0; 8];
+        rand::fill(&mut nonce);
+        let (tx, rx) = oneshot::channel();
+        {
+            // these share the in-flight limit with ordinary pings
+            let in_flight = self.inner.ping_stats.lock().await.in_flight();
+            let mut waiters = self.inner.rtt_waiters.lock().await;
+            if in_flight + waiters.len() >= self.max_inflight_pings {
+                return Err(PingError::TooMany);
+            }
+            waiters.insert(nonce, (Instant::now(), tx));
+        }
+
+        if self.ctrl_tx.send(control::ping::<R>(&nonce)).await.is_err() {
+            self.inner.rtt_waiters.lock().await.remove(&nonce);
+            return Err(PingError::Closed);
+        }
+        self.inner.observe(|| Observation::PingSent);
+
+        match tokio::time::timeout(timeout, rx).await {
+            Ok(Ok(rtt)) => Ok(rtt),
+            Ok(Err(_)) => Err(PingError::Closed),
+            Err(_) => {
+                self.inner.rtt_waiters.lock().await.remove(&nonce);
+                Err(PingError::Timeout)
+            }
+        }
     }
 
     /// Returns the peer socket address.
@@ -213,53 +1020,175 @@ impl<R: RolePolicy> WebSocket<R> {
     #[must_use]
     pub fn local_addr(&self) -> SocketAddr { self.local_addr }
 
+    /// Returns the subprotocol agreed in the opening handshake, if any.
+    #[must_use]
+    pub fn protocol(&self) -> Option<&str> { self.protocol.as_deref() }
+
+    /// Returns details of the upgrade request, such as its path and query parameters.
+    #[must_use]
+    pub fn info(&self) -> &ConnectionInfo { &self.info }
+
+    /// Returns the negotiated compression parameters, or `None` if messages are not compressed.
+    ///
+    /// Applications can use this to avoid compressing payloads twice.
+    #[must_use]
+    pub fn compression(&self) -> Option<CompressionInfo> {
+        self.deflate.map(|_| CompressionInfo {
+            context_takeover: self.send_context,
+            window_bits: 15,
+        })
+    }
+
+    /// Returns the estimated memory held for permessage-deflate, 0 if messages are not
+    /// compressed.
+    ///
+    /// This covers the compressor and decompressor state, which with context takeover
+    /// lives as long as the connection, and the output buffer kept for reuse, which is
+    /// as large as the largest compressed message sent.
+    #[must_use]
+    pub fn deflate_memory(&self) -> usize {
+        if self.deflate.is_none() {
+            return 0;
+        }
+        // the buffer is left out while a message is being compressed
+        let buffer = self.send_state.try_lock().ok().and_then(|state| {
+            let deflater = state.deflater.as_ref()?;
+            Some(deflater.get_ref().capacity())
+        });
+        DEFLATE_MEMORY + buffer.unwrap_or(0)
+    }
+
+    /// Returns the extensions agreed in the opening handshake, for branching on without
+    /// parsing the `Sec-WebSocket-Extensions` header.
+    #[must_use]
+    pub fn negotiated_extensions(&self) -> NegotiatedExtensions {
+        NegotiatedExtensions {
+            permessage_deflate: self.deflate,
+        }
+    }
+
+    /// Returns the number of events received but not yet consumed by [`recv`](WebSocket::recv).
+    ///
+    /// A growing count means the application is falling behind the peer.
+    #[must_use]
+    pub fn pending_events(&self) -> usize { self.event_rx.len() }
+
+    /// Returns the number of message bytes buffered by this connection, both queued to be
+    /// written and received but not yet consumed.
+    ///
+    /// Outbound messages are counted as the frames to be written, so after compression and
+    /// including each frame's header, until the writer takes them or the connection
+    /// closes. Inbound messages are counted by payload. Control frames are small and
+    /// bounded, so they are not counted.
+    #[must_use]
+    pub fn buffered_bytes(&self) -> usize { self.inner.buffered_bytes.load(Ordering::Relaxed) }
+
+    /// Changes how long the connection may sit idle before the peer is pinged, `None` to
+    /// stop pinging, see [`WebSocketConfig::ping_interval`]. Takes effect immediately.
+    pub fn set_ping_interval(&mut self, interval: Option<Duration>) {
+        self.inner.ping_interval.send_replace(interval);
+    }
+
     /// Returns the average latency in ms from last 5 pings
     #[must_use]
     pub async fn latency(&self) -> Option<u16> { self.inner.ping_stats.lock().await.average() }
 
+    /// Returns a summary of the latencies measured by the last `N` pings, e.g.
+    /// `ws.latency_stats::<20>()`, or `None` before any pong has arrived. `N` may be at
+    /// most 64.
+    #[must_use]
+    pub async fn latency_stats<const N: usize>(&self) -> Option<LatencyStats> {
+        const {
+            assert!(
+                N > 0 && N <= MAX_WINDOW,
+                "latency window must be 1 to 64 pings"
+            );
+        };
+        self.inner.ping_stats.lock().await.stats(N)
+    }
+
     /// Wait for and return the next [`Event`].
-    pub async fn recv(&mut self) -> Option<Event> { self.event_rx.recv().await }
+    pub async fn recv(&mut self) -> Option<Event> { self.next_event().await }
 
     /// Wait for and return the next [`Event`] with a given timeout.
     pub async fn recv_timeout(&mut self, timeout: Duration) -> Option<Event> {
-        tokio::time::timeout(timeout, self.event_rx.recv())
+        tokio::time::timeout(timeout, self.next_event())
             .await
             .unwrap_or_default()
     }
 
-    /// Start a recv loop which handles the events with a [`MessageHandler`]
-    pub async fn recv_loop<H: MessageHandler>(&mut self, handler: Arc<H>) {
+    // every event must be taken through here to keep `buffered_bytes` accurate
+    pub(crate) async fn next_event(&mut self) -> Option<Event> {
+        let event = self.event_rx.recv().await?;
+        self.inner
+            .buffered_bytes
+            .fetch_sub(event.len(), Ordering::Relaxed);
+        Some(event)
+    }
+
+    /// Start a recv loop which handles the events with a [`MessageHandler`].
+    ///
+    /// Each handler call is awaited before the next event is read, see
+    /// [`recv_loop_concurrent`](WebSocket::recv_loop_concurrent) to overlap them.
+    ///
+    /// Returns the [`CloseInfo`] which ended the connection.
+    pub async fn recv_loop<H: MessageHandler + ?Sized>(&mut self, handler: Arc<H>) -> CloseInfo {
+        let conn = self.context();
+        handler.on_open(&conn).await;
         // start a loop to handle events from this client
-        while let Some(event) = self.event_rx.recv().await {
+        while let Some(event) = self.next_event().await {
             match event {
                 Event::Text(s) => {
-                    self.handle_ws_message(handler.on_text(s).await).await;
+                    self.handle_ws_message(handler.on_text(&conn, s).await)
+                        .await;
                 }
                 Event::Binary(b) => {
-                    self.handle_ws_message(handler.on_binary(b).await).await;
+                    self.handle_ws_message(handler.on_binary(&conn, b).await)
+                        .await;
                 }
-                Event::Closed => {
-                    handler.on_close().await;
-                    break;
+                Event::Message { data, text, .. } => {
+                    let reply = if text {
+                        handler.on_text(&conn, Text { inner: data }).await
+                    } else {
+                        handler.on_binary(&conn, data).await
+                    };
+                    self.handle_ws_message(reply).await;
                 }
-                Event::Pong(latency) => handler.on_pong(latency).await,
+                Event::Closed(info) => {
+                    handler.on_close(&conn).await;
+                    return info;
+                }
+                Event::Pong { latency, .. } => handler.on_pong(&conn, latency).await,
+                Event::Ping(_) | Event::Drained => {}
             }
         }
+        CloseInfo::abnormal()
     }
 
-    async fn handle_ws_message(&mut self, msg: Option<Message>) {
-        match msg {
-            Some(Message::Text(s)) => {
-                if let Err(e) = self.send_data(s.as_bytes(), Opcode::Text).await {
-                    tracing::error!(e = ?e, "failed to send text message");
-                }
-            }
-            Some(Message::Binary(b)) => {
-                if let Err(e) = self.send_bytes(&b).await {
-                    tracing::error!(e = ?e, "failed to send binary message");
-                }
-            }
-            None => {}
+    // what handlers are told about this connection
+    pub(super) fn context(&self) -> ConnectionContext {
+        ConnectionContext {
+            handle: self.handle(),
+            peer_addr: self.peer_addr,
+            local_addr: self.local_addr,
+            protocol: self.protocol.clone(),
+            compressed: self.deflate.is_some(),
+            info: self.info.clone(),
+        }
+    }
+
+    pub(super) async fn handle_ws_message(&mut self, msg: Option<Message>) {
+        let Some(msg) = msg else {
+            return;
+        };
+        let kind = match msg {
+            Message::Text(_) => "text message",
+            Message::Binary(_) => "binary message",
+            Message::Ping(_) => "ping",
+            Message::Pong(_) => "pong",
+        };
+        if let Err(e) = self.send_message(msg).await {
+            tracing::error!(e = ?e, "failed to send {kind}");
         }
     }
 
@@ -273,6 +1202,26 @@ impl<R: RolePolicy> WebSocket<R> {
         BASE64.encode(hasher.finalize())
     }
 
+    /// Reads the first line of a handshake, failing if it exceeds `limit` bytes
+    /// before a newline is found.
+    pub(crate) async fn read_status_line<S: AsyncRead + Unpin>(
+        reader: &mut BufReader<S>,
+        limit: usize,
+    ) -> std::result::Result<String, UpgradeError> {
+        let mut line = String::new();
+        let n = (&mut *reader)
+            .take(limit as u64)
+            .read_line(&mut line)
+            .await
+            .map_err(|_| UpgradeError::Read)?;
+
+        if n == limit && !line.ends_with('\n') {
+            tracing::warn!(limit = limit, "handshake line exceeded maximum length");
+            return Err(UpgradeError::StatusLine(line));
+        }
+        Ok(line)
+    }
+
     pub(crate) fn validate_header(
         headers: &HashMap<String, String>,
         field: &'static str,
@@ -282,7 +1231,8 @@ impl<R: RolePolicy> WebSocket<R> {
             .get(field)
             .ok_or(UpgradeError::MissingHeader(field))?;
 
-        if value.eq_ignore_ascii_case(expected) {
+        // headers such as `Connection: keep-alive, Upgrade` hold a list of tokens
+        if header_values(headers, field).any(|v| v.eq_ignore_ascii_case(expected)) {
             Ok(())
         } else {
             Err(UpgradeError::Header {
@@ -294,18 +1244,38 @@ impl<R: RolePolicy> WebSocket<R> {
     }
 
     pub(crate) fn writer_loop<S: AsyncWrite + Send + 'static>(
-        mut close_rx: Receiver<Vec<u8>>,
+        mut close_rx: Receiver<Closing>,
         mut ctrl_rx: Receiver<Vec<u8>>,
-        mut data_rx: Receiver<Bytes>,
+        data_rx: Receiver<Outgoing>,
         mut writer: WriteHalf<S>,
+        inner: Arc<Inner>,
+        mut drain: Option<DrainWatch>,
+        close_timeout: Duration,
     ) {
-        tokio::spawn(async move {
+        let abort = inner.clone();
+        let task = tokio::spawn(async move {
+            let mut data_rx = Unwritten {
+                rx: data_rx,
+                inner: inner.clone(),
+            };
+            let data_rx = &mut data_rx.rx;
+            // nothing may follow our close frame
+            let mut close_sent = false;
             loop {
                 tokio::select! {
                     biased;
                      Some(close) = close_rx.recv() => {
-                         let _ = writer.write_all(&close).await;
-                         let _ = writer.flush().await;
+                         if let Closing::Frame { frame, teardown } = close {
+                             if !close_sent {
+                                 let _ = writer.write_all(&frame).await;
+                                 let _ = writer.flush().await;
+                                 close_sent = true;
+                             }
+                             if !teardown {
+                                 continue;
+                             }
+                             await_peer_close(&inner, close_timeout).await;
+                         }
                          if let Err(e) = writer.shutdown().await{
                              tracing::warn!(e = ?e, "stream shutdown");
                          }
@@ -313,60 +1283,143 @@ impl<R: RolePolicy> WebSocket<R> {
                          break;
 
                      }
-                    Some(ctrl) = ctrl_rx.recv() => {
+                    Some(mut ctrl) = ctrl_rx.recv(), if !close_sent => {
+                        // an empty control frame stands for the latest coalesced pong
+                        if ctrl.is_empty() {
+                            let Some(payload) = inner.pending_pong.lock().await.take() else {
+                                continue;
+                            };
+                            ctrl = control::pong::<R>(&payload);
+                        }
                         if writer.write_all(&ctrl).await.is_err()
                             || writer.flush().await.is_err() {
                             break;
                         }
                     }
-                    Some(data) = data_rx.recv() => {
-                        if writer.write_all(&data).await.is_err()
-                            || writer.flush().await.is_err() {
-                                break;
+                    Some(data) = data_rx.recv(), if !close_sent => {
+                        if let Outgoing::Flush(_) = data {
+                            inner.queued_flushes.fetch_sub(1, Ordering::Relaxed);
+                        } else if let Some(watch) = &mut drain {
+                            let flushes = inner.queued_flushes.load(Ordering::Relaxed);
+                            watch.taken(data_rx.len().saturating_sub(flushes));
+                        }
+                        let len = data.len();
+                        match data {
+                            Outgoing::Frame(frame) => {
+                                inner.buffered_bytes.fetch_sub(frame.len(), Ordering::Relaxed);
+                                if write_chunked(&mut writer, &frame).await.is_err()
+                                    || writer.flush().await.is_err() {
+                                        break;
+                                }
+                            }
+                            Outgoing::Split(header, payload) => {
+                                inner.buffered_bytes.fetch_sub(len, Ordering::Relaxed);
+                                if write_split(&mut writer, &header, &payload).await.is_err()
+                                    || writer.flush().await.is_err() {
+                                        break;
+                                }
+                            }
+                            Outgoing::Flush(ack) => {
+                                if writer.flush().await.is_err() {
+                                    break;
+                                }
+                                let _ = ack.send(());
+                            }
+                        }
+                        // flushed, so the bytes no longer count against the window
+                        if let Some(window) = &inner.send_window
+                            && len > 0
+                        {
+                            window.release(len);
                         }
                     }
                     else => break
                 }
             }
         });
+        let _ = abort.writer.set(task.abort_handle());
     }
 
-    pub(crate) fn ping_loop(&self, interval_secs: u64, sender: WsSender) {
+    // Ping the peer once nothing has been read for the ping interval, and close if
+    // the ping goes unanswered for `multiplier` intervals
+    pub(crate) fn ping_loop(&self, ctrl: Sender<Vec<u8>>, close: Sender<Closing>, multiplier: u32) {
         let inner = self.inner.clone();
+        let mut period = inner.ping_interval.subscribe();
         tokio::spawn(async move {
-            let mut interval = interval(Duration::from_secs(10));
-            let mut ping_sent = None;
+            // the ping awaiting a pong, and when it was sent
+            let mut ping_sent: Option<([u8; 8], Instant)> = None;
             loop {
-                interval.tick().await;
+                let every = *period.borrow_and_update();
+                // check on an unanswered ping every interval until it times out, times too
+                // far off to represent never come
+                let due = match (every, ping_sent) {
+                    (Some(every), Some((_, sent))) => {
+                        let timeout = every.saturating_mul(multiplier);
+                        [Instant::now().checked_add(every), sent.checked_add(timeout)]
+                            .into_iter()
+                            .flatten()
+                            .min()
+                    }
+                    (Some(every), None) => inner.last_seen.lock().await.checked_add(every),
+                    (None, _) => None,
+                };
+                tokio::select! {
+                    () = sleep_until(due) => {}
+                    res = period.changed() => {
+                        if res.is_err() {
+                            break;
+                        }
+                        let expiry = ping_expiry(*period.borrow(), multiplier);
+                        inner.ping_stats.lock().await.set_expiry(expiry);
+                        continue;
+                    }
+                    () = ctrl.closed() => break,
+                }
+                let Some(every) = every else {
+                    continue;
+                };
 
                 if inner.closing.load(Ordering::Acquire) {
                     tracing::trace!("socket closing, stopping ping loop");
                     break;
                 }
 
-                let last_seen = inner.last_seen.lock().await;
-                if last_seen.elapsed() >= Duration::from_secs(interval_secs) && ping_sent.is_none()
-                {
-                    // send ping
-                    tracing::trace!("interval exceeded, sending ping");
-                    let nonce = inner.ping_stats.lock().await.new_nonce();
-                    let frame = control::ping::<R>(&nonce);
-
-                    if sender.ctrl(frame).await.is_err() {
-                        tracing::warn!("Ping failed, stopping ping loop.");
+                let answered = match ping_sent {
+                    Some((nonce, _)) => inner.ping_stats.lock().await.answered(nonce),
+                    None => false,
+                };
+                let last_seen = *inner.last_seen.lock().await;
+                match ping_sent {
+                    // only a pong shows the peer is still answering
+                    Some(_) if answered => ping_sent = None,
+                    Some((_, sent)) if sent.elapsed() >= every.saturating_mul(multiplier) => {
+                        tracing::debug!("ping unanswered, closing");
+                        let _ = close
+                            .send(control::close::<R>(CloseReason::Policy, "ping timed out").into())
+                            .await;
                         break;
                     }
-                    ping_sent = Some(Instant::now());
-                } else if let Some(sent) = ping_sent
-                    && sent.elapsed() >= Duration::from_secs(interval_secs * 2)
-                {
-                    // send close
-                    let _ = sender
-                        .close(control::close::<R>(CloseReason::Policy, "ping timed out"))
-                        .await;
+                    Some(_) => {}
+                    None if last_seen.elapsed() >= every => {
+                        tracing::trace!("interval exceeded, sending ping");
+                        let mut stats = inner.ping_stats.lock().await;
+                        let Some(nonce) = stats.new_nonce() else {
+                            // the pings already in flight probe the peer just as well,
+                            // so time the newest out as if it had been sent now
+                            tracing::debug!("too many pings in flight, skipping ping");
+                            ping_sent = stats.newest().map(|nonce| (nonce, Instant::now()));
+                            continue;
+                        };
+                        drop(stats);
+                        if ctrl.send(control::ping::<R>(&nonce)).await.is_err() {
+                            tracing::warn!("Ping failed, stopping ping loop.");
+                            break;
+                        }
+                        inner.observe(|| Observation::PingSent);
+                        ping_sent = Some((nonce, Instant::now()));
+                    }
+                    None => tracing::trace!("last seen within interval"),
                 }
-
-                tracing::trace!("last seen within interval");
             }
         });
     }
@@ -376,43 +1429,84 @@ impl<R: RolePolicy> WebSocket<R> {
         mut reader: ReadHalf<S>,
         sender: WsSender,
         mut inflater: Option<DeflateDecoder<Vec<u8>>>,
+        config: WebSocketConfig,
     ) {
         let inner = self.inner.clone();
-        let use_context = self.use_context;
+        let use_context = self.recv_context;
 
         tokio::spawn(async move {
-            let mut buf = BytesMut::with_capacity(MAX_FRAME_PAYLOAD);
-            let mut partial_msg = None;
+            let mut buf = BytesMut::with_capacity(config.read_buffer_size);
+            let mut state = ReadState::default();
 
             let mut fd = FrameDecoder::<R>::new(inflater.is_some());
             loop {
-                let n = {
-                    match reader.read_buf(&mut buf).await {
-                        Ok(0) => {
-                            tracing::trace!("TCP FIN");
-                            break;
-                        }
-                        Ok(n) => n,
-                        Err(e) => {
-                            tracing::warn!(error = ?e, "reader error");
+                // reclaim consumed space so each read can fill a full buffer
+                buf.reserve(config.read_buffer_size);
+                let stalled = state.stalled.is_some();
+                // a slow application should slow the peer down, not grow our memory
+                let room = state.held_bytes < config.read_buffer_size;
+                // termination reads as the end of the stream
+                let wake = async {
+                    tokio::select! {
+                        res = reader.read_buf(&mut buf), if room => Wake::Read(res),
+                        res = sender.event_from(&mut state.stalled), if stalled => Wake::Room(res.is_ok()),
+                        () = inner.terminate.notified() => Wake::Read(Ok(0)),
+                    }
+                };
+                // a stalled reader is waiting on the application, not the peer
+                let wake = match config.idle_read_timeout {
+                    Some(limit) if !stalled => {
+                        if let Ok(wake) = tokio::time::timeout(limit, wake).await {
+                            wake
+                        } else {
+                            tracing::warn!(timeout = ?limit, "no data received, closing idle connection");
+                            fail::<R>(&inner, &sender, CloseReason::GoingAway, "idle timeout")
+                                .await;
                             break;
                         }
                     }
+                    _ => wake.await,
                 };
-                tracing::trace!(bytes = n, "read socket");
-                *inner.last_seen.lock().await = Instant::now();
+                match wake {
+                    Wake::Read(Ok(0)) => {
+                        tracing::trace!("TCP FIN");
+                        break;
+                    }
+                    Wake::Read(Ok(n)) => {
+                        tracing::trace!(bytes = n, "read socket");
+                        *inner.last_seen.lock().await = Instant::now();
+                        fd.push_bytes(&buf.split_to(n));
+                    }
+                    Wake::Read(Err(e)) => {
+                        tracing::warn!(error = ?e, "reader error");
+                        break;
+                    }
+                    Wake::Room(true) => tracing::trace!("event queue has room, resuming"),
+                    Wake::Room(false) => {
+                        receiver_dropped::<R>(&inner, &sender).await;
+                        break;
+                    }
+                }
 
-                fd.push_bytes(&buf.split_to(n));
                 loop {
-                    match fd.next_frame() {
+                    // frames held back by a stalled message go first
+                    let next = match state.resume() {
+                        Some(frame) => Ok(Some(FrameState::Complete(frame))),
+                        None => fd.next_frame(),
+                    };
+                    match next {
                         Ok(Some(FrameState::Complete(frame))) => {
+                            let Some(frame) = state.hold(frame) else {
+                                continue;
+                            };
                             if handle_frame::<R>(
                                 &frame,
                                 &inner,
-                                &mut partial_msg,
+                                &mut state,
                                 &sender,
                                 &mut inflater,
                                 use_context,
+                                &config,
                             )
                             .await
                             .is_none()
@@ -422,45 +1516,952 @@ impl<R: RolePolicy> WebSocket<R> {
                             }
                         }
 
-                        Ok(Some(FrameState::Incomplete)) => {
-                            // break to read more bytes
-                            break;
-                        }
-                        Ok(None) => {
-                            // EOF
-                            break;
-                        }
+                        // break to read more bytes
+                        Ok(Some(FrameState::Incomplete) | None) => break,
                         Err(FrameParseError::ProtoError) => {
                             // close connection with ProtoError
                             tracing::warn!("protocol violation detected, entering closing state");
-                            inner.closing.store(true, Ordering::Release);
-                            let _ = sender
-                                .close(control::close::<R>(
-                                    CloseReason::ProtoError,
-                                    "There was a ws protocol violation.",
-                                ))
-                                .await;
+                            let text = "There was a ws protocol violation.";
+                            fail::<R>(&inner, &sender, CloseReason::ProtoError, text).await;
                             break;
                         }
                         Err(FrameParseError::SizeErr) => {
                             // close connection with TooBig
                             tracing::warn!("size error detected, entering closing state");
-                            inner.closing.store(true, Ordering::Release);
-                            let _ = sender
-                                .close(control::close::<R>(
-                                    CloseReason::TooBig,
-                                    "Frame exceeded maximum size",
-                                ))
-                                .await;
+                            let text =
+                                format!("Frame exceeded maximum size of {MAX_FRAME_PAYLOAD} bytes");
+                            fail::<R>(&inner, &sender, CloseReason::TooBig, &text).await;
                             break;
                         }
                     }
                 }
+                // nothing may follow the peer's close
+                if state.peer_closed {
+                    break;
+                }
             }
             tracing::trace!("reading finished");
-            inner.closing.store(true, Ordering::Release);
-            inner.closed.store(true, Ordering::Release);
-            let _ = sender.event(Event::Closed).await;
+            inner.read_closed.notify_one();
+            finish_reading(&inner, &sender, &mut state.stalled).await;
+        });
+    }
+}
+
+// Mark the connection closed and report why, after any stalled message
+async fn finish_reading(inner: &Inner, sender: &WsSender, stalled: &mut Option<Event>) {
+    inner.closing.store(true, Ordering::Release);
+    inner.closed.store(true, Ordering::Release);
+    // no more pongs can arrive, fail any waiting `ping_rtt`
+    inner.rtt_waiters.lock().await.clear();
+    let info = inner
+        .close_info
+        .lock()
+        .await
+        .take()
+        .unwrap_or_else(CloseInfo::abnormal);
+    inner.observe(|| Observation::Closed(info.clone()));
+    let _ = sender.event_from(stalled).await;
+    let _ = sender.event(Event::Closed(info)).await;
+}
+
+// Encode a close frame with `code` and `reason`, cut to fit, if `code` may be sent
+fn close_frame<R: RolePolicy>(code: impl Into<u16>, reason: &str) -> SendResult<Vec<u8>> {
+    let code = code.into();
+    if !sendable(code) {
+        return Err(SendError::InvalidCloseCode);
+    }
+    Ok(control::close_code::<R>(code, reason))
+}
+
+// Keep the stream open until the peer answers our close, tearing the connection down if
+// it has not within `timeout`. An echo of the peer's own close finds it already read.
+async fn await_peer_close(inner: &Inner, timeout: Duration) {
+    if tokio::time::timeout(timeout, inner.read_closed.notified())
+        .await
+        .is_err()
+    {
+        tracing::debug!(timeout = ?timeout, "peer did not answer close in time, terminating");
+        inner.terminate.notify_one();
+    }
+}
+
+// Sleep until `due`, or forever if nothing is due
+async fn sleep_until(due: Option<Instant>) {
+    match due {
+        Some(due) => tokio::time::sleep_until(due.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+// Why the reader woke up
+enum Wake {
+    Read(std::io::Result<usize>),
+    // whether the stalled message was delivered, false if the receiver is gone
+    Room(bool),
+}
+
+// The writer's data queue, giving back the bytes of frames never written once the
+// writer stops, however it stops
+struct Unwritten {
+    rx: Receiver<Outgoing>,
+    inner: Arc<Inner>,
+}
+
+impl Drop for Unwritten {
+    fn drop(&mut self) {
+        self.rx.close();
+        while let Ok(data) = self.rx.try_recv() {
+            self.inner
+                .buffered_bytes
+                .fetch_sub(data.len(), Ordering::Relaxed);
+        }
+    }
+}
+
+// Hysteresis over the frames in the send queue, producing `Event::Drained` when they
+// fall to the low-water mark after reaching the high-water mark
+pub(crate) struct DrainWatch {
+    high: usize,
+    low: usize,
+    filled: bool,
+    events: Sender<Event>,
+}
+
+impl DrainWatch {
+    fn new(high: usize, low: usize, events: Sender<Event>) -> Self {
+        Self {
+            high,
+            low,
+            filled: false,
+            events,
+        }
+    }
+
+    // the writer took a frame, leaving `waiting` in the queue
+    fn taken(&mut self, waiting: usize) {
+        if waiting + 1 >= self.high {
+            self.filled = true;
+        } else if self.filled && waiting <= self.low {
+            self.filled = false;
+            // never hold up the writer on a full event queue
+            let _ = self.events.try_send(Event::Drained);
+        }
+    }
+}
+
+// Largest single write of a data frame, see `write_chunked`
+const WRITE_CHUNK: usize = 64 * 1024; // 64K
+
+// Write an unmasked frame's header along with the start of its payload in one
+// vectored write, then the rest of the payload as `write_chunked` does
+async fn write_split<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    mut header: &[u8],
+    mut payload: &[u8],
+) -> std::io::Result<()> {
+    while !header.is_empty() {
+        let first = &payload[..payload.len().min(WRITE_CHUNK)];
+        let n = writer
+            .write_vectored(&[IoSlice::new(header), IoSlice::new(first)])
+            .await?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::WriteZero.into());
+        }
+        let from_header = n.min(header.len());
+        header = &header[from_header..];
+        payload = &payload[n - from_header..];
+    }
+    write_chunked(writer, payload).await
+}
+
+// Write a frame in bounded pieces, yielding in between so a huge frame does not
+// monopolise the runtime thread. Control frames still wait for the whole frame,
+// use a smaller `max_send_frame_size` to let them in sooner.
+async fn write_chunked<W: AsyncWrite + Unpin>(writer: &mut W, frame: &[u8]) -> std::io::Result<()> {
+    let mut chunks = frame.chunks(WRITE_CHUNK).peekable();
+    while let Some(chunk) = chunks.next() {
+        writer.write_all(chunk).await?;
+        if chunks.peek().is_some() {
+            tokio::task::yield_now().await;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ConnectionContext, Message, Text, WebSocketClient,
+        error::{PingError, TrySendError},
+        role::Server,
+    };
+
+    #[tokio::test]
+    async fn unsolicited_pong_ignored() {
+        let (client, mut server) = WebSocketClient::pair();
+
+        assert_eq!(client.pong(&[0; 126]).await, Err(SendError::TooLarge));
+        client.pong(b"keepalive").await.unwrap();
+        client.flush().await.unwrap();
+        // the server produces no event for the pong and the connection stays up
+        server.close().await;
+        assert!(matches!(server.recv().await, Some(Event::Closed(info)) if info.code == 1000));
+    }
+
+    #[tokio::test]
+    async fn pong_sequence() {
+        let (mut client, _server) = WebSocketClient::pair();
+
+        for expected in 0..3 {
+            client.ping().await.unwrap();
+            match client.recv().await {
+                Some(Event::Pong { seq, .. }) => assert_eq!(seq, expected),
+                e => panic!("unexpected event {e:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn send_after_close_fails() {
+        let (mut client, _server) = WebSocketClient::pair();
+        client.close().await;
+        assert!(client.send_text("late").await.is_err());
+        assert!(client.send_bytes(b"late").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn send_errors_typed() {
+        let (mut client, _server) = WebSocketClient::pair();
+        let huge = vec![0; crate::MAX_MESSAGE_SIZE + 1];
+        assert_eq!(client.send_bytes(&huge).await, Err(SendError::TooLarge));
+
+        client.close().await;
+        assert_eq!(client.send_text("late").await, Err(SendError::Closing));
+        // the peer answers our close, after which the connection is closed
+        while client.recv().await.is_some() {}
+        assert_eq!(client.send_text("late").await, Err(SendError::Closed));
+    }
+
+    #[tokio::test]
+    async fn deflate_round_trip_each_context_takeover() {
+        for (server_no, client_no) in [(false, false), (true, false), (false, true), (true, true)] {
+            let params = DeflateParams {
+                server_no_context_takeover: server_no,
+                client_no_context_takeover: client_no,
+                ..DeflateParams::symmetric(true)
+            };
+            let (client, server) = tokio::io::duplex(64 * 1024);
+            let config = WebSocketConfig::default();
+            let mut client = WebSocketClient::over_stream(client, Some(params), &config);
+            let mut server = WebSocket::<Server>::over_stream(server, Some(params), &config);
+            assert_eq!(
+                client.negotiated_extensions().permessage_deflate,
+                Some(params)
+            );
+            assert_eq!(
+                server.negotiated_extensions().permessage_deflate,
+                Some(params)
+            );
+
+            // repeated content leans on the previous message when the context is kept
+            for i in 0..5 {
+                let text = format!("message {} ", i % 2).repeat(50);
+                client.send_text(&text).await.unwrap();
+                server.send_text(&text).await.unwrap();
+                for event in [server.recv().await, client.recv().await] {
+                    match event {
+                        Some(Event::Text(t)) => assert_eq!(t.as_str(), text),
+                        e => panic!("unexpected event {e:?} for {params:?}"),
+                    }
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn uncompressed_message_on_deflate_connection() {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let config = WebSocketConfig::default();
+        let mut client =
+            WebSocketClient::over_stream(client, Some(DeflateParams::symmetric(true)), &config);
+        let mut server =
+            WebSocket::<Server>::over_stream(server, Some(DeflateParams::symmetric(true)), &config);
+
+        let payload = vec![b'a'; 1000];
+        client.send_bytes(&payload).await.unwrap();
+        client.send_bytes_uncompressed(&payload).await.unwrap();
+        client.send_bytes(&payload).await.unwrap();
+        for _ in 0..3 {
+            match server.recv().await {
+                Some(Event::Binary(b)) => assert_eq!(b, payload),
+                e => panic!("unexpected event {e:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn preset_dictionary_round_trip() {
+        let dictionary = br#"{"name":"","email":"","verified":false}"#;
+        for context in [true, false] {
+            let (client, server) = tokio::io::duplex(64 * 1024);
+            let config = WebSocketConfig::default();
+            let mut client = WebSocketClient::over_stream(
+                client,
+                Some(DeflateParams::symmetric(context)),
+                &config,
+            );
+            let mut server = WebSocket::<Server>::over_stream(
+                server,
+                Some(DeflateParams::symmetric(context)),
+                &config,
+            );
+            client.set_deflate_dictionary(dictionary).await;
+            server.set_inflate_dictionary(dictionary);
+
+            for text in [
+                r#"{"name":"a","email":"a@b","verified":false}"#,
+                r#"{"name":"c","email":"c@d","verified":true}"#,
+            ] {
+                client.send_text(text).await.unwrap();
+                match server.recv().await {
+                    Some(Event::Text(t)) => assert_eq!(t.as_str(), text),
+                    e => panic!("unexpected event {e:?} with context {context}"),
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn drained_after_backlog() {
+        // a pipe small enough to back up the writer
+        let (client, server) = tokio::io::duplex(64);
+        let config = WebSocketConfig::default().drain_events(8, 2);
+        let mut client = WebSocketClient::over_stream(client, None, &config);
+        let mut server =
+            WebSocket::<Server>::over_stream(server, None, &WebSocketConfig::default());
+
+        for _ in 0..32 {
+            client.send_bytes(&[0; 100]).await.unwrap();
+        }
+        for _ in 0..32 {
+            assert!(matches!(server.recv().await, Some(Event::Binary(_))));
+        }
+        assert!(matches!(client.recv().await, Some(Event::Drained)));
+    }
+
+    #[tokio::test]
+    async fn drain_marks_skip_flushes() {
+        use std::time::Duration;
+
+        let (client, server) = tokio::io::duplex(64);
+        let config = WebSocketConfig::default().drain_events(8, 2);
+        let mut client = WebSocketClient::over_stream(client, None, &config);
+        let mut server =
+            WebSocket::<Server>::over_stream(server, None, &WebSocketConfig::default());
+
+        for _ in 0..4 {
+            client.send_bytes(&[0; 100]).await.unwrap();
+        }
+        // the flushes queue behind the frames, but never make the queue deep enough
+        let receive = async {
+            for _ in 0..4 {
+                assert!(matches!(server.recv().await, Some(Event::Binary(_))));
+            }
+        };
+        let flush_all = async {
+            tokio::try_join!(
+                client.flush(),
+                client.flush(),
+                client.flush(),
+                client.flush(),
+                client.flush(),
+                client.flush()
+            )
+        };
+        let (flushed, ()) = tokio::join!(flush_all, receive);
+        assert!(flushed.is_ok());
+        let event = tokio::time::timeout(Duration::from_millis(100), client.recv()).await;
+        assert!(event.is_err(), "unexpected event {event:?}");
+    }
+
+    #[tokio::test]
+    async fn ping_answered_by_slow_consumer() {
+        let (mut client, mut server) = WebSocketClient::pair();
+
+        // more than the event queue holds, nobody receives on the server yet
+        for i in 0..100u8 {
+            client.send_bytes(&[i]).await.unwrap();
+        }
+        // pings jump the send queue, make sure it reaches the server behind the messages
+        client.flush().await.unwrap();
+        let rtt = client.ping_rtt(std::time::Duration::from_secs(5)).await;
+        assert!(rtt.is_ok(), "{rtt:?}");
+
+        for i in 0..100u8 {
+            match server.recv().await {
+                Some(Event::Binary(b)) => assert_eq!(&b[..], &[i]),
+                e => panic!("unexpected event {e:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn extensions_reported() {
+        let config = WebSocketConfig::default();
+        // only the client gives up its context
+        let params = DeflateParams {
+            client_no_context_takeover: true,
+            ..DeflateParams::symmetric(true)
+        };
+        let (client, server) = tokio::io::duplex(1024);
+        let client = WebSocketClient::over_stream(client, Some(params), &config);
+        let server = WebSocket::<Server>::over_stream(server, Some(params), &config);
+        for ws in [
+            client.negotiated_extensions(),
+            server.negotiated_extensions(),
+        ] {
+            assert_eq!(ws.permessage_deflate, Some(params));
+        }
+        let (ours, theirs) = (client.compression().unwrap(), server.compression().unwrap());
+        assert!(!ours.context_takeover && theirs.context_takeover);
+
+        let (client, server) = tokio::io::duplex(1024);
+        let client = WebSocketClient::over_stream(client, None, &config);
+        let server = WebSocket::<Server>::over_stream(server, None, &config);
+        let none = crate::NegotiatedExtensions::default();
+        assert_eq!(client.negotiated_extensions(), none);
+        assert_eq!(server.negotiated_extensions(), none);
+    }
+
+    // with the clock paused, a timeout only fires once every task is stuck
+    #[tokio::test(start_paused = true)]
+    async fn send_window_blocks() {
+        use std::time::Duration;
+
+        use tokio::io::AsyncReadExt;
+
+        // nobody reads the server end until the window is full
+        let (client, mut server) = tokio::io::duplex(64);
+        let config = WebSocketConfig::default().send_window(1000);
+        let mut client = WebSocketClient::over_stream(client, None, &config);
+
+        let tick = Duration::from_millis(50);
+        // 106 bytes a frame, so a tenth would overrun the window
+        let mut sent = 0;
+        while tokio::time::timeout(tick, client.send_bytes(&[0; 100]))
+            .await
+            .is_ok()
+        {
+            sent += 1;
+            assert!(sent <= 12, "window never filled");
+        }
+        assert_eq!(sent, 9);
+        assert!(client.buffered_bytes() <= 1000);
+
+        let mut buf = [0; 4096];
+        let _ = server.read(&mut buf).await.unwrap();
+        let res = tokio::time::timeout(tick, client.send_bytes(&[0; 100])).await;
+        assert!(res.is_ok());
+    }
+
+    #[tokio::test]
+    async fn unwritten_frames_not_buffered_after_close() {
+        // nobody reads the server end, so frames pile up behind the writer
+        let (client, server) = tokio::io::duplex(64);
+        let mut client = WebSocketClient::over_stream(client, None, &WebSocketConfig::default());
+
+        for _ in 0..10 {
+            client.send_bytes(&[0; 100]).await.unwrap();
+        }
+        // 106 bytes a frame, counted until written
+        assert!(client.buffered_bytes() >= 8 * 106);
+        drop(server);
+        assert!(matches!(client.recv().await, Some(Event::Closed(_))));
+        assert_eq!(client.buffered_bytes(), 0);
+    }
+
+    #[tokio::test]
+    async fn failed_send_not_observed() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // nobody reads the server end, so the writer stalls and the queue fills
+        let (client, server) = tokio::io::duplex(64);
+        let sent = Arc::new(AtomicUsize::new(0));
+        let observed = sent.clone();
+        let config = WebSocketConfig::default()
+            .channel_capacity(1)
+            .observer(move |o| {
+                if let crate::Observation::MessageSent { .. } = o {
+                    observed.fetch_add(1, Ordering::Relaxed);
+                }
+            });
+        let mut client = WebSocketClient::over_stream(client, None, &config);
+
+        let mut queued = 0;
+        while tokio::time::timeout(Duration::from_millis(20), client.send_bytes(&[0; 100]))
+            .await
+            .is_ok()
+        {
+            queued += 1;
+        }
+        // the writer fails while a send waits for room
+        let waiting = client.send_bytes(&[0; 100]);
+        let hang_up = async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(server);
+        };
+        let (res, ()) = tokio::join!(waiting, hang_up);
+        assert!(res.is_err());
+        assert_eq!(sent.load(Ordering::Relaxed), queued);
+    }
+
+    #[tokio::test]
+    async fn try_send_hands_back_when_full() {
+        use tokio::io::AsyncReadExt;
+
+        // nobody reads the server end, so the writer stalls and the queue fills
+        let (client, mut server) = tokio::io::duplex(64);
+        let config = WebSocketConfig::default().channel_capacity(2);
+        let mut client = WebSocketClient::over_stream(client, None, &config);
+
+        let mut sent = 0;
+        let returned = loop {
+            match client.try_send_bytes(vec![sent; 100]) {
+                Ok(()) => sent += 1,
+                Err(TrySendError::Full(message)) => break message,
+                Err(e) => panic!("unexpected error {e:?}"),
+            }
+            assert!(sent <= 4, "queue never filled");
+            tokio::task::yield_now().await;
+        };
+        assert!(matches!(returned, Message::Binary(b) if b[..] == [sent; 100]));
+
+        let mut buf = [0; 4096];
+        let _ = server.read(&mut buf).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        assert!(client.try_send_text("room again").is_ok());
+    }
+
+    #[tokio::test]
+    async fn try_send_too_many_frames_fails() {
+        let (client, _server) = tokio::io::duplex(64);
+        let config = WebSocketConfig::default()
+            .channel_capacity(2)
+            .max_send_frame_size(100);
+        let mut client = WebSocketClient::over_stream(client, None, &config);
+
+        // three frames never fit a queue of two, even once it is empty
+        let res = client.try_send_bytes(vec![0; 300]);
+        assert!(matches!(
+            res,
+            Err(TrySendError::Failed(SendError::TooLarge))
+        ));
+        assert!(client.try_send_bytes(vec![0; 200]).is_ok());
+    }
+
+    #[tokio::test]
+    async fn try_ping_errors() {
+        let (client, _server) = tokio::io::duplex(4096);
+        let config = WebSocketConfig::default().max_inflight_pings(1);
+        let client = WebSocketClient::over_stream(client, None, &config);
+
+        assert!(client.try_ping().is_ok());
+        assert!(matches!(client.try_ping(), Err(PingError::TooMany)));
+
+        // pings may still go out while closing, but not once closed
+        let (mut client, mut server) = WebSocketClient::pair();
+        client.close().await;
+        assert!(client.try_ping().is_ok());
+        assert!(matches!(server.recv().await, Some(Event::Closed(_))));
+        while client.recv().await.is_some() {}
+        assert!(matches!(client.try_ping(), Err(PingError::Closed)));
+    }
+
+    #[tokio::test]
+    async fn idle_connection_closed() {
+        use std::time::{Duration, Instant};
+
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        use crate::frames::{FrameDecoder, FrameState, Opcode, control};
+
+        let (client, mut server) = tokio::io::duplex(4096);
+        let idle = Duration::from_millis(100);
+        let config = WebSocketConfig::default()
+            .idle_read_timeout(Some(idle))
+            .ping_interval(None);
+        let _client = WebSocketClient::over_stream(client, None, &config);
+
+        // anything read restarts the idle period
+        tokio::time::sleep(idle / 2).await;
+        let heard = Instant::now();
+        server
+            .write_all(&control::pong::<Server>(b""))
+            .await
+            .unwrap();
+
+        let mut decoder = FrameDecoder::<Server>::new(false);
+        let mut buf = [0; 1024];
+        let closed = async {
+            loop {
+                let n = server.read(&mut buf).await.unwrap();
+                assert!(n > 0, "pipe closed before a close frame");
+                decoder.push_bytes(&buf[..n]);
+                while let Ok(Some(FrameState::Complete(frame))) = decoder.next_frame() {
+                    if frame.opcode == Opcode::Close {
+                        return frame.payload;
+                    }
+                }
+            }
+        };
+        let payload = tokio::time::timeout(idle * 5, closed).await.unwrap();
+        assert!(heard.elapsed() >= idle);
+        assert_eq!(payload[..2], 1001u16.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn silent_peer_closed_with_pings_capped() {
+        use std::time::{Duration, Instant};
+
+        use tokio::io::AsyncReadExt;
+
+        use crate::frames::{FrameDecoder, FrameState, Opcode};
+
+        // the peer reads everything and never answers
+        let (client, mut server) = tokio::io::duplex(4096);
+        let every = Duration::from_millis(50);
+        let config = WebSocketConfig::default()
+            .ping_interval(Some(every))
+            .max_inflight_pings(1);
+        let client = WebSocketClient::over_stream(client, None, &config);
+        // the application's ping takes the only slot
+        let start = Instant::now();
+        client.ping().await.unwrap();
+
+        let mut decoder = FrameDecoder::<Server>::new(false);
+        let mut buf = [0; 1024];
+        let closed = async {
+            loop {
+                let n = server.read(&mut buf).await.unwrap();
+                assert!(n > 0, "pipe closed before a close frame");
+                decoder.push_bytes(&buf[..n]);
+                while let Ok(Some(FrameState::Complete(frame))) = decoder.next_frame() {
+                    if frame.opcode == Opcode::Close {
+                        return;
+                    }
+                }
+            }
+        };
+        // one interval until the ping is due, then the timeout
+        tokio::time::timeout(every * 6, closed).await.unwrap();
+        assert!(start.elapsed() >= every * 2);
+    }
+
+    #[tokio::test]
+    async fn silent_peer_closed_once() {
+        use std::time::Duration;
+
+        use tokio::io::AsyncReadExt;
+
+        use crate::frames::{FrameDecoder, FrameState, Opcode};
+
+        // the peer reads everything and never answers
+        let (client, mut server) = tokio::io::duplex(4096);
+        let config = WebSocketConfig::default().ping_interval(Some(Duration::from_millis(20)));
+        let _client = WebSocketClient::over_stream(client, None, &config);
+
+        let mut decoder = FrameDecoder::<Server>::new(false);
+        let mut opcodes = Vec::new();
+        let mut buf = [0; 1024];
+        // many ping timeouts, but well inside the close timeout
+        let deadline = tokio::time::Instant::now() + Duration::from_millis(300);
+        while let Ok(Ok(n @ 1..)) = tokio::time::timeout_at(deadline, server.read(&mut buf)).await {
+            decoder.push_bytes(&buf[..n]);
+            while let Ok(Some(FrameState::Complete(frame))) = decoder.next_frame() {
+                opcodes.push(frame.opcode);
+            }
+        }
+        assert_eq!(opcodes.first(), Some(&Opcode::Ping));
+        assert_eq!(opcodes.last(), Some(&Opcode::Close));
+        assert_eq!(opcodes.iter().filter(|&&op| op == Opcode::Close).count(), 1);
+    }
+
+    #[tokio::test]
+    async fn data_does_not_answer_ping() {
+        use std::time::Duration;
+
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        use crate::frames::{FrameDecoder, FrameState, Opcode};
+
+        // the peer goes quiet long enough to be pinged, then only ever sends text
+        let (client, server) = tokio::io::duplex(4096);
+        let config = WebSocketConfig::default().ping_interval(Some(Duration::from_millis(20)));
+        let mut client = WebSocketClient::over_stream(client, None, &config);
+        tokio::spawn(async move { while client.recv().await.is_some() {} });
+        let (mut read, mut write) = tokio::io::split(server);
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            while write.write_all(&[0x81, 2, b'h', b'i']).await.is_ok() {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
         });
+
+        let mut decoder = FrameDecoder::<Server>::new(false);
+        let mut opcodes = Vec::new();
+        let mut buf = [0; 1024];
+        let deadline = tokio::time::Instant::now() + Duration::from_millis(300);
+        while !opcodes.contains(&Opcode::Close)
+            && let Ok(Ok(n @ 1..)) = tokio::time::timeout_at(deadline, read.read(&mut buf)).await
+        {
+            decoder.push_bytes(&buf[..n]);
+            while let Ok(Some(FrameState::Complete(frame))) = decoder.next_frame() {
+                opcodes.push(frame.opcode);
+            }
+        }
+        assert_eq!(opcodes, [Opcode::Ping, Opcode::Close]);
+    }
+
+    #[tokio::test]
+    async fn close_deadline_tears_down() {
+        use std::time::{Duration, Instant};
+
+        use tokio::io::AsyncReadExt;
+
+        // the peer never reads, so the writer is stuck behind a full pipe
+        let (client, mut server) = tokio::io::duplex(64);
+        let mut client = WebSocketClient::over_stream(client, None, &WebSocketConfig::default());
+        client.send_bytes(&[0; 1000]).await.unwrap();
+
+        let start = Instant::now();
+        let info = client
+            .close_with_deadline(CloseReason::Normal, "bye", Duration::from_millis(100))
+            .await
+            .unwrap();
+        assert_eq!(info.code, 1006);
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // both halves are gone, so the peer sees the end of the stream
+        let mut rest = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(1), server.read_to_end(&mut rest));
+        assert!(read.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn close_waits_for_peer_close() {
+        use std::time::Duration;
+
+        use tokio::io::AsyncReadExt;
+
+        let (client, mut peer) = tokio::io::duplex(1024);
+        let config = WebSocketConfig::default().close_timeout(Duration::from_millis(200));
+        let mut client = WebSocketClient::over_stream(client, None, &config);
+
+        // our close arrives, but the stream stays open for the answer
+        client.close().await;
+        // a masked close carrying only the code
+        let mut buf = [0; 8];
+        peer.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf[..2], [0x88, 0x82]);
+        let early = tokio::time::timeout(Duration::from_millis(50), peer.read(&mut buf));
+        assert!(early.await.is_err());
+
+        peer.write_all(&[0x88, 0x02, 0x03, 0xE8]).await.unwrap();
+        assert_eq!(peer.read(&mut buf).await.unwrap(), 0);
+        assert!(matches!(client.recv().await, Some(Event::Closed(info)) if info.code == 1000));
+        let again = tokio::time::timeout(Duration::from_millis(50), client.recv()).await;
+        assert!(!matches!(again, Ok(Some(_))));
+
+        // a peer which never answers is cut off once the timeout elapses
+        let (client, mut peer) = tokio::io::duplex(1024);
+        let mut client = WebSocketClient::over_stream(client, None, &config);
+        client.close().await;
+        assert!(matches!(client.recv().await, Some(Event::Closed(info)) if info.code == 1006));
+        let mut rest = Vec::new();
+        peer.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest[0], 0x88);
+    }
+
+    #[tokio::test]
+    async fn ping_interval_configurable() {
+        use std::time::Duration;
+
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let config = WebSocketConfig::default().ping_interval(Some(Duration::from_millis(20)));
+        let mut client = WebSocketClient::over_stream(client, None, &config);
+        let _server = WebSocket::<Server>::over_stream(
+            server,
+            None,
+            &WebSocketConfig::default().ping_interval(None),
+        );
+
+        // answered pings do not stop the next one
+        for expected in 0..3 {
+            let event = tokio::time::timeout(Duration::from_secs(1), client.recv()).await;
+            assert!(matches!(event, Ok(Some(Event::Pong { seq, .. })) if seq == expected));
+        }
+
+        client.set_ping_interval(None);
+        while tokio::time::timeout(Duration::from_millis(50), client.recv())
+            .await
+            .is_ok()
+        {}
+        let event = tokio::time::timeout(Duration::from_millis(100), client.recv()).await;
+        assert!(event.is_err(), "pinged after pings were disabled");
+
+        // an interval too long to add to the clock means never, and keeps the loop alive
+        client.set_ping_interval(Some(Duration::MAX));
+        let event = tokio::time::timeout(Duration::from_millis(100), client.recv()).await;
+        assert!(event.is_err(), "pinged with the longest interval");
+        client.set_ping_interval(Some(Duration::from_millis(20)));
+        let event = tokio::time::timeout(Duration::from_secs(1), client.recv()).await;
+        assert!(matches!(event, Ok(Some(Event::Pong { seq: 3, .. }))));
+    }
+
+    #[tokio::test]
+    async fn close_with_runtime_reason() {
+        let (mut client, mut server) = WebSocketClient::pair();
+        // 41 three-byte characters, the cut must not split the last one that fits
+        let reason = format!("rate limit exceeded for {}", "€".repeat(41));
+        server
+            .close_with(CloseReason::Policy, &reason)
+            .await
+            .unwrap();
+        match client.recv().await {
+            Some(Event::Closed(info)) => {
+                assert_eq!(info.code, 1008);
+                assert!(info.reason.len() <= 123 && reason.starts_with(&info.reason));
+                assert!(info.reason.ends_with('€'));
+            }
+            e => panic!("unexpected event {e:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn send_close_leaves_stream_open() {
+        let (mut client, mut server) = WebSocketClient::pair();
+        client.send_close(4001u16, "bye").await.unwrap();
+        assert_eq!(client.send_text("late").await, Err(SendError::Closing));
+        assert_eq!(
+            client.send_close(CloseReason::Normal, "").await,
+            Err(SendError::Closing)
+        );
+        assert!(matches!(server.recv().await, Some(Event::Closed(info)) if info.code == 4001));
+        // the peer's answer is still read
+        assert!(matches!(client.recv().await, Some(Event::Closed(info)) if info.code == 1000));
+        client.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn no_pong_after_close() {
+        let (client, _server) = WebSocketClient::pair();
+        assert!(client.pong(b"alive").await.is_ok());
+        client.send_close(CloseReason::Normal, "").await.unwrap();
+        assert_eq!(client.pong(b"alive").await, Err(SendError::Closing));
+    }
+
+    #[tokio::test]
+    async fn close_with_reserved_code() {
+        let (_client, mut server) = WebSocketClient::pair();
+        let res = server.close_with(1006u16, "").await;
+        assert_eq!(res, Err(SendError::InvalidCloseCode));
+        let res = server
+            .close_with_deadline(1005u16, "", Duration::from_millis(10))
+            .await;
+        assert_eq!(res, Err(SendError::InvalidCloseCode));
+        // nothing was sent, so the connection may still be closed properly
+        assert!(server.close_with(CloseReason::Normal, "").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn handler_told_connection() {
+        struct Tagger;
+        #[async_trait::async_trait]
+        impl crate::MessageHandler for Tagger {
+            async fn on_text(&self, conn: &ConnectionContext, s: Text) -> Option<Message> {
+                let tag = format!("{} {} {s}", conn.peer_addr(), conn.info().path());
+                Some(Message::Text(tag))
+            }
+            async fn on_binary(
+                &self,
+                _conn: &ConnectionContext,
+                _b: bytes::Bytes,
+            ) -> Option<Message> {
+                None
+            }
+            async fn on_close(&self, _conn: &ConnectionContext) {}
+            async fn on_error(&self, _conn: &ConnectionContext) {}
+            async fn on_pong(&self, _conn: &ConnectionContext, _latency: u16) {}
+        }
+
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let config = WebSocketConfig::default();
+        let (addr, peer) = (crate::UNSPECIFIED_ADDR, "10.0.0.1:4000".parse().unwrap());
+        let mut client = WebSocketClient::over_stream(client, None, &config);
+        let info = ConnectionInfo::from_target("/chat");
+        let mut server = WebSocket::<Server>::from_stream(server, addr, peer, None, &config, info);
+        tokio::spawn(async move { server.recv_loop(Arc::new(Tagger)).await });
+
+        client.send_text("hi").await.unwrap();
+        match client.recv().await {
+            Some(Event::Text(t)) => assert_eq!(t.as_str(), "10.0.0.1:4000 /chat hi"),
+            e => panic!("unexpected event {e:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn handler_sends_control_frames() {
+        struct Keepalive;
+        #[async_trait::async_trait]
+        impl crate::MessageHandler for Keepalive {
+            async fn on_text(&self, _conn: &ConnectionContext, s: Text) -> Option<Message> {
+                let payload = bytes::Bytes::copy_from_slice(s.as_str().as_bytes());
+                Some(match s.as_str() {
+                    "pong" => Message::Pong(payload),
+                    _ => Message::Ping(payload),
+                })
+            }
+            async fn on_binary(
+                &self,
+                _conn: &ConnectionContext,
+                _b: bytes::Bytes,
+            ) -> Option<Message> {
+                None
+            }
+            async fn on_close(&self, _conn: &ConnectionContext) {}
+            async fn on_error(&self, _conn: &ConnectionContext) {}
+            async fn on_pong(&self, _conn: &ConnectionContext, _latency: u16) {}
+        }
+
+        let (mut client, mut server) = WebSocketClient::pair();
+        tokio::spawn(async move { server.recv_loop(Arc::new(Keepalive)).await });
+
+        client.send_text("ping").await.unwrap();
+        assert!(matches!(client.recv().await, Some(Event::Ping(b)) if b[..] == *b"ping"));
+        client.send_text("pong").await.unwrap();
+        client.send_text("after").await.unwrap();
+        // the pong answers none of the client's pings, so only the next ping shows
+        assert!(matches!(client.recv().await, Some(Event::Ping(b)) if b[..] == *b"after"));
+
+        let (mut sink, _stream) = client.split();
+        let huge = Message::Ping(vec![0; 126].into());
+        assert_eq!(sink.send(huge).await, Err(SendError::TooLarge));
+    }
+
+    #[tokio::test]
+    async fn try_again_later_sent() {
+        let (mut client, mut server) = WebSocketClient::pair();
+
+        server
+            .close_reason(CloseReason::TryAgainLater, "busy")
+            .await;
+        match client.recv().await {
+            Some(Event::Closed(info)) => {
+                assert_eq!((info.code, info.reason.as_str()), (1013, "busy"));
+            }
+            e => panic!("unexpected event {e:?}"),
+        }
+        assert!(matches!(server.recv().await, Some(Event::Closed(_))));
     }
 }
@@ -0,0 +1,291 @@
+use std::{
+    io,
+    pin::Pin,
+    sync::atomic::Ordering,
+    task::{Context, Poll},
+};
+
+use tokio::{
+    io::AsyncWrite,
+    sync::mpsc::{OwnedPermit, error::SendError},
+};
+
+use super::{WebSocket, websocket::SendLock};
+use crate::{
+    frames::{Opcode, Outgoing, fragment},
+    role::RolePolicy,
+};
+
+type Reserve = Pin<Box<dyn Future<Output = Result<OwnedPermit<Outgoing>, SendError<()>>> + Send>>;
+type Acquire = Pin<Box<dyn Future<Output = bool> + Send>>;
+
+// Most an encoded frame can exceed its payload by, the header and deflate's framing
+const FRAME_OVERHEAD: usize = 14 + 16;
+
+/// Sends a single message as a stream of fragments, created with
+/// [`binary_writer`](WebSocket::binary_writer) or [`text_writer`](WebSocket::text_writer).
+///
+/// Each write becomes one or more frames of the message and
+/// [`shutdown`](tokio::io::AsyncWriteExt::shutdown) finishes it, so the message never has
+/// to be held in memory at once. Pings and pongs are still sent between the fragments.
+/// The writer mutably borrows the connection and holds its sending side from the first
+/// write, so no other message, including from a [`WsHandle`](crate::WsHandle), can be sent
+/// while one is in progress. If the writer is dropped without being shut down, the
+/// message is finished on a best-effort basis.
+pub struct MessageWriter<'a, R: RolePolicy> {
+    ws: &'a mut WebSocket<R>,
+    opcode: Opcode,
+    first: bool,
+    finished: bool,
+    reserve: Option<Reserve>,
+    lock: SendLock,
+    // waiting for, or holding, room in the send window for the next frame
+    acquire: Option<Acquire>,
+    taken: Option<usize>,
+}
+
+/// The writer of a binary message, the name it had before text messages could be streamed.
+pub type BinaryWriter<'a, R> = MessageWriter<'a, R>;
+
+impl<R: RolePolicy> WebSocket<R> {
+    /// Returns an [`AsyncWrite`] which sends everything written to it as one binary message,
+    /// e.g. `tokio::io::copy(&mut file, &mut ws.binary_writer())`.
+    ///
+    /// The message must be finished by shutting the writer down.
+    pub fn binary_writer(&mut self) -> MessageWriter<'_, R> { self.message_writer(Opcode::Bin) }
+
+    /// Returns an [`AsyncWrite`] which sends everything written to it as one text message.
+    ///
+    /// Writes may split characters, but the message as a whole must be valid UTF-8 or
+    /// the peer will fail the connection. It must be finished by shutting the writer down.
+    pub fn text_writer(&mut self) -> MessageWriter<'_, R> { self.message_writer(Opcode::Text) }
+
+    fn message_writer(&mut self, opcode: Opcode) -> MessageWriter<'_, R> {
+        let lock = SendLock::new(self.send_state.clone());
+        MessageWriter {
+            ws: self,
+            opcode,
+            first: true,
+            finished: false,
+            reserve: None,
+            lock,
+            acquire: None,
+            taken: None,
+        }
+    }
+}
+
+impl<R: RolePolicy> MessageWriter<'_, R> {
+    // wait for room in the data channel then queue a frame containing `payload`
+    fn poll_send(
+        &mut self,
+        cx: &mut Context<'_>,
+        payload: &[u8],
+        last: bool,
+    ) -> Poll<io::Result<()>> {
+        std::task::ready!(self.lock.poll_lock(cx));
+        std::task::ready!(self.poll_window(cx, payload.len() + FRAME_OVERHEAD))?;
+        let reserve = self
+            .reserve
+            .get_or_insert_with(|| Box::pin(self.ws.data_tx.clone().reserve_owned()));
+        let permit = match reserve.as_mut().poll(cx) {
+            Poll::Ready(Ok(permit)) => permit,
+            Poll::Ready(Err(_)) => {
+                self.reserve = None;
+                return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+            }
+            Poll::Pending => return Poll::Pending,
+        };
+        self.reserve = None;
+
+        let state = std::task::ready!(self.lock.poll_lock(cx));
+        let frame = fragment::<R>(
+            payload,
+            self.opcode,
+            &mut state.deflater,
+            self.ws.send_context,
+            state.dictionary.as_deref(),
+            &mut self.first,
+            last,
+        );
+        if last {
+            self.lock.unlock();
+        }
+        if let Some(window) = &self.ws.inner.send_window
+            && let Some(taken) = self.taken.take()
+        {
+            window.settle(taken, frame.len());
+        }
+        self.ws
+            .inner
+            .buffered_bytes
+            .fetch_add(frame.len(), Ordering::Relaxed);
+        permit.send(Outgoing::Frame(frame));
+        Poll::Ready(Ok(()))
+    }
+
+    // take `estimate` bytes of the send window, if there is one, before encoding a frame
+    fn poll_window(&mut self, cx: &mut Context<'_>, estimate: usize) -> Poll<io::Result<()>> {
+        if self.ws.inner.send_window.is_none() || self.taken.is_some() {
+            return Poll::Ready(Ok(()));
+        }
+        let acquire = self.acquire.get_or_insert_with(|| {
+            let inner = self.ws.inner.clone();
+            let tx = self.ws.data_tx.clone();
+            Box::pin(async move {
+                let window = inner.send_window.as_ref().expect("checked above");
+                window.acquire(estimate, &tx).await
+            })
+        });
+        let acquired = std::task::ready!(acquire.as_mut().poll(cx));
+        self.acquire = None;
+        if !acquired {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        self.taken = Some(estimate);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<R: RolePolicy> AsyncWrite for MessageWriter<'_, R> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.finished || this.ws.inner.closing.load(Ordering::Acquire) {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let state = std::task::ready!(this.lock.poll_lock(cx));
+        let n = buf.len().min(state.encoder.frame_size());
+        this.poll_send(cx, &buf[..n], false).map_ok(|()| n)
+    }
+
+    // frames are handed to the writer task as soon as they are written,
+    // use `WebSocket::flush` to wait for them to reach the stream
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.finished {
+            return Poll::Ready(Ok(()));
+        }
+        let res = std::task::ready!(this.poll_send(cx, &[], true));
+        this.finished = true;
+        Poll::Ready(res)
+    }
+}
+
+impl<R: RolePolicy> Drop for MessageWriter<'_, R> {
+    fn drop(&mut self) {
+        if let Some(window) = &self.ws.inner.send_window
+            && let Some(taken) = self.taken.take()
+        {
+            window.release(taken);
+        }
+        if self.finished {
+            return;
+        }
+        let Some(state) = self.lock.try_lock() else {
+            tracing::warn!("message writer dropped without finishing the message");
+            return;
+        };
+        let frame = fragment::<R>(
+            &[],
+            self.opcode,
+            &mut state.deflater,
+            self.ws.send_context,
+            state.dictionary.as_deref(),
+            &mut self.first,
+            true,
+        );
+        let len = frame.len();
+        if self.ws.data_tx.try_send(Outgoing::Frame(frame)).is_ok() {
+            self.ws
+                .inner
+                .buffered_bytes
+                .fetch_add(len, Ordering::Relaxed);
+            // too late to wait, the writer gives the bytes back all the same
+            if let Some(window) = &self.ws.inner.send_window {
+                window.force(len);
+            }
+        } else {
+            tracing::warn!("message writer dropped without finishing the message");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use crate::{DeflateParams, Event, WebSocket, WebSocketClient, WebSocketConfig, role::Server};
+
+    #[tokio::test]
+    async fn copies_stream_as_one_message() {
+        let (mut client, mut server) = WebSocketClient::pair();
+        #[allow(clippy::cast_possible_truncation)]
+        let payload: Vec<u8> = (0..40_000usize).map(|i| i as u8).collect();
+
+        let mut writer = client.binary_writer();
+        tokio::io::copy(&mut payload.as_slice(), &mut writer)
+            .await
+            .unwrap();
+        writer.shutdown().await.unwrap();
+
+        match server.recv().await {
+            Some(Event::Binary(b)) => assert_eq!(b, payload),
+            e => panic!("unexpected event {e:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn text_streamed_around_ping() {
+        let (mut client, mut server) = WebSocketClient::pair();
+        let text = "héllo wörld";
+        let (head, tail) = text.as_bytes().split_at(2); // splits the é
+
+        let mut writer = client.text_writer();
+        writer.write_all(head).await.unwrap();
+        writer.ws.ping().await.unwrap();
+        writer.write_all(tail).await.unwrap();
+        writer.shutdown().await.unwrap();
+        drop(writer);
+
+        assert!(matches!(server.recv().await, Some(Event::Ping(_))));
+        assert!(matches!(server.recv().await, Some(Event::Text(t)) if t.as_str() == text));
+        assert!(matches!(client.recv().await, Some(Event::Pong { .. })));
+    }
+
+    #[tokio::test]
+    async fn streams_through_send_window() {
+        let (client, server) = tokio::io::duplex(1024);
+        // smaller than a single write, so every frame waits for the one before
+        let config = WebSocketConfig::default().send_window(4096);
+        let mut client =
+            WebSocketClient::over_stream(client, Some(DeflateParams::symmetric(true)), &config);
+        let mut server = WebSocket::<Server>::over_stream(
+            server,
+            Some(DeflateParams::symmetric(true)),
+            &WebSocketConfig::default(),
+        );
+        let payload = vec![7; 100_000];
+
+        let mut writer = client.binary_writer();
+        tokio::io::copy(&mut payload.as_slice(), &mut writer)
+            .await
+            .unwrap();
+        writer.shutdown().await.unwrap();
+        drop(writer);
+
+        match server.recv().await {
+            Some(Event::Binary(b)) => assert_eq!(b, payload),
+            e => panic!("unexpected event {e:?}"),
+        }
+        client.flush().await.unwrap();
+        assert_eq!(client.buffered_bytes(), 0);
+    }
+}